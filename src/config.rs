@@ -1,58 +1,514 @@
-use clap::Parser;
+use crate::onvif::client_ip::Cidr;
+use crate::onvif::profiles::ProfileConfig;
+use crate::onvif::responses::DeviceInformation;
+use clap::parser::ValueSource;
+use clap::{CommandFactory, Parser};
+use log::info;
+use sha1::{Digest, Sha1};
+use std::ffi::OsString;
 use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Presets accepted by ffmpeg's libx264 encoder
+const X264_PRESETS: [&str; 9] = [
+    "ultrafast",
+    "superfast",
+    "veryfast",
+    "faster",
+    "fast",
+    "medium",
+    "slow",
+    "slower",
+    "veryslow",
+];
 
 /// Configuration structure for the ONVIF Media Transcoder
+///
+/// Each setting is read from its command-line argument, then its environment
+/// variable (e.g. `ONVIF_PORT`), then the config file, then the default.
 #[derive(Debug, Clone, Parser)]
 #[command(name = "onvif-media-transcoder")]
 #[command(
     about = "ONVIF Media Transcoder - Converts media streams to ONVIF-compatible RTSP streams"
 )]
 pub struct Config {
+    /// TOML file with settings named like the fields below (arguments and environment variables take precedence)
+    #[arg(long = "config", env = "CONFIG_FILE")]
+    pub config_file: Option<PathBuf>,
+
     /// RTSP stream URL to transcode
-    #[arg(short = 'r', long, default_value = "rtsp://127.0.0.1:8554/stream")]
+    #[arg(
+        short = 'r',
+        long,
+        env = "RTSP_STREAM_URL",
+        default_value = "rtsp://127.0.0.1:8554/stream"
+    )]
     pub rtsp_stream_url: String,
 
+    /// RTSP stream URL for the LQ profile (the LQ profile shares the main stream when unset)
+    #[arg(long = "rtsp-stream-url-lq", env = "RTSP_STREAM_URL_LQ")]
+    pub rtsp_stream_url_lq: Option<String>,
+
+    /// Exit at startup if the RTSP stream is unreachable (otherwise only a warning is logged)
+    #[arg(long = "validate-stream", env = "VALIDATE_STREAM", action = clap::ArgAction::SetTrue)]
+    pub validate_stream: bool,
+
+    /// Extra attempts when the RTSP source can't be reached (snapshots, startup check)
+    #[arg(
+        long = "rtsp-retries",
+        env = "RTSP_RETRIES",
+        default_value = "2",
+        value_parser = clap::value_parser!(u32).range(0..=10)
+    )]
+    pub rtsp_retries: u32,
+
+    /// Delay in milliseconds before the first RTSP retry, doubling on each further attempt
+    #[arg(
+        long = "rtsp-retry-delay-ms",
+        env = "RTSP_RETRY_DELAY_MS",
+        default_value = "500"
+    )]
+    pub rtsp_retry_delay_ms: u64,
+
+    /// External RTSP/RTMP URL ffmpeg also pushes the stream to; GetStreamUri keeps advertising the RTSP stream
+    #[arg(long = "output-url", env = "OUTPUT_URL")]
+    pub output_url: Option<String>,
+
+    /// Republish each profile's source to the local RTSP server with ffmpeg and advertise
+    /// that stream in GetStreamUri instead of the upstream URL
+    #[arg(long = "enable-relay", env = "ENABLE_RELAY", action = clap::ArgAction::SetTrue)]
+    pub enable_relay: bool,
+
+    /// Port of the local RTSP server relayed streams are published to
+    #[arg(long = "relay-port", env = "RELAY_PORT", default_value = "8554")]
+    pub relay_port: u16,
+
+    /// Path prefix of relayed streams; each profile is published below it by token
+    #[arg(long = "relay-path", env = "RELAY_PATH", default_value = "/relay")]
+    pub relay_path: String,
+
+    /// Relay sources that aren't H264 through ffmpeg's libx264 encoder, so H264-only
+    /// clients can play HEVC or MJPEG cameras (uses the relay port and path)
+    #[arg(long = "transcode", env = "TRANSCODE", action = clap::ArgAction::SetTrue)]
+    pub transcode: bool,
+
+    /// Video bitrate of transcoded streams in kbit/s
+    #[arg(
+        long = "transcode-bitrate",
+        env = "TRANSCODE_BITRATE",
+        default_value = "2048",
+        value_parser = clap::value_parser!(u32).range(100..=50000)
+    )]
+    pub transcode_bitrate: u32,
+
+    /// libx264 preset of transcoded streams (ultrafast ... veryslow)
+    #[arg(
+        long = "transcode-preset",
+        env = "TRANSCODE_PRESET",
+        default_value = "veryfast"
+    )]
+    pub transcode_preset: String,
+
+    /// Frame rate advertised to clients and used when transcoding (follows the source when unset)
+    #[arg(long = "frame-rate", env = "FRAME_RATE", value_parser = clap::value_parser!(u32).range(1..=120))]
+    pub frame_rate: Option<u32>,
+
+    /// Media profile to advertise, repeatable (replaces the default HQ/LQ pair); rtsp-url goes last, e.g.
+    /// "token=main,name=Main,resolution=1920x1080,codec=H264,bitrate=4000,framerate=25,rtsp-url=rtsp://..."
+    #[arg(long = "profile", value_parser = ProfileConfig::parse)]
+    pub profiles: Vec<ProfileConfig>,
+
+    /// Prefix applied to the default profile tokens (e.g. "cam1_" gives "cam1_HQProfile")
+    #[arg(
+        long = "profile-token-prefix",
+        env = "PROFILE_TOKEN_PREFIX",
+        default_value = ""
+    )]
+    pub profile_token_prefix: String,
+
     /// Port for the ONVIF service
-    #[arg(short = 'P', long, default_value = "8080")]
+    #[arg(short = 'P', long, env = "ONVIF_PORT", default_value = "8080")]
     pub onvif_port: String,
 
+    /// Address the ONVIF service listens on ("::" accepts IPv6 and, on dual-stack hosts, IPv4 too)
+    #[arg(long = "bind-address", env = "BIND_ADDRESS", default_value = "0.0.0.0")]
+    pub bind_address: IpAddr,
+
+    /// PEM certificate chain; with --tls-key the ONVIF service is served over HTTPS
+    #[arg(long = "tls-cert", env = "TLS_CERT", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long = "tls-key", env = "TLS_KEY", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
     /// Device name for ONVIF identification
-    #[arg(short = 'n', long, default_value = "ONVIF-Media-Transcoder")]
+    #[arg(
+        short = 'n',
+        long,
+        env = "DEVICE_NAME",
+        default_value = "ONVIF-Media-Transcoder"
+    )]
     pub device_name: String,
 
+    /// Manufacturer reported by GetDeviceInformation and WS-Discovery
+    #[arg(
+        long = "manufacturer",
+        env = "MANUFACTURER",
+        default_value = "ONVIF Media Solutions"
+    )]
+    pub manufacturer: String,
+
+    /// Firmware version reported by GetDeviceInformation and WS-Discovery
+    #[arg(
+        long = "firmware-version",
+        env = "FIRMWARE_VERSION",
+        default_value = "1.0.0"
+    )]
+    pub firmware_version: String,
+
+    /// Hardware ID reported by GetDeviceInformation
+    #[arg(
+        long = "hardware-id",
+        env = "HARDWARE_ID",
+        default_value = "onvif-media-transcoder"
+    )]
+    pub hardware_id: String,
+
+    /// Serial number reported by GetDeviceInformation and WS-Discovery (derived from the device name when unset)
+    #[arg(long = "serial-number", env = "SERIAL_NUMBER")]
+    pub serial_number: Option<String>,
+
+    /// UUID WS-Discovery identifies the device by (derived from the serial number and device name when unset)
+    #[arg(long = "device-uuid", env = "DEVICE_UUID")]
+    pub device_uuid: Option<Uuid>,
+
+    /// Time zone reported by GetSystemDateAndTime: a POSIX TZ string (e.g. "CET-1CEST,M3.5.0,M10.5.0/3")
+    /// or an IANA zone name (e.g. "Europe/Berlin")
+    #[arg(long = "timezone", env = "TIMEZONE", default_value = "UTC")]
+    pub timezone: String,
+
+    /// Report daylight saving time as in effect (IANA zones follow their own DST rules instead)
+    #[arg(long = "dst", env = "DST", action = clap::ArgAction::SetTrue)]
+    pub dst: bool,
+
     /// Username for ONVIF authentication
-    #[arg(short = 'u', long, default_value = "admin")]
+    #[arg(short = 'u', long, env = "ONVIF_USERNAME", default_value = "admin")]
     pub onvif_username: String,
 
     /// Password for ONVIF authentication
-    #[arg(short = 'p', long, default_value = "onvif-rust")]
+    #[arg(
+        short = 'p',
+        long,
+        env = "ONVIF_PASSWORD",
+        default_value = "onvif-rust"
+    )]
     pub onvif_password: String,
 
     /// Container IP address for WS-Discovery
-    #[arg(long = "container-ip", short = 'i', default_value = "127.0.0.1")]
+    #[arg(
+        long = "container-ip",
+        short = 'i',
+        env = "CONTAINER_IP",
+        default_value = "127.0.0.1"
+    )]
     pub container_ip: String,
 
     /// Enable WS-Discovery service for automatic device discovery
-    #[arg(long = "ws-discovery-enabled", short = 'w', action = clap::ArgAction::SetTrue)]
+    #[arg(long = "ws-discovery-enabled", short = 'w', env = "WS_DISCOVERY_ENABLED", action = clap::ArgAction::SetTrue)]
     pub ws_discovery_enabled: bool,
 
+    /// Answer Windows network discovery (wsdp:Device) probes so the device shows up in Explorer
+    #[arg(long = "enable-windows-wsd", env = "ENABLE_WINDOWS_WSD", action = clap::ArgAction::SetTrue)]
+    pub enable_windows_wsd: bool,
+
+    /// Also answer WS-Discovery probes sent directly to this management address, advertising its XAddrs
+    #[arg(long = "discovery-management-ip", env = "DISCOVERY_MANAGEMENT_IP")]
+    pub discovery_management_ip: Option<IpAddr>,
+
+    /// Interface addresses to join the WS-Discovery multicast group on, comma-separated (CONTAINER_IP when empty)
+    #[arg(
+        long = "discovery-interfaces",
+        env = "DISCOVERY_INTERFACES",
+        value_delimiter = ','
+    )]
+    pub discovery_interfaces: Vec<IpAddr>,
+
+    /// Longest random delay in milliseconds before answering a multicast WS-Discovery probe
+    #[arg(
+        long = "discovery-max-delay-ms",
+        env = "DISCOVERY_MAX_DELAY_MS",
+        default_value = "500"
+    )]
+    pub discovery_max_delay_ms: u64,
+
+    /// Seconds between periodic WS-Discovery Hello announcements
+    #[arg(
+        long = "hello-interval",
+        env = "HELLO_INTERVAL",
+        default_value = "60",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub hello_interval: u64,
+
+    /// Extra scope URI advertised by WS-Discovery and GetScopes; repeatable or comma-separated.
+    /// A name or location scope replaces the default one
+    #[arg(long = "scope", env = "SCOPES", value_delimiter = ',')]
+    pub scopes: Vec<String>,
+
+    /// Stop answering WS-Discovery (and send Bye) while the RTSP source is unreachable
+    #[arg(long = "discovery-follow-rtsp", env = "DISCOVERY_FOLLOW_RTSP", action = clap::ArgAction::SetTrue)]
+    pub discovery_follow_rtsp: bool,
+
+    /// Number of worker threads handling ONVIF connections (further connections are queued, then rejected with 503)
+    #[arg(
+        long = "max-workers",
+        env = "MAX_WORKERS",
+        default_value = "16",
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    pub max_workers: u16,
+
+    /// How long in milliseconds a captured snapshot is reused before capturing a new one
+    #[arg(
+        long = "snapshot-cache-ms",
+        env = "SNAPSHOT_CACHE_MS",
+        default_value = "1000"
+    )]
+    pub snapshot_cache_ms: u64,
+
+    /// ffmpeg binary used for snapshots, the MJPEG stream and the relay
+    #[arg(long = "ffmpeg-path", env = "FFMPEG_PATH", default_value = "ffmpeg")]
+    pub ffmpeg_path: String,
+
+    /// ffprobe binary used to probe the source stream
+    #[arg(long = "ffprobe-path", env = "FFPROBE_PATH", default_value = "ffprobe")]
+    pub ffprobe_path: String,
+
+    /// Serve GET /snapshot.jpg without authentication
+    #[arg(long = "public-snapshot", env = "PUBLIC_SNAPSHOT", action = clap::ArgAction::SetTrue)]
+    pub public_snapshot: bool,
+
+    /// JPEG quality of snapshots, from 2 (best) to 31 (smallest)
+    #[arg(
+        long = "snapshot-quality",
+        env = "SNAPSHOT_QUALITY",
+        default_value = "2",
+        value_parser = clap::value_parser!(u8).range(2..=31)
+    )]
+    pub snapshot_quality: u8,
+
+    /// Scale snapshots down to at most this width, keeping the aspect ratio
+    #[arg(long = "snapshot-max-width", env = "SNAPSHOT_MAX_WIDTH", value_parser = clap::value_parser!(u32).range(1..))]
+    pub snapshot_max_width: Option<u32>,
+
+    /// Scale snapshots down to at most this height, keeping the aspect ratio
+    #[arg(long = "snapshot-max-height", env = "SNAPSHOT_MAX_HEIGHT", value_parser = clap::value_parser!(u32).range(1..))]
+    pub snapshot_max_height: Option<u32>,
+
+    /// Seconds a snapshot capture may take before ffmpeg is killed and 504 returned
+    #[arg(
+        long = "snapshot-timeout",
+        env = "SNAPSHOT_TIMEOUT",
+        default_value = "20",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub snapshot_timeout: u64,
+
+    /// Serve an HTTP MJPEG stream at GET /stream.mjpeg, returned by GetStreamUri for HTTP transport
+    #[arg(long = "enable-mjpeg", env = "ENABLE_MJPEG", action = clap::ArgAction::SetTrue)]
+    pub enable_mjpeg: bool,
+
+    /// Maximum number of clients watching the MJPEG stream at once; each holds a worker
+    /// thread, so further viewers are rejected with 503. Must be lower than --max-workers
+    #[arg(
+        long = "max-mjpeg-viewers",
+        env = "MAX_MJPEG_VIEWERS",
+        default_value = "4",
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    pub max_mjpeg_viewers: u16,
+
+    /// Maximum age in seconds of a WS-Security UsernameToken `Created` timestamp
+    #[arg(
+        long = "ws-security-max-age",
+        env = "WS_SECURITY_MAX_AGE",
+        default_value = "300"
+    )]
+    pub ws_security_max_age: u64,
+
+    /// Failed logins from one client IP within the window before it gets 429 (0 disables)
+    #[arg(
+        long = "auth-max-failures",
+        env = "AUTH_MAX_FAILURES",
+        default_value = "10"
+    )]
+    pub auth_max_failures: u32,
+
+    /// Window in seconds over which failed logins are counted for throttling
+    #[arg(
+        long = "auth-failure-window",
+        env = "AUTH_FAILURE_WINDOW",
+        default_value = "60",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub auth_failure_window: u64,
+
+    /// Restart the services when a client sends SystemReboot (otherwise it is only acknowledged)
+    #[arg(long = "allow-reboot", env = "ALLOW_REBOOT", action = clap::ArgAction::SetTrue)]
+    pub allow_reboot: bool,
+
+    /// Operations to expose, comma-separated (all operations when empty)
+    #[arg(
+        long = "allow-operations",
+        env = "ALLOW_OPERATIONS",
+        value_delimiter = ','
+    )]
+    pub allow_operations: Vec<String>,
+
+    /// Operations to reject even if implemented, comma-separated (takes precedence over allow)
+    #[arg(
+        long = "deny-operations",
+        env = "DENY_OPERATIONS",
+        value_delimiter = ','
+    )]
+    pub deny_operations: Vec<String>,
+
+    /// Reject SOAP operations whose element namespace doesn't match the service they belong to
+    #[arg(long = "strict-soap", env = "STRICT_SOAP", action = clap::ArgAction::SetTrue)]
+    pub strict_soap: bool,
+
+    /// Report a synthetic MotionAlarm that toggles every 10s on the Events pull point
+    #[arg(long = "emit-test-events", env = "EMIT_TEST_EVENTS", action = clap::ArgAction::SetTrue)]
+    pub emit_test_events: bool,
+
+    /// Turn off the Events service: it is no longer advertised and its operations fault
+    #[arg(long = "disable-events", env = "DISABLE_EVENTS", action = clap::ArgAction::SetTrue)]
+    pub disable_events: bool,
+
+    /// Reverse proxies (CIDRs, comma-separated) whose X-Forwarded-For/Forwarded headers are trusted
+    #[arg(long = "trust-proxy", env = "TRUST_PROXY", value_delimiter = ',', value_parser = Cidr::parse)]
+    pub trust_proxy: Vec<Cidr>,
+
+    /// Build service and stream URLs from the request's Host header instead of CONTAINER_IP and ONVIF_PORT
+    #[arg(long = "use-host-header", env = "USE_HOST_HEADER", action = clap::ArgAction::SetTrue)]
+    pub use_host_header: bool,
+
     /// Enable debug mode with verbose request logging (NOT FOR PRODUCTION USE, LOGS SENSITIVE INFORMATION)
-    #[arg(short = 'd', long = "debug", action = clap::ArgAction::SetTrue)]
+    #[arg(short = 'd', long = "debug", env = "DEBUGLOGGING", action = clap::ArgAction::SetTrue)]
     pub debug: bool,
 }
 
 impl Config {
     pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
-        println!("Parsing command-line arguments...");
+        info!("Parsing command-line arguments...");
         let config = Config::parse();
 
+        let config = match &config.config_file {
+            Some(path) => {
+                info!("Loading configuration file {}", path.display());
+                Self::parse_with_file(path, std::env::args_os())?
+            }
+            None => config,
+        };
+
+        config.validate()?;
+        info!("Configuration creation completed successfully");
+        Ok(config)
+    }
+
+    /// Loads the configuration from a TOML file alone, with the same checks as `load()`
+    pub fn load_from_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let config = Self::parse_with_file(path, ["onvif-media-transcoder"])?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parses `args` with the settings of a TOML file merged underneath them
+    ///
+    /// File settings are turned into arguments placed before the command line, and
+    /// skipped for any setting the command line or environment provides, so both win.
+    fn parse_with_file<I, T>(path: &Path, args: I) -> Result<Self, Box<dyn std::error::Error>>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString>,
+    {
+        let args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+        let command = Config::command();
+        let matches = command.clone().try_get_matches_from(&args)?;
+
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config file {}: {e}", path.display()))?;
+        let table: toml::Table = contents
+            .parse()
+            .map_err(|e| format!("Invalid config file {}: {e}", path.display()))?;
+
+        let mut merged: Vec<OsString> = args.iter().take(1).cloned().collect();
+        for (key, value) in &table {
+            let long = command
+                .get_arguments()
+                .filter(|arg| arg.get_id() != "config_file")
+                .find(|arg| arg.get_id() == key.as_str())
+                .and_then(|arg| arg.get_long())
+                .ok_or_else(|| format!("Unknown setting '{key}' in config file"))?;
+            if matches!(
+                matches.value_source(key),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            ) {
+                continue;
+            }
+
+            let values = match value {
+                toml::Value::Array(items) => items.iter().collect(),
+                value => vec![value],
+            };
+            for value in values {
+                match value {
+                    toml::Value::Boolean(true) => merged.push(format!("--{long}").into()),
+                    toml::Value::Boolean(false) => {}
+                    toml::Value::String(s) => merged.push(format!("--{long}={s}").into()),
+                    toml::Value::Integer(_) | toml::Value::Float(_) => {
+                        merged.push(format!("--{long}={value}").into())
+                    }
+                    _ => return Err(format!("Unsupported value for '{key}' in config file").into()),
+                }
+            }
+        }
+        merged.extend(args.into_iter().skip(1));
+
+        Ok(Config::try_parse_from(merged)?)
+    }
+
+    /// Returns true if the ONVIF service is served over HTTPS
+    pub fn tls_enabled(&self) -> bool {
+        self.tls_cert.is_some()
+    }
+
+    /// Most PullMessages requests held open at once
+    ///
+    /// Half of the workers not reserved for MJPEG viewers, so held pulls can
+    /// never keep the pool from answering other requests.
+    pub fn max_held_pulls(&self) -> usize {
+        let reserved = if self.enable_mjpeg {
+            self.max_mjpeg_viewers
+        } else {
+            0
+        };
+        usize::from(self.max_workers.saturating_sub(reserved) / 2)
+    }
+
+    /// Checks the values clap can't validate on its own
+    fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let config = self;
+
         // Validate port number
-        println!("Validating port number...");
+        info!("Validating port number...");
         let _: u16 = config
             .onvif_port
             .parse()
             .map_err(|_| "ONVIF_PORT must be a valid port number")?;
-        println!("Port validation successful");
+        info!("Port validation successful");
 
         // Validate container IP is not empty
         if config.container_ip.is_empty() {
@@ -77,54 +533,316 @@ impl Config {
             .into());
         }
 
-        println!("Configuration creation completed successfully");
-        Ok(config)
+        // MJPEG viewers hold their worker, so some must be left for ONVIF requests
+        if config.enable_mjpeg && config.max_mjpeg_viewers >= config.max_workers {
+            return Err(format!(
+                "MAX_MJPEG_VIEWERS ({}) must be lower than MAX_WORKERS ({})",
+                config.max_mjpeg_viewers, config.max_workers
+            )
+            .into());
+        }
+
+        if let Some(lq_url) = &config.rtsp_stream_url_lq {
+            if !lq_url.starts_with("rtsp://") {
+                return Err(
+                    format!("RTSP_STREAM_URL_LQ must start with 'rtsp://', got: {lq_url}").into(),
+                );
+            }
+        }
+
+        // Validate push output URL format
+        if let Some(output_url) = &config.output_url {
+            if !crate::stream_output::is_supported_output_url(output_url) {
+                return Err(format!(
+                    "OUTPUT_URL must be an rtsp://, rtsps://, rtmp:// or rtmps:// URL, got: {output_url}"
+                )
+                .into());
+            }
+        }
+
+        if config.enable_relay || config.transcode {
+            if config.output_url.is_some() {
+                return Err(
+                    "--enable-relay and --transcode can't be combined with OUTPUT_URL push mode"
+                        .into(),
+                );
+            }
+            if !config.relay_path.starts_with('/') {
+                return Err(
+                    format!("RELAY_PATH must start with '/', got: {}", config.relay_path).into(),
+                );
+            }
+        }
+
+        if !X264_PRESETS.contains(&config.transcode_preset.as_str()) {
+            return Err(format!(
+                "TRANSCODE_PRESET must be one of {}, got: {}",
+                X264_PRESETS.join(", "),
+                config.transcode_preset
+            )
+            .into());
+        }
+
+        if let Some(first) = config.discovery_interfaces.first() {
+            if config
+                .discovery_interfaces
+                .iter()
+                .any(|ip| ip.is_ipv4() != first.is_ipv4())
+            {
+                return Err("DISCOVERY_INTERFACES must be all IPv4 or all IPv6 addresses".into());
+            }
+        }
+
+        crate::scopes::validate_configured(&config.scopes)
+            .map_err(|e| format!("Invalid SCOPES: {e}"))?;
+
+        crate::onvif::date_time::validate_time_zone(&config.timezone)
+            .map_err(|e| format!("Invalid TIMEZONE: {e}"))?;
+
+        crate::onvif::profiles::validate_token_prefix(&config.profile_token_prefix)?;
+        crate::onvif::profiles::validate_profiles(&config.profiles)?;
+        // --profile replaces the default HQ/LQ pair these settings apply to
+        if !config.profiles.is_empty() {
+            if config.rtsp_stream_url_lq.is_some() {
+                return Err("RTSP_STREAM_URL_LQ cannot be combined with --profile; give the profile an rtsp-url instead".into());
+            }
+            if !config.profile_token_prefix.is_empty() {
+                return Err("PROFILE_TOKEN_PREFIX cannot be combined with --profile; set the profile tokens directly".into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Interfaces WS-Discovery joins the multicast group on
+    ///
+    /// Falls back to the container IP when `--discovery-interfaces` isn't given.
+    pub fn discovery_interfaces(&self) -> Vec<IpAddr> {
+        if !self.discovery_interfaces.is_empty() {
+            return self.discovery_interfaces.clone();
+        }
+        self.container_ip.parse().into_iter().collect()
+    }
+
+    /// The container IP as it appears in URLs, with IPv6 literals in brackets
+    pub fn url_host(&self) -> String {
+        match self.container_ip.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => format!("[{ip}]"),
+            _ => self.container_ip.clone(),
+        }
+    }
+
+    /// Identity the device reports to clients, with the serial number filled in
+    pub fn device_information(&self) -> DeviceInformation {
+        DeviceInformation {
+            manufacturer: self.manufacturer.clone(),
+            model: self.device_name.clone(),
+            firmware_version: self.firmware_version.clone(),
+            serial_number: self.serial_number.clone().unwrap_or_else(|| {
+                format!(
+                    "EMU-{}",
+                    self.device_name.chars().take(6).collect::<String>()
+                )
+            }),
+            hardware_id: self.hardware_id.clone(),
+        }
+    }
+
+    /// UUID the device is advertised under as its WS-Discovery endpoint reference
+    ///
+    /// NVRs recognise a device by it, so it stays the same across restarts:
+    /// `--device-uuid`, or else a name-based UUID of the serial number and
+    /// device name. A device left with the default name and serial gets a
+    /// random one instead, since every such device would otherwise share it.
+    pub fn device_uuid(&self) -> Uuid {
+        if let Some(uuid) = self.device_uuid {
+            return uuid;
+        }
+        if self.serial_number.is_none() && self.device_name == "ONVIF-Media-Transcoder" {
+            return Uuid::new_v4();
+        }
+
+        // A version 5 UUID, as in RFC 9562
+        let name = format!(
+            "onvif-media-transcoder:{}:{}",
+            self.device_information().serial_number,
+            self.device_name
+        );
+        let digest = Sha1::new()
+            .chain_update(Uuid::NAMESPACE_URL.as_bytes())
+            .chain_update(name.as_bytes())
+            .finalize();
+        let mut bytes = [0; 16];
+        bytes.copy_from_slice(&digest[..16]);
+        uuid::Builder::from_sha1_bytes(bytes).into_uuid()
     }
 
     pub fn display(&self) {
-        println!("Configuration:");
+        info!("Configuration:");
+
+        if let Some(path) = &self.config_file {
+            info!("  Config File: {}", path.display());
+        }
 
         // Check if default values are being used and log accordingly
         if self.rtsp_stream_url == "rtsp://127.0.0.1:8554/stream" {
-            println!(
+            info!(
                 "  RTSP Input Stream: {} (using default)",
                 self.rtsp_stream_url
             );
         } else {
-            println!("  RTSP Input Stream: {}", self.rtsp_stream_url);
+            info!("  RTSP Input Stream: {}", self.rtsp_stream_url);
+        }
+
+        if let Some(lq_url) = &self.rtsp_stream_url_lq {
+            info!("  RTSP Input Stream (LQ): {lq_url}");
+        }
+
+        if self.validate_stream {
+            info!("  Startup Stream Check: REQUIRED (exit if unreachable)");
+        } else {
+            info!("  Startup Stream Check: warn only (using default)");
+        }
+
+        if self.rtsp_retries == 2 {
+            info!("  RTSP Retries: {} (using default)", self.rtsp_retries);
+        } else {
+            info!("  RTSP Retries: {}", self.rtsp_retries);
+        }
+
+        if self.rtsp_retry_delay_ms == 500 {
+            info!(
+                "  RTSP Retry Delay: {}ms (using default)",
+                self.rtsp_retry_delay_ms
+            );
+        } else {
+            info!("  RTSP Retry Delay: {}ms", self.rtsp_retry_delay_ms);
+        }
+
+        match &self.output_url {
+            Some(url) => info!("  Stream Output: push to {url}"),
+            None => info!("  Stream Output: serve locally (using default)"),
+        }
+
+        if self.enable_relay {
+            info!(
+                "  RTSP Relay: ENABLED via port {}{}",
+                self.relay_port, self.relay_path
+            );
+        } else {
+            info!("  RTSP Relay: disabled, advertising source URLs (using default)");
+        }
+
+        if self.transcode {
+            info!(
+                "  Transcoding: ENABLED to H264 at {} kbit/s ({} preset)",
+                self.transcode_bitrate, self.transcode_preset
+            );
+        } else {
+            info!("  Transcoding: disabled, sources are passed through (using default)");
+        }
+
+        match self.frame_rate {
+            Some(frame_rate) => info!("  Frame Rate: {frame_rate} fps"),
+            None => info!("  Frame Rate: follows source (using default)"),
+        }
+
+        if !self.profiles.is_empty() {
+            let tokens: Vec<&str> = self.profiles.iter().map(|p| p.token.as_str()).collect();
+            info!("  Media Profiles: {}", tokens.join(", "));
+        }
+
+        if !self.profile_token_prefix.is_empty() {
+            info!("  Profile Token Prefix: {}", self.profile_token_prefix);
         }
 
         if self.onvif_port == "8080" {
-            println!("  ONVIF Port: {} (using default)", self.onvif_port);
+            info!("  ONVIF Port: {} (using default)", self.onvif_port);
         } else {
-            println!("  ONVIF Port: {}", self.onvif_port);
+            info!("  ONVIF Port: {}", self.onvif_port);
+        }
+
+        if self.bind_address == IpAddr::from([0, 0, 0, 0]) {
+            info!("  Bind Address: {} (using default)", self.bind_address);
+        } else {
+            info!("  Bind Address: {}", self.bind_address);
+        }
+
+        match &self.tls_cert {
+            Some(cert) => info!("  TLS: enabled (certificate {})", cert.display()),
+            None => info!("  TLS: disabled (using default)"),
         }
 
         if self.device_name == "ONVIF-Media-Transcoder" {
-            println!("  Device Name: {} (using default)", self.device_name);
+            info!("  Device Name: {} (using default)", self.device_name);
         } else {
-            println!("  Device Name: {}", self.device_name);
+            info!("  Device Name: {}", self.device_name);
+        }
+
+        if self.manufacturer == "ONVIF Media Solutions" {
+            info!("  Manufacturer: {} (using default)", self.manufacturer);
+        } else {
+            info!("  Manufacturer: {}", self.manufacturer);
+        }
+
+        if self.firmware_version == "1.0.0" {
+            info!(
+                "  Firmware Version: {} (using default)",
+                self.firmware_version
+            );
+        } else {
+            info!("  Firmware Version: {}", self.firmware_version);
+        }
+
+        if self.hardware_id == "onvif-media-transcoder" {
+            info!("  Hardware ID: {} (using default)", self.hardware_id);
+        } else {
+            info!("  Hardware ID: {}", self.hardware_id);
+        }
+
+        match &self.serial_number {
+            Some(serial_number) => info!("  Serial Number: {serial_number}"),
+            None => info!(
+                "  Serial Number: {} (using default)",
+                self.device_information().serial_number
+            ),
+        }
+
+        if let Some(uuid) = &self.device_uuid {
+            info!("  Device UUID: {uuid}");
+        }
+
+        if self.timezone == "UTC" {
+            info!("  Time Zone: {} (using default)", self.timezone);
+        } else {
+            info!("  Time Zone: {}", self.timezone);
+        }
+
+        if self.dst {
+            info!("  Daylight Saving Time: IN EFFECT");
+        } else {
+            info!("  Daylight Saving Time: not in effect (using default)");
         }
 
         if self.onvif_username == "admin" {
-            println!("  ONVIF Username: {} (using default)", self.onvif_username);
+            info!("  ONVIF Username: {} (using default)", self.onvif_username);
         } else {
-            println!("  ONVIF Username: {}", self.onvif_username);
+            info!("  ONVIF Username: {}", self.onvif_username);
         }
 
         if self.onvif_password == "onvif-rust" {
-            println!("  ONVIF Password: [HIDDEN] (using default)");
+            info!("  ONVIF Password: [HIDDEN] (using default)");
         } else {
-            println!("  ONVIF Password: [HIDDEN]");
+            info!("  ONVIF Password: [HIDDEN]");
         }
 
         if self.container_ip == "127.0.0.1" {
-            println!("  Container IP: {} (using default)", self.container_ip);
+            info!("  Container IP: {} (using default)", self.container_ip);
         } else {
-            println!("  Container IP: {}", self.container_ip);
+            info!("  Container IP: {}", self.container_ip);
         }
 
-        println!(
+        info!(
             "  WS-Discovery: {}",
             if self.ws_discovery_enabled {
                 "ENABLED"
@@ -133,10 +851,434 @@ impl Config {
             }
         );
 
+        if self.ws_discovery_enabled {
+            info!(
+                "  Windows WSD Probes: {}",
+                if self.enable_windows_wsd {
+                    "ENABLED"
+                } else {
+                    "DISABLED"
+                }
+            );
+            let interfaces: Vec<String> = self
+                .discovery_interfaces()
+                .iter()
+                .map(IpAddr::to_string)
+                .collect();
+            info!("  Discovery Interfaces: {}", interfaces.join(", "));
+            if self.hello_interval == 60 {
+                info!("  Hello Interval: {}s (using default)", self.hello_interval);
+            } else {
+                info!("  Hello Interval: {}s", self.hello_interval);
+            }
+            if self.discovery_max_delay_ms == 500 {
+                info!(
+                    "  Discovery Max Response Delay: {}ms (using default)",
+                    self.discovery_max_delay_ms
+                );
+            } else {
+                info!(
+                    "  Discovery Max Response Delay: {}ms",
+                    self.discovery_max_delay_ms
+                );
+            }
+            if let Some(ip) = self.discovery_management_ip {
+                info!("  Discovery Management Address: {ip}:3702 (unicast)");
+            }
+            info!(
+                "  Discovery Follows RTSP Health: {}",
+                if self.discovery_follow_rtsp {
+                    "ENABLED"
+                } else {
+                    "DISABLED"
+                }
+            );
+        }
+
+        if self.scopes.is_empty() {
+            info!("  Extra Scopes: none (using default)");
+        } else {
+            info!("  Extra Scopes: {}", self.scopes.join(" "));
+        }
+
+        if self.max_workers == 16 {
+            info!("  Max Workers: {} (using default)", self.max_workers);
+        } else {
+            info!("  Max Workers: {}", self.max_workers);
+        }
+
+        if self.snapshot_cache_ms == 1000 {
+            info!(
+                "  Snapshot Cache: {}ms (using default)",
+                self.snapshot_cache_ms
+            );
+        } else {
+            info!("  Snapshot Cache: {}ms", self.snapshot_cache_ms);
+        }
+
+        if self.ffmpeg_path == "ffmpeg" {
+            info!("  FFmpeg Path: {} (using default)", self.ffmpeg_path);
+        } else {
+            info!("  FFmpeg Path: {}", self.ffmpeg_path);
+        }
+
+        if self.ffprobe_path == "ffprobe" {
+            info!("  FFprobe Path: {} (using default)", self.ffprobe_path);
+        } else {
+            info!("  FFprobe Path: {}", self.ffprobe_path);
+        }
+
+        if self.public_snapshot {
+            info!("  Snapshot Access: PUBLIC (no authentication)");
+        } else {
+            info!("  Snapshot Access: authenticated (using default)");
+        }
+
+        if self.snapshot_quality == 2 {
+            info!(
+                "  Snapshot Quality: {} (using default)",
+                self.snapshot_quality
+            );
+        } else {
+            info!("  Snapshot Quality: {}", self.snapshot_quality);
+        }
+
+        match (self.snapshot_max_width, self.snapshot_max_height) {
+            (None, None) => info!("  Snapshot Size: full resolution (using default)"),
+            (width, height) => info!(
+                "  Snapshot Size: at most {}x{}",
+                width.map_or("any".to_string(), |w| w.to_string()),
+                height.map_or("any".to_string(), |h| h.to_string())
+            ),
+        }
+
+        if self.snapshot_timeout == 20 {
+            info!(
+                "  Snapshot Timeout: {}s (using default)",
+                self.snapshot_timeout
+            );
+        } else {
+            info!("  Snapshot Timeout: {}s", self.snapshot_timeout);
+        }
+
+        if self.enable_mjpeg {
+            info!("  MJPEG Stream: ENABLED at /stream.mjpeg");
+            if self.max_mjpeg_viewers == 4 {
+                info!(
+                    "  Max MJPEG Viewers: {} (using default)",
+                    self.max_mjpeg_viewers
+                );
+            } else {
+                info!("  Max MJPEG Viewers: {}", self.max_mjpeg_viewers);
+            }
+        } else {
+            info!("  MJPEG Stream: disabled (using default)");
+        }
+
+        if self.ws_security_max_age == 300 {
+            info!(
+                "  WS-Security Max Age: {}s (using default)",
+                self.ws_security_max_age
+            );
+        } else {
+            info!("  WS-Security Max Age: {}s", self.ws_security_max_age);
+        }
+
+        if self.auth_max_failures == 0 {
+            info!("  Login Throttling: disabled");
+        } else if self.auth_max_failures == 10 && self.auth_failure_window == 60 {
+            info!(
+                "  Login Throttling: {} failures per {}s (using default)",
+                self.auth_max_failures, self.auth_failure_window
+            );
+        } else {
+            info!(
+                "  Login Throttling: {} failures per {}s",
+                self.auth_max_failures, self.auth_failure_window
+            );
+        }
+
+        if self.allow_reboot {
+            info!("  System Reboot: ENABLED (restarts the services)");
+        } else {
+            info!("  System Reboot: acknowledged only (using default)");
+        }
+
+        if self.allow_operations.is_empty() {
+            info!("  Allowed Operations: ALL (using default)");
+        } else {
+            info!("  Allowed Operations: {}", self.allow_operations.join(", "));
+        }
+
+        if !self.deny_operations.is_empty() {
+            info!("  Denied Operations: {}", self.deny_operations.join(", "));
+        }
+
+        if !self.trust_proxy.is_empty() {
+            let proxies: Vec<String> = self.trust_proxy.iter().map(Cidr::to_string).collect();
+            info!("  Trusted Proxies: {}", proxies.join(", "));
+        }
+
+        if self.use_host_header {
+            info!("  Advertised URLs: from the request Host header");
+        }
+
+        if self.disable_events {
+            info!("  Events Service: DISABLED");
+        } else if self.emit_test_events {
+            info!("  Test Events: ENABLED (synthetic MotionAlarm)");
+        }
+
+        info!(
+            "  Strict SOAP Namespaces: {}",
+            if self.strict_soap {
+                "ENABLED"
+            } else {
+                "DISABLED"
+            }
+        );
+
         if self.debug {
-            println!("  Debug Mode: ENABLED (verbose request logging)");
+            info!("  Debug Mode: ENABLED (verbose request logging)");
         } else {
-            println!("  Debug Mode: DISABLED");
+            info!("  Debug Mode: DISABLED");
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+    use std::io::Write;
+
+    /// Runs `f` with the given environment variables set, removing them afterwards
+    fn with_env<T>(vars: &[(&str, &str)], f: impl FnOnce() -> T) -> T {
+        for (name, value) in vars {
+            std::env::set_var(name, value);
+        }
+        let result = f();
+        for (name, _) in vars {
+            std::env::remove_var(name);
+        }
+        result
+    }
+
+    fn write_config(contents: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        file
+    }
+
+    #[test]
+    #[serial]
+    fn test_load_from_file() {
+        let file = write_config(
+            r#"
+rtsp_stream_url = "rtsp://10.0.0.5:8554/cam"
+onvif_port = 8081
+device_name = "Garage"
+onvif_password = "-starts-with-dash"
+container_ip = "10.0.0.2"
+ws_discovery_enabled = true
+ws_security_max_age = 120
+allow_operations = ["GetProfiles", "GetStreamUri"]
+frame_rate = 20
+profiles = ["token=main,resolution=1920x1080", "token=sub,resolution=640x360"]
+"#,
+        );
+
+        let config = Config::load_from_file(file.path()).unwrap();
+        assert_eq!(config.rtsp_stream_url, "rtsp://10.0.0.5:8554/cam");
+        assert_eq!(config.onvif_port, "8081");
+        assert_eq!(config.device_name, "Garage");
+        assert_eq!(config.onvif_password, "-starts-with-dash");
+        assert_eq!(config.container_ip, "10.0.0.2");
+        assert!(config.ws_discovery_enabled);
+        assert_eq!(config.ws_security_max_age, 120);
+        assert_eq!(config.allow_operations, ["GetProfiles", "GetStreamUri"]);
+        assert_eq!(config.frame_rate, Some(20));
+        assert_eq!(config.profiles.len(), 2);
+        assert_eq!(config.profiles[1].token, "sub");
+        // Unset settings keep their defaults
+        assert_eq!(config.onvif_username, "admin");
+    }
+
+    #[test]
+    #[serial]
+    fn test_command_line_overrides_file() {
+        let file = write_config(
+            "device_name = \"From File\"\nonvif_port = \"9000\"\nallow_operations = [\"GetProfiles\"]\n",
+        );
+
+        let config = Config::parse_with_file(
+            file.path(),
+            [
+                "onvif-media-transcoder",
+                "-n",
+                "From CLI",
+                "--allow-operations",
+                "GetStreamUri",
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.device_name, "From CLI");
+        assert_eq!(config.onvif_port, "9000");
+        assert_eq!(config.allow_operations, ["GetStreamUri"]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_invalid_file_settings_are_rejected() {
+        let unknown = write_config("no_such_setting = 1\n");
+        assert!(Config::load_from_file(unknown.path()).is_err());
+
+        let invalid = write_config("rtsp_stream_url = \"http://not-rtsp\"\n");
+        assert!(Config::load_from_file(invalid.path()).is_err());
+
+        assert!(Config::load_from_file(Path::new("/nonexistent/config.toml")).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_device_uuid_is_stable_across_restarts() {
+        let parse = |args: &[&str]| {
+            let mut argv = vec!["onvif-media-transcoder"];
+            argv.extend_from_slice(args);
+            Config::try_parse_from(argv).unwrap()
+        };
+
+        // The same configuration always gives the same endpoint reference
+        let first = parse(&["-n", "Front Door"]).device_uuid();
+        assert_eq!(parse(&["-n", "Front Door"]).device_uuid(), first);
+        assert_eq!(first.get_version_num(), 5);
+        assert_ne!(parse(&["-n", "Back Door"]).device_uuid(), first);
+        assert_ne!(
+            parse(&["-n", "Front Door", "--serial-number", "SN-2"]).device_uuid(),
+            first
+        );
+
+        let explicit = "6f1a3c2e-8d4b-4e6a-9c1f-2b3d4e5f6a7b";
+        let config = parse(&["-n", "Front Door", "--device-uuid", explicit]);
+        assert_eq!(config.device_uuid().to_string(), explicit);
+
+        // Unconfigured devices don't all claim the same identity
+        assert_ne!(parse(&[]).device_uuid(), parse(&[]).device_uuid());
+        assert!(
+            Config::try_parse_from(["onvif-media-transcoder", "--device-uuid", "not-a-uuid"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_mjpeg_viewers_must_leave_workers_free() {
+        let parse = |args: &[&str]| {
+            let mut argv = vec!["onvif-media-transcoder"];
+            argv.extend_from_slice(args);
+            Config::try_parse_from(argv).unwrap()
+        };
+
+        assert!(parse(&["--enable-mjpeg"]).validate().is_ok());
+        assert!(parse(&["--enable-mjpeg", "--max-workers", "4"])
+            .validate()
+            .is_err());
+        assert!(parse(&[
+            "--enable-mjpeg",
+            "--max-workers",
+            "4",
+            "--max-mjpeg-viewers",
+            "3"
+        ])
+        .validate()
+        .is_ok());
+        // The cap doesn't matter while MJPEG is off
+        assert!(parse(&["--max-workers", "1"]).validate().is_ok());
+    }
+
+    #[test]
+    #[serial]
+    fn test_held_pulls_leave_workers_free() {
+        let parse = |args: &[&str]| {
+            let mut argv = vec!["onvif-media-transcoder"];
+            argv.extend_from_slice(args);
+            Config::try_parse_from(argv).unwrap()
+        };
+
+        assert_eq!(parse(&[]).max_held_pulls(), 8);
+        assert_eq!(parse(&["--enable-mjpeg"]).max_held_pulls(), 6);
+        assert_eq!(parse(&["--max-workers", "1"]).max_held_pulls(), 0);
+    }
+
+    #[test]
+    #[serial]
+    fn test_profiles_replace_default_profile_settings() {
+        let parse = |args: &[&str]| {
+            let mut argv = vec!["onvif-media-transcoder"];
+            argv.extend_from_slice(args);
+            Config::try_parse_from(argv).unwrap()
+        };
+
+        assert!(parse(&["--profile", "token=main"]).validate().is_ok());
+        assert!(parse(&[
+            "--profile",
+            "token=main",
+            "--rtsp-stream-url-lq",
+            "rtsp://cam/sub"
+        ])
+        .validate()
+        .is_err());
+        assert!(
+            parse(&["--profile", "token=main", "--profile-token-prefix", "cam1_"])
+                .validate()
+                .is_err()
+        );
+        assert!(parse(&[
+            "--rtsp-stream-url-lq",
+            "rtsp://cam/sub",
+            "--profile-token-prefix",
+            "cam1_"
+        ])
+        .validate()
+        .is_ok());
+    }
+
+    #[test]
+    #[serial]
+    fn test_environment_variables_fill_unset_arguments() {
+        let config = with_env(
+            &[
+                ("DEVICE_NAME", "Env Camera"),
+                ("WS_SECURITY_MAX_AGE", "60"),
+                ("ENABLE_WINDOWS_WSD", "true"),
+                ("DEBUGLOGGING", "false"),
+            ],
+            || Config::try_parse_from(["onvif-media-transcoder"]).unwrap(),
+        );
+        assert_eq!(config.device_name, "Env Camera");
+        assert_eq!(config.ws_security_max_age, 60);
+        assert!(config.enable_windows_wsd);
+        assert!(!config.debug);
+        assert_eq!(config.onvif_username, "admin");
+    }
+
+    #[test]
+    #[serial]
+    fn test_precedence_is_cli_then_env_then_file() {
+        let file = write_config("device_name = \"From File\"\nws_security_max_age = 30\nonvif_username = \"file-user\"\n");
+
+        let config = with_env(
+            &[("DEVICE_NAME", "From Env"), ("WS_SECURITY_MAX_AGE", "45")],
+            || {
+                Config::parse_with_file(
+                    file.path(),
+                    ["onvif-media-transcoder", "--ws-security-max-age", "90"],
+                )
+                .unwrap()
+            },
+        );
+        assert_eq!(config.ws_security_max_age, 90);
+        assert_eq!(config.device_name, "From Env");
+        assert_eq!(config.onvif_username, "file-user");
+    }
+}
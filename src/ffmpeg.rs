@@ -0,0 +1,214 @@
+// FFmpeg Tools
+// Snapshots, the MJPEG stream, the relay and stream probing all run ffmpeg or
+// ffprobe, by default from PATH. When a tool can't be found the errors name it
+// and say how to fix that, and startup warns once instead of every request
+// failing with a bare "No such file or directory". Captures and probes run
+// through a CommandRunner, so tests can stand in for the real tools.
+
+use crate::config::Config;
+use log::{info, warn};
+use std::io::{self, Read};
+use std::process::{Command, Output, Stdio};
+use std::time::{Duration, Instant};
+
+/// How often the watchdog checks whether a command has exited
+const WATCHDOG_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Runs external programs such as ffmpeg and ffprobe to completion
+pub trait CommandRunner {
+    /// Runs `program` with `args`, returning its exit status and output
+    ///
+    /// A command still running after `timeout` is killed and reported as an
+    /// `ErrorKind::TimedOut` error.
+    fn run(&self, program: &str, args: &[String], timeout: Duration) -> io::Result<Output>;
+}
+
+/// Runs commands as child processes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemCommandRunner;
+
+impl CommandRunner for SystemCommandRunner {
+    /// ffmpeg's own `-timeout` only covers connecting, so a source that accepts
+    /// the connection but never sends a frame would otherwise hold the caller forever.
+    fn run(&self, program: &str, args: &[String], timeout: Duration) -> io::Result<Output> {
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Drain both pipes while waiting so a full pipe can't stall the child
+        let stdout = drain(child.stdout.take());
+        let stderr = drain(child.stderr.take());
+
+        let deadline = Instant::now() + timeout;
+        let status = loop {
+            match child.try_wait()? {
+                Some(status) => break status,
+                None if Instant::now() >= deadline => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("{program} was killed after {timeout:?}"),
+                    ));
+                }
+                None => std::thread::sleep(WATCHDOG_POLL_INTERVAL),
+            }
+        };
+
+        Ok(Output {
+            status,
+            stdout: stdout.join().unwrap_or_default(),
+            stderr: stderr.join().unwrap_or_default(),
+        })
+    }
+}
+
+/// Reads `pipe` to the end on a separate thread
+fn drain(pipe: Option<impl Read + Send + 'static>) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut data = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut data);
+        }
+        data
+    })
+}
+
+/// Answers commands with canned results instead of running them, recording each call
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MockCommandRunner {
+    results: std::sync::Mutex<std::collections::VecDeque<io::Result<Output>>>,
+    calls: std::sync::Mutex<Vec<(String, Vec<String>)>>,
+}
+
+#[cfg(test)]
+impl MockCommandRunner {
+    /// Answers successive commands with `results`; once they run out, commands aren't found
+    pub fn new(results: impl IntoIterator<Item = io::Result<Output>>) -> Self {
+        Self {
+            results: std::sync::Mutex::new(results.into_iter().collect()),
+            calls: Default::default(),
+        }
+    }
+
+    /// A command that exited with `code` after writing `stdout` and `stderr`
+    pub fn exited(code: i32, stdout: &[u8], stderr: &[u8]) -> io::Result<Output> {
+        use std::os::unix::process::ExitStatusExt;
+        Ok(Output {
+            status: std::process::ExitStatus::from_raw(code << 8),
+            stdout: stdout.to_vec(),
+            stderr: stderr.to_vec(),
+        })
+    }
+
+    /// Programs and arguments run so far
+    pub fn calls(&self) -> Vec<(String, Vec<String>)> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl CommandRunner for MockCommandRunner {
+    fn run(&self, program: &str, args: &[String], _timeout: Duration) -> io::Result<Output> {
+        self.calls
+            .lock()
+            .unwrap()
+            .push((program.to_string(), args.to_vec()));
+        self.results
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_else(|| Err(io::ErrorKind::NotFound.into()))
+    }
+}
+
+/// Explains that `tool` isn't installed at `path` and how to fix it
+pub fn missing_tool_message(tool: &str, path: &str) -> String {
+    format!(
+        "{tool} not found at '{path}' - install FFmpeg (e.g. `apt-get install ffmpeg` or `apk add ffmpeg`) or point --{tool}-path at the binary"
+    )
+}
+
+/// Describes a failure to start `tool` from `path`, naming the missing tool when that's the cause
+pub fn spawn_error_message(tool: &str, path: &str, error: &io::Error) -> String {
+    if error.kind() == io::ErrorKind::NotFound {
+        missing_tool_message(tool, path)
+    } else {
+        format!("failed to execute {tool} ('{path}'): {error}")
+    }
+}
+
+/// Runs `path -version` to check that `tool` can be started
+pub fn check_tool(tool: &str, path: &str) -> Result<(), String> {
+    Command::new(path)
+        .arg("-version")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|_| ())
+        .map_err(|e| spawn_error_message(tool, path, &e))
+}
+
+/// Warns at startup for each of ffmpeg and ffprobe that can't be started
+///
+/// The ONVIF service still runs without them, advertising default stream
+/// parameters, so a missing tool isn't fatal.
+pub fn check_tools(config: &Config) {
+    for (tool, path) in [
+        ("ffmpeg", &config.ffmpeg_path),
+        ("ffprobe", &config.ffprobe_path),
+    ] {
+        match check_tool(tool, path) {
+            Ok(()) => info!("Found {tool} at '{path}'"),
+            Err(e) => warn!("{e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_binary_gets_a_friendly_message() {
+        let error = check_tool("ffmpeg", "/nonexistent/bin/ffmpeg").unwrap_err();
+        assert_eq!(
+            error,
+            "ffmpeg not found at '/nonexistent/bin/ffmpeg' - install FFmpeg (e.g. `apt-get install ffmpeg` or `apk add ffmpeg`) or point --ffmpeg-path at the binary"
+        );
+
+        // Other failures keep the underlying error
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        let message = spawn_error_message("ffprobe", "/opt/ffprobe", &denied);
+        assert!(message.starts_with("failed to execute ffprobe ('/opt/ffprobe'): "));
+
+        assert!(check_tool("ffmpeg", "true").is_ok());
+    }
+
+    #[test]
+    fn test_watchdog_kills_slow_command() {
+        let started = Instant::now();
+        let error = SystemCommandRunner
+            .run("sleep", &["10".to_string()], Duration::from_millis(200))
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // A command finishing in time is returned with its output
+        let output = SystemCommandRunner
+            .run("echo", &["frame".to_string()], Duration::from_secs(5))
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"frame\n");
+
+        let error = SystemCommandRunner
+            .run("/nonexistent/bin/ffmpeg", &[], Duration::from_secs(5))
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,101 @@
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// Source of message IDs and nonces
+///
+/// Production code uses `RandomIdGenerator`; tests can inject a
+/// `SeededIdGenerator` to get reproducible values they can assert on.
+pub trait IdGenerator: std::fmt::Debug + Send + Sync {
+    /// Returns a UUID string (e.g. for WS-Addressing MessageIDs)
+    fn new_id(&self) -> String;
+    /// Returns a 32-character hex nonce (e.g. for HTTP Digest challenges)
+    fn new_nonce(&self) -> String;
+}
+
+/// Random v4 UUIDs and nonces
+#[derive(Debug, Default)]
+pub struct RandomIdGenerator;
+
+impl IdGenerator for RandomIdGenerator {
+    fn new_id(&self) -> String {
+        Uuid::new_v4().to_string()
+    }
+
+    fn new_nonce(&self) -> String {
+        Uuid::new_v4().simple().to_string()
+    }
+}
+
+/// Deterministic generator: the same seed always yields the same sequence
+#[derive(Debug)]
+pub struct SeededIdGenerator {
+    state: Mutex<u64>,
+}
+
+impl SeededIdGenerator {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: Mutex::new(seed),
+        }
+    }
+
+    /// Produces the next 16 bytes of the splitmix64 sequence
+    fn next_bytes(&self) -> [u8; 16] {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let mut bytes = [0u8; 16];
+        for chunk in bytes.chunks_mut(8) {
+            *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = *state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^= z >> 31;
+            chunk.copy_from_slice(&z.to_be_bytes());
+        }
+        bytes
+    }
+}
+
+impl IdGenerator for SeededIdGenerator {
+    fn new_id(&self) -> String {
+        uuid::Builder::from_random_bytes(self.next_bytes())
+            .into_uuid()
+            .to_string()
+    }
+
+    fn new_nonce(&self) -> String {
+        self.next_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded_generator_is_reproducible() {
+        let first = SeededIdGenerator::new(42);
+        let second = SeededIdGenerator::new(42);
+        assert_eq!(first.new_id(), second.new_id());
+        assert_eq!(first.new_nonce(), second.new_nonce());
+        assert_ne!(first.new_id(), first.new_id());
+
+        let id = SeededIdGenerator::new(7).new_id();
+        let parsed = Uuid::parse_str(&id).unwrap();
+        assert_eq!(parsed.get_version_num(), 4);
+    }
+
+    #[test]
+    fn test_random_generator_formats() {
+        let ids = RandomIdGenerator;
+        assert_eq!(ids.new_id().len(), 36);
+        let nonce = ids.new_nonce();
+        assert_eq!(nonce.len(), 32);
+        assert!(nonce.chars().all(|c| c.is_ascii_hexdigit()));
+    }
+}
@@ -1,3 +1,14 @@
 pub mod config;
+pub mod ffmpeg;
+pub mod id_generator;
 pub mod onvif;
+pub mod relay;
+pub mod rtsp_health;
+pub mod scopes;
+mod service;
+pub mod service_status;
+pub mod stream_output;
+pub mod stream_probe;
 pub mod ws_discovery;
+
+pub use service::run;
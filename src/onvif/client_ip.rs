@@ -0,0 +1,214 @@
+// Client IP Resolution
+// Derives the real client address when requests arrive through trusted reverse proxies
+
+use crate::onvif::http::header_values;
+use std::net::IpAddr;
+
+/// An IP network in CIDR notation (e.g. `10.0.0.0/8`, `fd00::/8`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Parses `addr/len`; a bare address is treated as a single host
+    pub fn parse(cidr: &str) -> Result<Self, String> {
+        let cidr = cidr.trim();
+        let (addr, prefix_len) = match cidr.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (cidr, None),
+        };
+        let network: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("Invalid CIDR '{cidr}': bad address"))?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| format!("Invalid CIDR '{cidr}': bad prefix length"))?,
+            None => max_len,
+        };
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+
+    /// Returns true if the address falls inside this network
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V4(network), IpAddr::V6(ip)) => ip
+                .to_ipv4_mapped()
+                .is_some_and(|ip| prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)),
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+fn prefix_matches(network: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = (prefix_len / 8) as usize;
+    if network[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+    let remaining_bits = prefix_len % 8;
+    if remaining_bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - remaining_bits);
+    network[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+/// Returns the IP address of the client that originated the request
+///
+/// Forwarding headers are only honoured when the TCP peer is a trusted proxy.
+/// The chain is walked from the nearest hop outwards, skipping trusted proxies,
+/// so a client can't spoof its address by sending its own forwarding header.
+pub fn resolve_client_ip(peer: IpAddr, request: &str, trusted_proxies: &[Cidr]) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted_proxies.iter().any(|cidr| cidr.contains(ip));
+    if !is_trusted(peer) {
+        return peer;
+    }
+
+    let mut chain = forwarded_chain(request);
+    if chain.is_empty() {
+        return peer;
+    }
+
+    let mut client = peer;
+    while let Some(hop) = chain.pop() {
+        client = hop;
+        if !is_trusted(hop) {
+            break;
+        }
+    }
+    client
+}
+
+/// Collects the forwarded-for addresses, client first, from `Forwarded` or `X-Forwarded-For`
+fn forwarded_chain(request: &str) -> Vec<IpAddr> {
+    let forwarded = header_values(request, "forwarded");
+    if !forwarded.is_empty() {
+        // RFC 7239: for=192.0.2.60;proto=http, for="[2001:db8:cafe::17]:4711"
+        return forwarded
+            .iter()
+            .flat_map(|value| value.split(','))
+            .filter_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.trim().split_once('=')?;
+                    key.trim()
+                        .eq_ignore_ascii_case("for")
+                        .then(|| parse_forwarded_node(value))?
+                })
+            })
+            .collect();
+    }
+
+    header_values(request, "x-forwarded-for")
+        .iter()
+        .flat_map(|value| value.split(','))
+        .filter_map(parse_forwarded_node)
+        .collect()
+}
+
+/// Parses a forwarded node, accepting optional quotes, IPv6 brackets and a port
+fn parse_forwarded_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    node.rsplit_once(':')?.0.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trusted(cidrs: &[&str]) -> Vec<Cidr> {
+        cidrs
+            .iter()
+            .map(|cidr| Cidr::parse(cidr).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        let cidr = Cidr::parse("10.0.0.0/8").unwrap();
+        assert!(cidr.contains("10.1.2.3".parse().unwrap()));
+        assert!(!cidr.contains("11.0.0.1".parse().unwrap()));
+        assert!(cidr.contains("::ffff:10.0.0.1".parse().unwrap()));
+
+        let cidr = Cidr::parse("192.168.1.128/25").unwrap();
+        assert!(cidr.contains("192.168.1.200".parse().unwrap()));
+        assert!(!cidr.contains("192.168.1.100".parse().unwrap()));
+
+        let host = Cidr::parse("fd00::1").unwrap();
+        assert!(host.contains("fd00::1".parse().unwrap()));
+        assert!(!host.contains("fd00::2".parse().unwrap()));
+
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("not-an-ip/8").is_err());
+    }
+
+    #[test]
+    fn test_trusted_proxy_forwarded_ip_is_used() {
+        let proxies = trusted(&["10.0.0.0/8"]);
+        let request =
+            "GET /info HTTP/1.1\r\nHost: cam\r\nX-Forwarded-For: 203.0.113.7, 10.0.0.2\r\n\r\n";
+        assert_eq!(
+            resolve_client_ip("10.0.0.1".parse().unwrap(), request, &proxies),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+
+        let request =
+            "GET /info HTTP/1.1\r\nForwarded: for=\"[2001:db8::17]:4711\";proto=http\r\n\r\n";
+        assert_eq!(
+            resolve_client_ip("10.0.0.1".parse().unwrap(), request, &proxies),
+            "2001:db8::17".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_untrusted_peer_forwarded_header_is_ignored() {
+        let proxies = trusted(&["10.0.0.0/8"]);
+        let request = "GET /info HTTP/1.1\r\nX-Forwarded-For: 203.0.113.7\r\n\r\n";
+        assert_eq!(
+            resolve_client_ip("198.51.100.9".parse().unwrap(), request, &proxies),
+            "198.51.100.9".parse::<IpAddr>().unwrap()
+        );
+
+        // With no trusted proxies configured the header is never used
+        assert_eq!(
+            resolve_client_ip("10.0.0.1".parse().unwrap(), request, &[]),
+            "10.0.0.1".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_spoofed_entries_before_untrusted_hop_are_ignored() {
+        // The client prepended a fake address; the proxy appended the real one
+        let proxies = trusted(&["10.0.0.0/8"]);
+        let request = "GET /info HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4, 203.0.113.7\r\n\r\n";
+        assert_eq!(
+            resolve_client_ip("10.0.0.1".parse().unwrap(), request, &proxies),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+    }
+}
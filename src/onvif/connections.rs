@@ -0,0 +1,162 @@
+// Active Connections
+// The connections the workers are serving, so shutdown can drain them: idle
+// keep-alive connections are closed at once, requests in progress get until
+// the drain timeout to finish their response, and whatever is left is cut off.
+
+use log::{info, warn};
+use std::collections::HashMap;
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// How often draining checks whether the in-progress requests have finished
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+#[derive(Debug)]
+struct TrackedConnection {
+    /// Handle used to close the connection; `None` if the socket couldn't be cloned
+    socket: Option<TcpStream>,
+    /// A request has been read and its response isn't written yet
+    busy: bool,
+}
+
+/// Connections being served, shared by the workers and shutdown
+#[derive(Debug, Default)]
+pub struct ActiveConnections {
+    connections: Mutex<HashMap<u64, TrackedConnection>>,
+    next_id: AtomicU64,
+    draining: AtomicBool,
+}
+
+impl ActiveConnections {
+    fn lock(&self) -> MutexGuard<'_, HashMap<u64, TrackedConnection>> {
+        match self.connections.lock() {
+            Ok(connections) => connections,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Tracks a connection until the returned guard is dropped
+    pub fn track(&self, socket: Option<TcpStream>) -> TrackedGuard<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.lock().insert(
+            id,
+            TrackedConnection {
+                socket,
+                busy: false,
+            },
+        );
+        TrackedGuard {
+            connections: self,
+            id,
+        }
+    }
+
+    /// Returns true once shutdown has started; responses then close their connection
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Closes every idle connection and waits up to `timeout` for the busy ones
+    ///
+    /// Connections still busy at the deadline are closed too. Returns how many
+    /// were cut off mid-request.
+    pub fn drain(&self, timeout: Duration) -> usize {
+        self.draining.store(true, Ordering::SeqCst);
+        let deadline = Instant::now() + timeout;
+        let mut announced = false;
+        loop {
+            let busy = {
+                let connections = self.lock();
+                for connection in connections.values().filter(|connection| !connection.busy) {
+                    close(connection);
+                }
+                connections
+                    .values()
+                    .filter(|connection| connection.busy)
+                    .count()
+            };
+            if busy == 0 {
+                return 0;
+            }
+            if Instant::now() >= deadline {
+                warn!("{busy} request(s) still in progress after {timeout:?} - closing them");
+                self.lock().values().for_each(close);
+                return busy;
+            }
+            if !announced {
+                info!("Waiting for {busy} request(s) in progress to finish...");
+                announced = true;
+            }
+            std::thread::sleep(DRAIN_POLL_INTERVAL);
+        }
+    }
+}
+
+fn close(connection: &TrackedConnection) {
+    if let Some(socket) = &connection.socket {
+        let _ = socket.shutdown(Shutdown::Both);
+    }
+}
+
+/// Keeps a connection tracked while it is being served
+#[derive(Debug)]
+pub struct TrackedGuard<'a> {
+    connections: &'a ActiveConnections,
+    id: u64,
+}
+
+impl TrackedGuard<'_> {
+    /// Marks whether a request is being answered on the connection
+    pub fn set_busy(&self, busy: bool) {
+        if let Some(connection) = self.connections.lock().get_mut(&self.id) {
+            connection.busy = busy;
+        }
+    }
+}
+
+impl Drop for TrackedGuard<'_> {
+    fn drop(&mut self) {
+        self.connections.lock().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    /// A connected pair of sockets: (client, server)
+    fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn test_drain_closes_idle_connections_and_cuts_off_stuck_ones() {
+        let connections = ActiveConnections::default();
+        let (mut idle_client, idle_server) = socket_pair();
+        let (mut busy_client, busy_server) = socket_pair();
+        let _idle = connections.track(Some(idle_server));
+        let busy = connections.track(Some(busy_server));
+        busy.set_busy(true);
+
+        let started = Instant::now();
+        assert_eq!(connections.drain(Duration::from_millis(200)), 1);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(connections.is_draining());
+
+        // Both clients see the connection closed
+        assert_eq!(idle_client.read(&mut [0; 1]).unwrap(), 0);
+        assert_eq!(busy_client.read(&mut [0; 1]).unwrap(), 0);
+
+        // Finished requests don't hold up draining
+        busy.set_busy(false);
+        drop(busy);
+        assert_eq!(connections.drain(Duration::from_secs(5)), 0);
+    }
+}
@@ -0,0 +1,242 @@
+// System Date and Time
+// Settings applied by SetSystemDateAndTime and reported by GetSystemDateAndTime.
+// The host clock is never changed: a manual time is kept as an offset from it.
+// Local time follows the TZ setting: IANA zone names carry their own DST rules,
+// POSIX TZ strings contribute their standard offset plus an hour when DST is set.
+
+use crate::config::Config;
+use crate::onvif::soap::find_element_text;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use chrono_tz::OffsetComponents;
+
+/// How a TZ setting maps UTC to local time
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TimeZoneRule {
+    /// IANA zone from the tz database
+    Named(chrono_tz::Tz),
+    /// POSIX TZ string, reduced to its standard offset in seconds east of UTC
+    Posix { std_offset: i32 },
+}
+
+/// Date/time configuration as seen by ONVIF clients
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DateTimeSettings {
+    /// "NTP" or "Manual"
+    pub date_time_type: String,
+    pub daylight_savings: bool,
+    /// POSIX TZ string (e.g. "CST6CDT,M3.2.0,M11.1.0")
+    pub time_zone: String,
+    /// Difference between the client-set manual time and the host clock
+    pub offset: chrono::Duration,
+}
+
+impl Default for DateTimeSettings {
+    fn default() -> Self {
+        Self {
+            date_time_type: "NTP".to_string(),
+            daylight_savings: false,
+            time_zone: "UTC".to_string(),
+            offset: chrono::Duration::zero(),
+        }
+    }
+}
+
+impl DateTimeSettings {
+    /// Settings for the configured `--timezone` and `--dst`
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            daylight_savings: config.dst,
+            time_zone: config.timezone.clone(),
+            ..Self::default()
+        }
+    }
+
+    /// Current UTC time as the device reports it
+    pub fn now(&self) -> DateTime<Utc> {
+        Utc::now() + self.offset
+    }
+
+    /// Converts `utc` to local time, returning it with whether DST is in effect
+    ///
+    /// A TZ setting that can't be interpreted (e.g. one sent by a client) is
+    /// treated as UTC.
+    pub fn local_time(&self, utc: DateTime<Utc>) -> (NaiveDateTime, bool) {
+        match parse_time_zone(&self.time_zone) {
+            Some(TimeZoneRule::Named(tz)) => {
+                let local = utc.with_timezone(&tz);
+                let dst = !local.offset().dst_offset().is_zero();
+                (local.naive_local(), dst)
+            }
+            Some(TimeZoneRule::Posix { std_offset }) => {
+                let dst_shift = if self.daylight_savings { 3600 } else { 0 };
+                let offset = chrono::Duration::seconds(i64::from(std_offset + dst_shift));
+                (utc.naive_utc() + offset, self.daylight_savings)
+            }
+            None => (utc.naive_utc(), self.daylight_savings),
+        }
+    }
+
+    /// Applies a SetSystemDateAndTime request, returning an error for invalid values
+    pub fn apply_set_request(&mut self, request: &str) -> Result<(), String> {
+        let date_time_type = match find_element_text(request, "DateTimeType") {
+            Some(value) if value == "Manual" || value == "NTP" => value.to_string(),
+            Some(value) => return Err(format!("Invalid DateTimeType '{value}'")),
+            None => return Err("Missing DateTimeType".to_string()),
+        };
+        let daylight_savings = find_element_text(request, "DaylightSavings")
+            .map(|value| value == "true" || value == "1")
+            .unwrap_or(false);
+
+        let offset = if date_time_type == "Manual" {
+            let utc = find_element_text(request, "UTCDateTime")
+                .ok_or("Manual DateTimeType requires UTCDateTime")?;
+            parse_utc_date_time(utc)? - Utc::now()
+        } else {
+            chrono::Duration::zero()
+        };
+
+        self.date_time_type = date_time_type;
+        self.daylight_savings = daylight_savings;
+        if let Some(tz) = find_element_text(request, "TZ").filter(|tz| !tz.is_empty()) {
+            self.time_zone = tz.to_string();
+        }
+        self.offset = offset;
+        Ok(())
+    }
+}
+
+/// Checks that `time_zone` is an IANA zone name or a POSIX TZ string
+pub fn validate_time_zone(time_zone: &str) -> Result<(), String> {
+    parse_time_zone(time_zone)
+        .map(|_| ())
+        .ok_or_else(|| format!("'{time_zone}' is neither an IANA zone name nor a POSIX TZ string"))
+}
+
+fn parse_time_zone(time_zone: &str) -> Option<TimeZoneRule> {
+    if let Ok(tz) = time_zone.parse::<chrono_tz::Tz>() {
+        return Some(TimeZoneRule::Named(tz));
+    }
+    posix_std_offset(time_zone).map(|std_offset| TimeZoneRule::Posix { std_offset })
+}
+
+/// Reads the standard offset of a POSIX TZ string, in seconds east of UTC
+///
+/// The string starts with the standard zone name (three or more letters, or
+/// any text in angle brackets) followed by its offset, `[+-]hh[:mm[:ss]]`,
+/// which POSIX counts positive *west* of Greenwich: "CET-1" is UTC+1.
+fn posix_std_offset(time_zone: &str) -> Option<i32> {
+    let rest = match time_zone.strip_prefix('<') {
+        Some(quoted) => &quoted[quoted.find('>')? + 1..],
+        None => {
+            let name_len = time_zone
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(time_zone.len());
+            if name_len < 3 {
+                return None;
+            }
+            &time_zone[name_len..]
+        }
+    };
+
+    let (west, rest) = match rest.strip_prefix('-') {
+        Some(rest) => (false, rest),
+        None => (true, rest.strip_prefix('+').unwrap_or(rest)),
+    };
+    let end = rest
+        .find(|c: char| !(c.is_ascii_digit() || c == ':'))
+        .unwrap_or(rest.len());
+    let mut parts = rest[..end].split(':').map(|part| part.parse::<i32>().ok());
+    let hours = parts.next()??;
+    let minutes = parts.next().unwrap_or(Some(0))?;
+    let seconds = parts.next().unwrap_or(Some(0))?;
+    if hours > 24 || minutes > 59 || seconds > 59 || parts.next().is_some() {
+        return None;
+    }
+
+    let offset = hours * 3600 + minutes * 60 + seconds;
+    Some(if west { -offset } else { offset })
+}
+
+/// Parses a `tt:DateTime` (`Date` with Year/Month/Day and `Time` with Hour/Minute/Second)
+fn parse_utc_date_time(xml: &str) -> Result<DateTime<Utc>, String> {
+    let field = |name: &str| -> Result<u32, String> {
+        find_element_text(xml, name)
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| format!("Missing or invalid {name} in UTCDateTime"))
+    };
+
+    let date = NaiveDate::from_ymd_opt(field("Year")? as i32, field("Month")?, field("Day")?)
+        .ok_or("UTCDateTime has an invalid date")?;
+    let date_time = date
+        .and_hms_opt(field("Hour")?, field("Minute")?, field("Second")?)
+        .ok_or("UTCDateTime has an invalid time")?;
+    Ok(date_time.and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_manual_time() {
+        let request = r#"<tds:SetSystemDateAndTime><tds:DateTimeType>Manual</tds:DateTimeType><tds:DaylightSavings>true</tds:DaylightSavings><tds:TimeZone><tt:TZ>CET-1CEST,M3.5.0,M10.5.0/3</tt:TZ></tds:TimeZone><tds:UTCDateTime><tt:Date><tt:Year>2030</tt:Year><tt:Month>6</tt:Month><tt:Day>15</tt:Day></tt:Date><tt:Time><tt:Hour>12</tt:Hour><tt:Minute>30</tt:Minute><tt:Second>0</tt:Second></tt:Time></tds:UTCDateTime></tds:SetSystemDateAndTime>"#;
+
+        let mut settings = DateTimeSettings::default();
+        settings.apply_set_request(request).unwrap();
+        assert_eq!(settings.date_time_type, "Manual");
+        assert!(settings.daylight_savings);
+        assert_eq!(settings.time_zone, "CET-1CEST,M3.5.0,M10.5.0/3");
+
+        let expected = parse_utc_date_time(request).unwrap();
+        let drift = (settings.now() - expected).num_seconds().abs();
+        assert!(drift < 5);
+    }
+
+    #[test]
+    fn test_posix_std_offset() {
+        assert_eq!(posix_std_offset("CET-1CEST,M3.5.0,M10.5.0/3"), Some(3600));
+        assert_eq!(posix_std_offset("EST5EDT"), Some(-5 * 3600));
+        assert_eq!(posix_std_offset("<+0530>-5:30"), Some(5 * 3600 + 1800));
+        assert_eq!(posix_std_offset("NZST-12"), Some(12 * 3600));
+        assert_eq!(posix_std_offset("CET"), None);
+        assert_eq!(posix_std_offset("X-1"), None);
+        assert!(validate_time_zone("Europe/Berlin").is_ok());
+        assert!(validate_time_zone("Mars/Olympus").is_err());
+    }
+
+    #[test]
+    fn test_local_time() {
+        let utc = NaiveDate::from_ymd_opt(2030, 7, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap()
+            .and_utc();
+        let settings = |time_zone: &str, daylight_savings: bool| DateTimeSettings {
+            time_zone: time_zone.to_string(),
+            daylight_savings,
+            ..DateTimeSettings::default()
+        };
+
+        // POSIX strings: standard offset, plus an hour with --dst
+        let (local, dst) = settings("EST5EDT,M3.2.0,M11.1.0", true).local_time(utc);
+        assert_eq!(local - utc.naive_utc(), chrono::Duration::hours(-4));
+        assert!(dst);
+
+        // IANA zones apply their own rules, whatever the DST flag says
+        let (local, dst) = settings("Europe/Berlin", false).local_time(utc);
+        assert_eq!(local - utc.naive_utc(), chrono::Duration::hours(2));
+        assert!(dst);
+
+        let (local, _) = settings("not a zone", false).local_time(utc);
+        assert_eq!(local, utc.naive_utc());
+    }
+
+    #[test]
+    fn test_apply_rejects_invalid_time() {
+        let request = r#"<tds:SetSystemDateAndTime><tds:DateTimeType>Manual</tds:DateTimeType><tds:UTCDateTime><tt:Date><tt:Year>2030</tt:Year><tt:Month>13</tt:Month><tt:Day>1</tt:Day></tt:Date><tt:Time><tt:Hour>0</tt:Hour><tt:Minute>0</tt:Minute><tt:Second>0</tt:Second></tt:Time></tds:UTCDateTime></tds:SetSystemDateAndTime>"#;
+
+        let mut settings = DateTimeSettings::default();
+        assert!(settings.apply_set_request(request).is_err());
+        assert_eq!(settings, DateTimeSettings::default());
+    }
+}
@@ -0,0 +1,236 @@
+// Video Encoder Configuration
+// The options advertised by GetVideoEncoderConfigurationOptions and the settings
+// a SetVideoEncoderConfiguration request asks for, checked against those options.
+
+use crate::onvif::profiles::ProfileConfig;
+use crate::onvif::soap::{find_element_attribute, find_element_text};
+use crate::stream_probe::{StreamParams, VideoEncoding};
+
+/// Lowest bitrate, in kbit/s, offered in the encoder options
+pub const MIN_BITRATE_KBPS: u32 = 64;
+/// Quality range offered; configurations report a fixed quality of 4
+pub const QUALITY_RANGE: (u32, u32) = (1, 10);
+/// GOP length reported in every configuration
+pub const GOV_LENGTH: u32 = 15;
+
+/// What the encoder configurations of a set of profiles may be set to
+///
+/// The ranges are built around what GetVideoEncoderConfigurations reports, so
+/// every advertised configuration is within its own options.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncoderOptions {
+    pub encodings: Vec<VideoEncoding>,
+    pub resolutions: Vec<(u32, u32)>,
+    pub max_frame_rate: u32,
+    pub max_bitrate_kbps: u32,
+    pub h264_profiles: Vec<String>,
+}
+
+impl EncoderOptions {
+    pub fn for_profiles(params: &StreamParams, profiles: &[&ProfileConfig]) -> Self {
+        let mut options = Self {
+            encodings: Vec::new(),
+            resolutions: Vec::new(),
+            max_frame_rate: 1,
+            max_bitrate_kbps: MIN_BITRATE_KBPS,
+            h264_profiles: Vec::new(),
+        };
+        for profile in profiles {
+            push_unique(&mut options.encodings, profile.encoding(params));
+            push_unique(&mut options.resolutions, profile.resolution(params));
+            push_unique(&mut options.h264_profiles, profile.h264_profile.clone());
+            options.max_frame_rate = options.max_frame_rate.max(profile.frame_rate_limit(params));
+            options.max_bitrate_kbps = options.max_bitrate_kbps.max(profile.bitrate_kbps);
+        }
+        options
+    }
+
+    /// Checks every value `settings` sets against these options
+    pub fn validate(&self, settings: &EncoderSettings) -> Result<(), String> {
+        if let Some(encoding) = settings.encoding {
+            if !self.encodings.contains(&encoding) {
+                return Err(format!("Encoding {} is not supported", encoding.as_onvif()));
+            }
+        }
+        if let Some((width, height)) = settings.resolution {
+            if !self.resolutions.contains(&(width, height)) {
+                return Err(format!("Resolution {width}x{height} is not supported"));
+            }
+        }
+        check_range("Quality", settings.quality, QUALITY_RANGE)?;
+        check_range(
+            "FrameRateLimit",
+            settings.frame_rate,
+            (1, self.max_frame_rate),
+        )?;
+        check_range("EncodingInterval", settings.encoding_interval, (1, 1))?;
+        check_range(
+            "BitrateLimit",
+            settings.bitrate_kbps,
+            (MIN_BITRATE_KBPS, self.max_bitrate_kbps),
+        )?;
+        check_range("GovLength", settings.gov_length, (GOV_LENGTH, GOV_LENGTH))?;
+        if let Some(h264_profile) = &settings.h264_profile {
+            if !self.h264_profiles.contains(h264_profile) {
+                return Err(format!("H264 profile {h264_profile} is not supported"));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn push_unique<T: PartialEq>(values: &mut Vec<T>, value: T) {
+    if !values.contains(&value) {
+        values.push(value);
+    }
+}
+
+fn check_range(name: &str, value: Option<u32>, (min, max): (u32, u32)) -> Result<(), String> {
+    match value {
+        Some(value) if value < min || value > max => Err(format!(
+            "{name} {value} is outside the supported range {min}-{max}"
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// The `Configuration` of a SetVideoEncoderConfiguration request
+///
+/// Values the request leaves out stay `None` and keep their current setting.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EncoderSettings {
+    /// Token of the configuration being set
+    pub token: String,
+    pub encoding: Option<VideoEncoding>,
+    pub resolution: Option<(u32, u32)>,
+    pub quality: Option<u32>,
+    pub frame_rate: Option<u32>,
+    pub encoding_interval: Option<u32>,
+    pub bitrate_kbps: Option<u32>,
+    pub gov_length: Option<u32>,
+    pub h264_profile: Option<String>,
+}
+
+impl EncoderSettings {
+    /// Applies the persisted values to `profile`
+    ///
+    /// Quality, GOP length and encoding interval are fixed in the reported
+    /// configurations, so only validated, not kept.
+    pub fn apply(&self, profile: &mut ProfileConfig) {
+        if let Some(resolution) = self.resolution {
+            profile.resolution = Some(resolution);
+        }
+        if let Some(frame_rate) = self.frame_rate {
+            profile.frame_rate = Some(frame_rate);
+        }
+        if let Some(bitrate) = self.bitrate_kbps {
+            profile.bitrate_kbps = bitrate;
+        }
+        if let Some(h264_profile) = &self.h264_profile {
+            profile.h264_profile = h264_profile.clone();
+        }
+    }
+}
+
+/// Parses the `Configuration` element of a SetVideoEncoderConfiguration request
+pub fn parse_encoder_configuration(request: &str) -> Result<EncoderSettings, String> {
+    let token = find_element_attribute(request, "Configuration", "token")
+        .filter(|token| !token.is_empty())
+        .ok_or("Configuration token is missing")?;
+    let configuration =
+        find_element_text(request, "Configuration").ok_or("Configuration is missing")?;
+
+    let number = |element: &str, within: &str| -> Result<Option<u32>, String> {
+        find_element_text(within, element)
+            .map(|value| {
+                // Quality is an xs:float; whole numbers are all the options allow
+                let value = value.strip_suffix(".0").unwrap_or(value);
+                value
+                    .parse()
+                    .map_err(|_| format!("Invalid {element} '{value}'"))
+            })
+            .transpose()
+    };
+
+    let encoding = find_element_text(configuration, "Encoding")
+        .map(|encoding| {
+            VideoEncoding::from_codec_name(encoding)
+                .filter(VideoEncoding::is_media1)
+                .ok_or_else(|| format!("Encoding {encoding} is not supported"))
+        })
+        .transpose()?;
+    let resolution = match find_element_text(configuration, "Resolution") {
+        Some(resolution) => Some((
+            number("Width", resolution)?.ok_or("Resolution is missing its Width")?,
+            number("Height", resolution)?.ok_or("Resolution is missing its Height")?,
+        )),
+        None => None,
+    };
+    let rate_control = find_element_text(configuration, "RateControl").unwrap_or_default();
+    let h264 = find_element_text(configuration, "H264").unwrap_or_default();
+
+    Ok(EncoderSettings {
+        token: token.to_string(),
+        encoding,
+        resolution,
+        quality: number("Quality", configuration)?,
+        frame_rate: number("FrameRateLimit", rate_control)?,
+        encoding_interval: number("EncodingInterval", rate_control)?,
+        bitrate_kbps: number("BitrateLimit", rate_control)?,
+        gov_length: number("GovLength", h264)?,
+        h264_profile: find_element_text(h264, "H264Profile").map(str::to_string),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUEST: &str = r#"<trt:SetVideoEncoderConfiguration><trt:Configuration token="VideoEncoderConfig_HQ"><tt:Name>VideoEncoderConfig_HQ</tt:Name><tt:UseCount>1</tt:UseCount><tt:Encoding>H264</tt:Encoding><tt:Resolution><tt:Width>960</tt:Width><tt:Height>540</tt:Height></tt:Resolution><tt:Quality>5</tt:Quality><tt:RateControl><tt:FrameRateLimit>10</tt:FrameRateLimit><tt:EncodingInterval>1</tt:EncodingInterval><tt:BitrateLimit>800</tt:BitrateLimit></tt:RateControl><tt:H264><tt:GovLength>15</tt:GovLength><tt:H264Profile>Main</tt:H264Profile></tt:H264></trt:Configuration><trt:ForcePersistence>true</trt:ForcePersistence></trt:SetVideoEncoderConfiguration>"#;
+
+    #[test]
+    fn test_parse_encoder_configuration() {
+        let settings = parse_encoder_configuration(REQUEST).unwrap();
+        assert_eq!(settings.token, "VideoEncoderConfig_HQ");
+        assert_eq!(settings.encoding, Some(VideoEncoding::H264));
+        assert_eq!(settings.resolution, Some((960, 540)));
+        assert_eq!(settings.quality, Some(5));
+        assert_eq!(settings.frame_rate, Some(10));
+        assert_eq!(settings.bitrate_kbps, Some(800));
+        assert_eq!(settings.gov_length, Some(15));
+        assert_eq!(settings.h264_profile.as_deref(), Some("Main"));
+
+        assert!(parse_encoder_configuration(
+            "<trt:Configuration><tt:Quality>1</tt:Quality></trt:Configuration>"
+        )
+        .is_err());
+
+        // Media1 encoder configurations can't be H265
+        let h265 = REQUEST.replace("<tt:Encoding>H264<", "<tt:Encoding>H265<");
+        assert!(parse_encoder_configuration(&h265)
+            .unwrap_err()
+            .contains("H265 is not supported"));
+    }
+
+    #[test]
+    fn test_validate_against_options() {
+        let profile = ProfileConfig::parse("token=HQProfile").unwrap();
+        let options = EncoderOptions::for_profiles(&StreamParams::default(), &[&profile]);
+        let settings = parse_encoder_configuration(REQUEST).unwrap();
+        assert!(options.validate(&settings).is_ok());
+
+        let too_fast = EncoderSettings {
+            frame_rate: Some(options.max_frame_rate + 1),
+            ..settings.clone()
+        };
+        assert!(options
+            .validate(&too_fast)
+            .unwrap_err()
+            .contains("FrameRateLimit"));
+        let other_size = EncoderSettings {
+            resolution: Some((1920, 1080)),
+            ..settings
+        };
+        assert!(options.validate(&other_size).is_err());
+    }
+}
@@ -1,16 +1,190 @@
 // ONVIF Endpoints Module
 // Contains lists of supported and unsupported ONVIF endpoints
 
+use crate::config::Config;
+
+pub const DEVICE_NAMESPACE: &str = "http://www.onvif.org/ver10/device/wsdl";
+pub const MEDIA_NAMESPACE: &str = "http://www.onvif.org/ver10/media/wsdl";
+pub const PTZ_NAMESPACE: &str = "http://www.onvif.org/ver20/ptz/wsdl";
+pub const EVENTS_NAMESPACE: &str = "http://www.onvif.org/ver10/events/wsdl";
+pub const IMAGING_NAMESPACE: &str = "http://www.onvif.org/ver20/imaging/wsdl";
+/// WS-BaseNotification, used for subscription management (Renew, Unsubscribe)
+pub const WSN_NAMESPACE: &str = "http://docs.oasis-open.org/wsn/b-2";
+
+/// Returns the service namespaces an implemented operation may be sent in
+///
+/// Used by `--strict-soap`. An empty slice means the operation isn't
+/// implemented and its namespace isn't checked.
+pub fn expected_namespaces(operation: &str) -> &'static [&'static str] {
+    match operation {
+        "GetCapabilities"
+        | "GetServices"
+        | "GetSystemDateAndTime"
+        | "SetSystemDateAndTime"
+        | "GetDeviceInformation"
+        | "GetHostname"
+        | "GetDNS"
+        | "GetScopes"
+        | "SetScopes"
+        | "AddScopes"
+        | "SystemReboot"
+        | "GetUsers"
+        | "CreateUsers"
+        | "DeleteUsers"
+        | "SetUser" => &[DEVICE_NAMESPACE],
+        "GetProfiles"
+        | "GetProfile"
+        | "GetStreamUri"
+        | "GetSnapshotUri"
+        | "GetVideoSources"
+        | "GetVideoSourceConfigurations"
+        | "GetVideoEncoderConfigurations"
+        | "GetVideoEncoderConfigurationOptions"
+        | "SetVideoEncoderConfiguration"
+        | "GetAudioSources"
+        | "GetAudioOutputs"
+        | "GetAudioSourceConfigurations"
+        | "GetAudioEncoderConfigurations" => &[MEDIA_NAMESPACE],
+        "GetConfigurations"
+        | "GetConfigurationOptions"
+        | "GetStatus"
+        | "GetNodes"
+        | "GetNode"
+        | "ContinuousMove"
+        | "Stop" => &[PTZ_NAMESPACE],
+        "GetImagingSettings" | "SetImagingSettings" | "GetOptions" => &[IMAGING_NAMESPACE],
+        "GetEventProperties" | "CreatePullPointSubscription" | "PullMessages" => {
+            &[EVENTS_NAMESPACE]
+        }
+        "Renew" | "Unsubscribe" => &[WSN_NAMESPACE],
+        // Every service defines its own GetServiceCapabilities
+        "GetServiceCapabilities" => &[
+            DEVICE_NAMESPACE,
+            MEDIA_NAMESPACE,
+            PTZ_NAMESPACE,
+            EVENTS_NAMESPACE,
+            IMAGING_NAMESPACE,
+        ],
+        _ => &[],
+    }
+}
+
+/// An ONVIF service, each posted to its own path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Service {
+    Device,
+    Media,
+    Ptz,
+    Events,
+    Imaging,
+}
+
+impl Service {
+    /// Returns the service answering SOAP requests posted to `path`
+    ///
+    /// Pull point subscriptions live below the events service path.
+    pub fn from_path(path: &str) -> Option<Self> {
+        match path.trim_end_matches('/') {
+            "/onvif/device_service" => Some(Service::Device),
+            "/onvif/media_service" => Some(Service::Media),
+            "/onvif/ptz_service" => Some(Service::Ptz),
+            "/onvif/imaging_service" => Some(Service::Imaging),
+            path if path == "/onvif/events_service"
+                || path.starts_with("/onvif/events_service/") =>
+            {
+                Some(Service::Events)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the service whose WSDL defines `namespace`
+    pub fn from_namespace(namespace: &str) -> Option<Self> {
+        match namespace {
+            DEVICE_NAMESPACE => Some(Service::Device),
+            MEDIA_NAMESPACE => Some(Service::Media),
+            PTZ_NAMESPACE => Some(Service::Ptz),
+            EVENTS_NAMESPACE | WSN_NAMESPACE => Some(Service::Events),
+            IMAGING_NAMESPACE => Some(Service::Imaging),
+            _ => None,
+        }
+    }
+
+    /// The namespace of the service's WSDL
+    pub fn namespace(self) -> &'static str {
+        match self {
+            Service::Device => DEVICE_NAMESPACE,
+            Service::Media => MEDIA_NAMESPACE,
+            Service::Ptz => PTZ_NAMESPACE,
+            Service::Events => EVENTS_NAMESPACE,
+            Service::Imaging => IMAGING_NAMESPACE,
+        }
+    }
+
+    /// The path the service is posted to
+    pub fn path(self) -> &'static str {
+        match self {
+            Service::Device => "/onvif/device_service",
+            Service::Media => "/onvif/media_service",
+            Service::Ptz => "/onvif/ptz_service",
+            Service::Events => "/onvif/events_service",
+            Service::Imaging => "/onvif/imaging_service",
+        }
+    }
+
+    /// Returns the one service implementing `operation`, or `None` if several or none do
+    pub fn of_operation(operation: &str) -> Option<Self> {
+        let mut services = expected_namespaces(operation)
+            .iter()
+            .filter_map(|namespace| Service::from_namespace(namespace));
+        let first = services.next()?;
+        services.all(|service| service == first).then_some(first)
+    }
+
+    /// Returns false for a service left out of the build or turned off in
+    /// `config`; its operations then fault
+    pub fn is_enabled(self, config: &Config) -> bool {
+        match self {
+            Service::Device | Service::Media => true,
+            Service::Ptz => cfg!(feature = "ptz"),
+            Service::Events => cfg!(feature = "events") && !config.disable_events,
+            Service::Imaging => cfg!(feature = "imaging"),
+        }
+    }
+
+    /// Returns true if this service implements `operation`
+    ///
+    /// Operations no service implements return true, so they reach the
+    /// unsupported-operation fault wherever they're posted.
+    pub fn implements(self, operation: &str) -> bool {
+        let namespaces = expected_namespaces(operation);
+        namespaces.is_empty()
+            || namespaces
+                .iter()
+                .any(|namespace| Service::from_namespace(namespace) == Some(self))
+    }
+}
+
+/// Services listed by GetServices and GetCapabilities, leaving out disabled ones
+///
+/// PTZ answers its stubs but isn't advertised: this camera can't move.
+pub fn advertised_services(config: &Config) -> Vec<Service> {
+    [
+        Service::Device,
+        Service::Media,
+        Service::Events,
+        Service::Imaging,
+    ]
+    .into_iter()
+    .filter(|service| service.is_enabled(config))
+    .collect()
+}
+
 pub const UNSUPPORTED_ENDPOINTS: &[&str] = &[
     // Device Management Service
     "GetSystemDateAndTime",
-    "SetSystemDateAndTime",
     "GetSystemLog",
     "GetSystemSupportInformation",
-    "GetUsers",
-    "CreateUsers",
-    "DeleteUsers",
-    "SetUser",
     "GetWsdlUrl",
     "GetPasswordComplexityOptions",
     "GetPasswordComplexityConfiguration",
@@ -33,21 +207,18 @@ pub const UNSUPPORTED_ENDPOINTS: &[&str] = &[
     "DeleteStorageConfiguration",
     // Media Service - Configuration Management
     "CreateProfile",
-    "GetProfile",
     "DeleteProfile",
     "GetVideoSourceConfiguration",
     "GetVideoEncoderConfiguration",
     "GetAudioSourceConfiguration",
     "GetAudioEncoderConfiguration",
     "SetVideoSourceConfiguration",
-    "SetVideoEncoderConfiguration",
     "SetAudioSourceConfiguration",
     "SetAudioEncoderConfiguration",
     "GetCompatibleVideoEncoderConfigurations",
     "GetCompatibleVideoSourceConfigurations",
     "GetCompatibleAudioEncoderConfigurations",
     "GetCompatibleAudioSourceConfigurations",
-    "GetVideoEncoderConfigurationOptions",
     "GetAudioEncoderConfigurationOptions",
     "GetVideoSourceConfigurationOptions",
     "GetAudioSourceConfigurationOptions",
@@ -57,17 +228,10 @@ pub const UNSUPPORTED_ENDPOINTS: &[&str] = &[
     "StartMulticastStreaming",
     "StopMulticastStreaming",
     // PTZ Service
-    "GetNodes",
-    "GetNode",
     "GetConfiguration",
-    "GetConfigurations",
     "SetConfiguration",
-    "GetConfigurationOptions",
     "AbsoluteMove",
     "RelativeMove",
-    "ContinuousMove",
-    "Stop",
-    "GetStatus",
     "SetHomePosition",
     "GotoHomePosition",
     "SetPreset",
@@ -83,10 +247,7 @@ pub const UNSUPPORTED_ENDPOINTS: &[&str] = &[
     "ModifyAnalyticsModules",
     // Events Service
     "Subscribe",
-    "Unsubscribe",
     "CreatePullPoint",
-    "GetEventProperties",
-    "Renew",
     "GetMessages",
     "DestroyPullPoint",
     "Notify",
@@ -94,9 +255,6 @@ pub const UNSUPPORTED_ENDPOINTS: &[&str] = &[
     "SetSynchronizationPoint",
     // Imaging Service
     "GetServiceCapabilities",
-    "GetImagingSettings",
-    "SetImagingSettings",
-    "GetOptions",
     "Move",
     "GetMoveOptions",
     "Stop",
@@ -0,0 +1,569 @@
+// ONVIF Events Service
+// Minimal pull-point support so event-driven recorders can subscribe and poll.
+// No real events are produced; with --emit-test-events PullMessages reports a
+// synthetic MotionAlarm so integrators can exercise their event pipeline.
+// Each subscription is told the alarm state once, then only when it changes;
+// a pull with nothing to report is held open until its Timeout.
+
+use crate::onvif::endpoints::{EVENTS_NAMESPACE, WSN_NAMESPACE};
+use crate::onvif::http::url_scheme;
+use crate::onvif::soap::{xml_escape, SoapResponseBuilder};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Lifetime granted to a new pull-point subscription
+const SUBSCRIPTION_LIFETIME_SECS: i64 = 60;
+/// Most pull-point subscriptions kept at once; the one expiring first makes room
+const MAX_SUBSCRIPTIONS: usize = 32;
+/// Longest a PullMessages request is held open, whatever Timeout it asks for
+const MAX_PULL_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long the synthetic motion alarm stays in each state
+const TEST_EVENT_INTERVAL: Duration = Duration::from_secs(10);
+const MOTION_ALARM_TOPIC: &str = "tns1:VideoSource/MotionAlarm";
+const CONCRETE_SET_DIALECT: &str = "http://www.onvif.org/ver10/tev/topicExpression/ConcreteSet";
+
+fn build(body_content: &str) -> String {
+    SoapResponseBuilder::new()
+        .add_namespace("tev", EVENTS_NAMESPACE)
+        .add_namespace("wsnt", WSN_NAMESPACE)
+        .add_namespace("wsa", "http://www.w3.org/2005/08/addressing")
+        .add_namespace("tt", "http://www.onvif.org/ver10/schema")
+        .add_namespace("tns1", "http://www.onvif.org/ver10/topics")
+        .set_body(body_content)
+        .build()
+}
+
+/// Returns the current time and the subscription termination time as xs:dateTime
+fn subscription_times() -> (String, String) {
+    let now = chrono::Utc::now();
+    let termination = now + chrono::Duration::seconds(SUBSCRIPTION_LIFETIME_SECS);
+    (
+        now.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        termination.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+    )
+}
+
+/// Address of the pull point handed out to subscription `id`
+pub fn pull_point_address(container_ip: &str, onvif_port: &str, tls: bool, id: u64) -> String {
+    format!(
+        "{}://{container_ip}:{onvif_port}/onvif/events_service/pullpoint?subscription={id}",
+        url_scheme(tls)
+    )
+}
+
+pub fn get_service_capabilities_response() -> String {
+    build(&format!(
+        r#"<tev:GetServiceCapabilitiesResponse>
+<tev:Capabilities WSSubscriptionPolicySupport="false" WSPausableSubscriptionManagerInterfaceSupport="false" MaxNotificationProducers="0" MaxPullPoints="{MAX_SUBSCRIPTIONS}" PersistentNotificationStorage="false"/>
+</tev:GetServiceCapabilitiesResponse>"#
+    ))
+}
+
+/// Returns the state of the synthetic motion alarm, alternating every `TEST_EVENT_INTERVAL`
+pub fn test_motion_state(uptime: Duration) -> bool {
+    (uptime.as_secs() / TEST_EVENT_INTERVAL.as_secs()) % 2 == 1
+}
+
+/// Time left until the synthetic motion alarm next changes state
+fn until_test_motion_change(uptime: Duration) -> Duration {
+    let interval = TEST_EVENT_INTERVAL.as_millis();
+    let into = u64::try_from(uptime.as_millis() % interval).unwrap_or_default();
+    TEST_EVENT_INTERVAL - Duration::from_millis(into)
+}
+
+/// Parses an xs:duration such as `PT10S`, `PT1M30S` or `PT0.5S`
+///
+/// Years and months have no fixed length, so only days and smaller units are accepted.
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    let rest = value.trim().strip_prefix('P')?;
+    let (date, time) = rest.split_once('T').unwrap_or((rest, ""));
+    if date.is_empty() && time.is_empty() {
+        return None;
+    }
+
+    let mut seconds = 0.0;
+    let mut add = |part: &str, units: &[(char, f64)]| -> Option<()> {
+        // Units must come in order, each at most once
+        let mut units = units.iter();
+        let mut number = String::new();
+        for c in part.chars() {
+            if c.is_ascii_digit() || c == '.' {
+                number.push(c);
+                continue;
+            }
+            let (_, scale) = units.find(|(unit, _)| *unit == c)?;
+            seconds += number.parse::<f64>().ok()? * scale;
+            number.clear();
+        }
+        number.is_empty().then_some(())
+    };
+    add(date, &[('D', 86_400.0)])?;
+    add(time, &[('H', 3_600.0), ('M', 60.0), ('S', 1.0)])?;
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+/// A MotionAlarm state delivered to a subscription
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MotionEvent {
+    pub state: bool,
+    /// The subscription's first report of the alarm, sent as PropertyOperation "Initialized"
+    pub initialized: bool,
+}
+
+/// Number of PullMessages requests currently held open, each tying up a worker
+#[derive(Debug, Default)]
+pub struct HeldPulls {
+    holding: AtomicUsize,
+}
+
+impl HeldPulls {
+    /// Admits a held pull if fewer than `max` are held; it ends when the guard is dropped
+    pub fn admit(&self, max: usize) -> Option<HeldPullGuard<'_>> {
+        self.holding
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |holding| {
+                (holding < max).then_some(holding + 1)
+            })
+            .ok()
+            .map(|_| HeldPullGuard { pulls: self })
+    }
+}
+
+/// A pull admitted by `HeldPulls::admit`
+pub struct HeldPullGuard<'a> {
+    pulls: &'a HeldPulls,
+}
+
+impl Drop for HeldPullGuard<'_> {
+    fn drop(&mut self) {
+        self.pulls.holding.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Open pull-point subscriptions and the alarm state each was last told
+#[derive(Debug, Default)]
+pub struct Subscriptions {
+    entries: Mutex<HashMap<u64, Subscription>>,
+    next_id: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Subscription {
+    /// Motion state last delivered, `None` before the first
+    delivered: Option<bool>,
+    expires: Instant,
+}
+
+fn lifetime() -> Duration {
+    Duration::from_secs(SUBSCRIPTION_LIFETIME_SECS.unsigned_abs())
+}
+
+impl Subscriptions {
+    fn lock(&self) -> MutexGuard<'_, HashMap<u64, Subscription>> {
+        match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Opens a subscription and returns its id
+    pub fn create(&self) -> u64 {
+        let now = Instant::now();
+        let mut entries = self.lock();
+        entries.retain(|_, subscription| subscription.expires > now);
+        if entries.len() >= MAX_SUBSCRIPTIONS {
+            if let Some(oldest) = entries
+                .iter()
+                .min_by_key(|&(&id, subscription)| (subscription.expires, id))
+                .map(|(&id, _)| id)
+            {
+                entries.remove(&oldest);
+            }
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        entries.insert(
+            id,
+            Subscription {
+                delivered: None,
+                expires: now + lifetime(),
+            },
+        );
+        id
+    }
+
+    /// Extends subscription `id` by another lifetime; false if it doesn't exist or expired
+    pub fn renew(&self, id: u64) -> bool {
+        let now = Instant::now();
+        match self.lock().get_mut(&id) {
+            Some(subscription) if subscription.expires > now => {
+                subscription.expires = now + lifetime();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Closes subscription `id`; false if it didn't exist
+    pub fn remove(&self, id: u64) -> bool {
+        self.lock().remove(&id).is_some()
+    }
+
+    /// Records `state` as delivered to subscription `id` and returns the event, if it's news
+    ///
+    /// Also renews the subscription, as the TerminationTime of a pull response promises.
+    fn deliver(&self, id: u64, state: Option<bool>) -> Result<Option<MotionEvent>, String> {
+        let now = Instant::now();
+        let mut entries = self.lock();
+        let subscription = entries
+            .get_mut(&id)
+            .filter(|subscription| subscription.expires > now)
+            .ok_or_else(|| format!("Unknown or expired subscription {id}"))?;
+        subscription.expires = now + lifetime();
+        let Some(state) = state.filter(|&state| subscription.delivered != Some(state)) else {
+            return Ok(None);
+        };
+        let initialized = subscription.delivered.is_none();
+        subscription.delivered = Some(state);
+        Ok(Some(MotionEvent { state, initialized }))
+    }
+
+    /// Answers a PullMessages for subscription `id`
+    ///
+    /// Returns the alarm state if subscription `id` hasn't been told it yet,
+    /// otherwise waits up to `timeout` (at most `MAX_PULL_TIMEOUT`) for it to
+    /// change. `uptime` drives the synthetic alarm when `emit_test_events` is
+    /// set; `wait` sleeps for the given time and returns true if the service is
+    /// shutting down, which ends the pull early.
+    pub fn pull(
+        &self,
+        id: u64,
+        timeout: Duration,
+        emit_test_events: bool,
+        uptime: impl Fn() -> Duration,
+        mut wait: impl FnMut(Duration) -> bool,
+    ) -> Result<Option<MotionEvent>, String> {
+        let mut remaining = timeout.min(MAX_PULL_TIMEOUT);
+        loop {
+            let now = uptime();
+            let state = emit_test_events.then(|| test_motion_state(now));
+            if let Some(event) = self.deliver(id, state)? {
+                return Ok(Some(event));
+            }
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            let step = if emit_test_events {
+                remaining.min(until_test_motion_change(now))
+            } else {
+                remaining
+            };
+            if wait(step) {
+                return Ok(None);
+            }
+            remaining -= step;
+        }
+    }
+}
+
+/// Lists the topics this device can produce; only the MotionAlarm test topic when enabled
+pub fn get_event_properties_response(emit_test_events: bool) -> String {
+    let topic_set = if emit_test_events {
+        r#"<wstop:TopicSet xmlns:wstop="http://docs.oasis-open.org/wsn/t-1" xmlns:xs="http://www.w3.org/2001/XMLSchema">
+<tns1:VideoSource wstop:topic="false">
+<MotionAlarm wstop:topic="true">
+<tt:MessageDescription IsProperty="true">
+<tt:Source>
+<tt:SimpleItemDescription Name="Source" Type="tt:ReferenceToken"/>
+</tt:Source>
+<tt:Data>
+<tt:SimpleItemDescription Name="State" Type="xs:boolean"/>
+</tt:Data>
+</tt:MessageDescription>
+</MotionAlarm>
+</tns1:VideoSource>
+</wstop:TopicSet>"#
+    } else {
+        r#"<wstop:TopicSet xmlns:wstop="http://docs.oasis-open.org/wsn/t-1"/>"#
+    };
+
+    build(&format!(
+        r#"<tev:GetEventPropertiesResponse>
+<tev:TopicNamespaceLocation>http://www.onvif.org/onvif/ver10/topics/topicns.xml</tev:TopicNamespaceLocation>
+<wsnt:FixedTopicSet>true</wsnt:FixedTopicSet>
+{topic_set}
+<wsnt:TopicExpressionDialect>{CONCRETE_SET_DIALECT}</wsnt:TopicExpressionDialect>
+<wsnt:TopicExpressionDialect>http://docs.oasis-open.org/wsn/t-1/TopicExpression/Concrete</wsnt:TopicExpressionDialect>
+<tev:MessageContentFilterDialect>http://www.onvif.org/ver10/tev/messageContentFilter/ItemFilter</tev:MessageContentFilterDialect>
+<tev:MessageContentSchemaLocation>http://www.onvif.org/onvif/ver10/schema/onvif.xsd</tev:MessageContentSchemaLocation>
+</tev:GetEventPropertiesResponse>"#
+    ))
+}
+
+pub fn get_create_pull_point_subscription_response(
+    container_ip: &str,
+    onvif_port: &str,
+    tls: bool,
+    id: u64,
+) -> String {
+    let address = xml_escape(&pull_point_address(container_ip, onvif_port, tls, id));
+    let (current_time, termination_time) = subscription_times();
+
+    build(&format!(
+        r#"<tev:CreatePullPointSubscriptionResponse>
+<tev:SubscriptionReference>
+<wsa:Address>{address}</wsa:Address>
+</tev:SubscriptionReference>
+<wsnt:CurrentTime>{current_time}</wsnt:CurrentTime>
+<wsnt:TerminationTime>{termination_time}</wsnt:TerminationTime>
+</tev:CreatePullPointSubscriptionResponse>"#
+    ))
+}
+
+/// Renders a MotionAlarm notification for the video source
+fn motion_alarm_notification(event: MotionEvent, utc_time: &str) -> String {
+    let state = event.state;
+    let operation = if event.initialized {
+        "Initialized"
+    } else {
+        "Changed"
+    };
+    format!(
+        r#"<wsnt:NotificationMessage>
+<wsnt:Topic Dialect="{CONCRETE_SET_DIALECT}">{MOTION_ALARM_TOPIC}</wsnt:Topic>
+<wsnt:Message>
+<tt:Message UtcTime="{utc_time}" PropertyOperation="{operation}">
+<tt:Source>
+<tt:SimpleItem Name="Source" Value="VideoSource_1"/>
+</tt:Source>
+<tt:Data>
+<tt:SimpleItem Name="State" Value="{state}"/>
+</tt:Data>
+</tt:Message>
+</wsnt:Message>
+</wsnt:NotificationMessage>
+"#
+    )
+}
+
+/// Answers PullMessages, with a MotionAlarm notification when `event` is set
+pub fn get_pull_messages_response(event: Option<MotionEvent>) -> String {
+    let (current_time, termination_time) = subscription_times();
+    let notifications = event
+        .map(|event| motion_alarm_notification(event, &current_time))
+        .unwrap_or_default();
+
+    build(&format!(
+        r#"<tev:PullMessagesResponse>
+<tev:CurrentTime>{current_time}</tev:CurrentTime>
+<tev:TerminationTime>{termination_time}</tev:TerminationTime>
+{notifications}</tev:PullMessagesResponse>"#
+    ))
+}
+
+pub fn get_renew_response() -> String {
+    let (current_time, termination_time) = subscription_times();
+
+    build(&format!(
+        r#"<wsnt:RenewResponse>
+<wsnt:TerminationTime>{termination_time}</wsnt:TerminationTime>
+<wsnt:CurrentTime>{current_time}</wsnt:CurrentTime>
+</wsnt:RenewResponse>"#
+    ))
+}
+
+pub fn get_unsubscribe_response() -> String {
+    build("<wsnt:UnsubscribeResponse/>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::onvif::soap::{find_element_text, parse_soap_action};
+
+    #[test]
+    fn test_subscription_points_back_at_device() {
+        let response =
+            get_create_pull_point_subscription_response("192.168.1.10", "8080", false, 7);
+        assert!(response.contains(
+            "<wsa:Address>http://192.168.1.10:8080/onvif/events_service/pullpoint?subscription=7</wsa:Address>"
+        ));
+        assert!(response.contains("<wsnt:TerminationTime>"));
+    }
+
+    #[test]
+    fn test_pull_messages_is_empty() {
+        let response = get_pull_messages_response(None);
+        assert!(response.contains("<tev:PullMessagesResponse>"));
+        assert!(!response.contains("NotificationMessage"));
+    }
+
+    #[test]
+    fn test_pull_messages_motion_alarm() {
+        let response = get_pull_messages_response(Some(MotionEvent {
+            state: true,
+            initialized: false,
+        }));
+        assert_eq!(
+            parse_soap_action(&response),
+            Some("PullMessagesResponse".to_string())
+        );
+        assert_eq!(
+            find_element_text(&response, "Topic"),
+            Some("tns1:VideoSource/MotionAlarm")
+        );
+        assert!(response.contains(r#"<tt:SimpleItem Name="State" Value="true"/>"#));
+        assert!(response.contains(r#"PropertyOperation="Changed""#));
+        assert!(response.contains(r#"xmlns:tns1="http://www.onvif.org/ver10/topics""#));
+
+        let utc_time = response
+            .split("UtcTime=\"")
+            .nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(utc_time).is_ok());
+    }
+
+    #[test]
+    fn test_motion_state_toggles() {
+        assert!(!test_motion_state(Duration::from_secs(0)));
+        assert!(test_motion_state(Duration::from_secs(10)));
+        assert!(!test_motion_state(Duration::from_secs(25)));
+
+        assert_eq!(
+            until_test_motion_change(Duration::from_millis(2_500)),
+            Duration::from_millis(7_500)
+        );
+        assert_eq!(
+            until_test_motion_change(Duration::from_secs(20)),
+            TEST_EVENT_INTERVAL
+        );
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("PT5S"), Some(Duration::from_secs(5)));
+        assert_eq!(parse_duration("PT1M30S"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("PT0.5S"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("P1DT1H"), Some(Duration::from_secs(90_000)));
+        assert_eq!(parse_duration("PT"), None);
+        assert_eq!(parse_duration("PT5"), None);
+        assert_eq!(parse_duration("PT5S1M"), None);
+        assert_eq!(parse_duration("P1M"), None);
+        assert_eq!(parse_duration("5"), None);
+    }
+
+    #[test]
+    fn test_pull_reports_each_state_once() {
+        let subscriptions = Subscriptions::default();
+        let id = subscriptions.create();
+        let uptime = std::cell::Cell::new(Duration::from_secs(3));
+        let pull = |timeout| {
+            subscriptions.pull(
+                id,
+                timeout,
+                true,
+                || uptime.get(),
+                |step| {
+                    uptime.set(uptime.get() + step);
+                    false
+                },
+            )
+        };
+
+        // A new subscription is told the current state at once
+        let event = pull(Duration::from_secs(5)).unwrap();
+        assert_eq!(
+            event,
+            Some(MotionEvent {
+                state: false,
+                initialized: true
+            })
+        );
+        assert_eq!(uptime.get(), Duration::from_secs(3));
+
+        // Then the pull waits for the alarm to change
+        let event = pull(Duration::from_secs(60)).unwrap();
+        assert_eq!(
+            event,
+            Some(MotionEvent {
+                state: true,
+                initialized: false
+            })
+        );
+        assert_eq!(uptime.get(), Duration::from_secs(10));
+
+        // Nothing changes within the timeout: an empty answer once it's over
+        assert_eq!(pull(Duration::from_secs(4)).unwrap(), None);
+        assert_eq!(uptime.get(), Duration::from_secs(14));
+
+        // Another subscription has its own view of the state
+        let other = subscriptions.create();
+        let event = subscriptions
+            .pull(other, Duration::ZERO, true, || uptime.get(), |_| false)
+            .unwrap();
+        assert_eq!(
+            event,
+            Some(MotionEvent {
+                state: true,
+                initialized: true
+            })
+        );
+
+        assert!(subscriptions.remove(id));
+        assert!(pull(Duration::ZERO).is_err());
+        assert!(!subscriptions.renew(id));
+        assert!(subscriptions.renew(other));
+    }
+
+    #[test]
+    fn test_pull_without_events_waits_out_the_timeout() {
+        let subscriptions = Subscriptions::default();
+        let id = subscriptions.create();
+        let mut waited = Vec::new();
+        let event = subscriptions.pull(
+            id,
+            Duration::from_secs(120),
+            false,
+            || Duration::ZERO,
+            |step| {
+                waited.push(step);
+                false
+            },
+        );
+        assert_eq!(event, Ok(None));
+        assert_eq!(waited, [MAX_PULL_TIMEOUT]);
+
+        // Shutdown ends a held pull
+        let event = subscriptions.pull(
+            id,
+            Duration::from_secs(5),
+            false,
+            || Duration::ZERO,
+            |_| true,
+        );
+        assert_eq!(event, Ok(None));
+    }
+
+    #[test]
+    fn test_held_pulls_are_capped() {
+        let pulls = HeldPulls::default();
+        let first = pulls.admit(2).unwrap();
+        let _second = pulls.admit(2).unwrap();
+        assert!(pulls.admit(2).is_none());
+        drop(first);
+        assert!(pulls.admit(2).is_some());
+        assert!(pulls.admit(0).is_none());
+    }
+
+    #[test]
+    fn test_subscriptions_are_capped() {
+        let subscriptions = Subscriptions::default();
+        let first = subscriptions.create();
+        for _ in 0..MAX_SUBSCRIPTIONS {
+            subscriptions.create();
+        }
+        assert_eq!(subscriptions.lock().len(), MAX_SUBSCRIPTIONS);
+        assert!(!subscriptions.renew(first));
+    }
+}
@@ -0,0 +1,442 @@
+// HTTP request framing
+// Reads whole requests (headers plus Content-Length body) off a connection so
+// several requests can be served over one persistent connection.
+
+use crate::onvif::tls::ClientStream;
+use chrono::{DateTime, Utc};
+use std::io::{self, Read, Write};
+use std::net::{Ipv6Addr, TcpStream};
+use std::time::{Duration, Instant};
+
+/// Largest request accepted, headers and body together
+pub const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+/// How long a client may take to send a request
+///
+/// The header and total limits count from the request's first byte, so a
+/// client dribbling bytes can't hold a worker by keeping each read short.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestTimeouts {
+    /// Wait for the first byte of the request
+    pub idle: Duration,
+    /// Until the headers are complete
+    pub headers: Duration,
+    /// Until the whole request, body included, has arrived
+    pub total: Duration,
+}
+
+/// A stream whose read timeout can be changed between reads
+pub trait TimedRead: Read {
+    fn set_read_limit(&mut self, timeout: Duration) -> io::Result<()>;
+}
+
+impl TimedRead for TcpStream {
+    fn set_read_limit(&mut self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))
+    }
+}
+
+/// In-memory requests arrive at once, so there is nothing to time out
+impl TimedRead for &[u8] {
+    fn set_read_limit(&mut self, _timeout: Duration) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads the next request from `stream`
+///
+/// `pending` holds bytes read past the end of the previous request (a pipelined
+/// request) and must be passed back in on the next call. Returns `None` once
+/// the client closes the connection between requests. A request cut short by
+/// the client closing its side is returned as is. A request that doesn't
+/// arrive within `timeouts` fails with `TimedOut` or `WouldBlock`, leaving
+/// the bytes received so far in `pending`. A request whose length can't be
+/// told for certain fails with `InvalidInput`; the connection can't be reused.
+pub fn read_request(
+    stream: &mut impl TimedRead,
+    pending: &mut Vec<u8>,
+    timeouts: &RequestTimeouts,
+) -> io::Result<Option<Vec<u8>>> {
+    let mut buffer = [0; 4096];
+    let mut first_byte_at = (!pending.is_empty()).then(Instant::now);
+    loop {
+        if let Some(length) = complete_request_length(pending)? {
+            let rest = pending.split_off(length);
+            return Ok(Some(std::mem::replace(pending, rest)));
+        }
+        if pending.len() > MAX_REQUEST_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("request exceeds {MAX_REQUEST_SIZE} bytes"),
+            ));
+        }
+
+        let timeout = match first_byte_at {
+            None => timeouts.idle,
+            Some(started) => {
+                let limit = if has_complete_headers(pending) {
+                    timeouts.total
+                } else {
+                    timeouts.headers
+                };
+                limit
+                    .checked_sub(started.elapsed())
+                    .filter(|remaining| !remaining.is_zero())
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::TimedOut, "request not received in time")
+                    })?
+            }
+        };
+        stream.set_read_limit(timeout)?;
+
+        let size = stream.read(&mut buffer)?;
+        if size == 0 {
+            if pending.is_empty() {
+                return Ok(None);
+            }
+            return Ok(Some(std::mem::take(pending)));
+        }
+        pending.extend_from_slice(&buffer[..size]);
+        first_byte_at.get_or_insert_with(Instant::now);
+    }
+}
+
+fn has_complete_headers(data: &[u8]) -> bool {
+    data.windows(4).any(|window| window == b"\r\n\r\n")
+}
+
+/// Length of the first request in `data`, or None if it hasn't fully arrived
+///
+/// Only a single plain Content-Length frames a body. Anything else, including
+/// chunked Transfer-Encoding, is rejected rather than guessed at, since a wrong
+/// guess would read the rest of the body as the next request.
+fn complete_request_length(data: &[u8]) -> io::Result<Option<usize>> {
+    let Some(header_end) = data.windows(4).position(|window| window == b"\r\n\r\n") else {
+        return Ok(None);
+    };
+    let header_end = header_end + 4;
+    let headers = String::from_utf8_lossy(&data[..header_end]);
+    let malformed = |reason: &str| io::Error::new(io::ErrorKind::InvalidInput, reason.to_string());
+    if !header_values(&headers, "transfer-encoding").is_empty() {
+        return Err(malformed("Transfer-Encoding is not supported"));
+    }
+    let body_length = match header_values(&headers, "content-length").as_slice() {
+        [] => 0,
+        [value] if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => value
+            .parse::<usize>()
+            .map_err(|_| malformed("Content-Length is too large"))?,
+        [_] => return Err(malformed("Content-Length is not a number")),
+        _ => return Err(malformed("Content-Length is repeated")),
+    };
+    let length = header_end.saturating_add(body_length);
+    Ok((data.len() >= length).then_some(length))
+}
+
+/// A client connection, and whether it stays open after the current response
+///
+/// Responses written through it announce the outcome in their `Connection`
+/// header, so the header always matches what the server goes on to do.
+#[derive(Debug)]
+pub struct HttpConnection<S = ClientStream> {
+    pub stream: S,
+    pub keep_alive: bool,
+}
+
+impl<S> HttpConnection<S> {
+    /// Wraps `stream`; it is closed after the response until a request asks otherwise
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            keep_alive: false,
+        }
+    }
+
+    /// Value of the `Connection` response header
+    pub fn connection_header(&self) -> &'static str {
+        if self.keep_alive {
+            "keep-alive"
+        } else {
+            "close"
+        }
+    }
+}
+
+impl<S: Write> Write for HttpConnection<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Formats `time` as an HTTP `Date` header value (RFC 1123)
+pub fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// URL scheme clients reach the ONVIF service with
+pub fn url_scheme(tls: bool) -> &'static str {
+    if tls {
+        "https"
+    } else {
+        "http"
+    }
+}
+
+/// Splits a Host header value into the host, as it appears in URLs, and the port
+///
+/// IPv6 literals keep their brackets. Returns `None` for values that aren't a
+/// plain host name or IP address, so a forged header can't inject text into URLs.
+pub fn parse_host_header(value: &str) -> Option<(String, Option<u16>)> {
+    let value = value.trim();
+    let (host, port) = match value.strip_prefix('[') {
+        Some(rest) => {
+            let (ip, after) = rest.split_once(']')?;
+            ip.parse::<Ipv6Addr>().ok()?;
+            let port = match after {
+                "" => None,
+                after => Some(after.strip_prefix(':')?),
+            };
+            (&value[..ip.len() + 2], port)
+        }
+        None => {
+            let (host, port) = match value.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (value, None),
+            };
+            let valid_name = !host.is_empty()
+                && host
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.'));
+            if !valid_name {
+                return None;
+            }
+            (host, port)
+        }
+    };
+    let port = match port {
+        Some(port) => Some(port.parse::<u16>().ok()?),
+        None => None,
+    };
+    Some((host.to_string(), port))
+}
+
+/// Returns true if a write failed because the client went away or stopped reading
+pub fn is_disconnect(kind: io::ErrorKind) -> bool {
+    matches!(
+        kind,
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+    )
+}
+
+/// Returns true if the connection should stay open after answering `request`
+///
+/// HTTP/1.1 connections are persistent unless the client sends
+/// `Connection: close`; HTTP/1.0 ones only with `Connection: keep-alive`.
+pub fn wants_keep_alive(request: &str) -> bool {
+    let is_http_10 = request
+        .lines()
+        .next()
+        .is_some_and(|line| line.trim_end().ends_with("HTTP/1.0"));
+    let connection = header_values(request, "connection");
+    let has_option = |option: &str| {
+        connection
+            .iter()
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(option))
+    };
+
+    if is_http_10 {
+        has_option("keep-alive")
+    } else {
+        !has_option("close")
+    }
+}
+
+/// Returns the values of every header with the given lowercase name, in order
+pub fn header_values<'a>(request: &'a str, name: &str) -> Vec<&'a str> {
+    request
+        .lines()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.trim()
+                .eq_ignore_ascii_case(name)
+                .then_some(value.trim())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_host_header() {
+        let parse = |value| parse_host_header(value);
+        assert_eq!(
+            parse("cam.example.com"),
+            Some(("cam.example.com".into(), None))
+        );
+        assert_eq!(
+            parse("10.0.0.9:8443"),
+            Some(("10.0.0.9".into(), Some(8443)))
+        );
+        assert_eq!(parse("[fd00::9]:80"), Some(("[fd00::9]".into(), Some(80))));
+        assert_eq!(parse("[fd00::9]"), Some(("[fd00::9]".into(), None)));
+
+        for invalid in [
+            "",
+            "cam:port",
+            "cam:99999",
+            "a b",
+            "evil\"/><x",
+            "user@cam",
+            "[not-v6]:80",
+            "[fd00::9]x",
+        ] {
+            assert_eq!(parse(invalid), None, "{invalid}");
+        }
+    }
+
+    const TIMEOUTS: RequestTimeouts = RequestTimeouts {
+        idle: Duration::from_millis(300),
+        headers: Duration::from_millis(300),
+        total: Duration::from_millis(600),
+    };
+
+    #[test]
+    fn test_read_pipelined_requests() {
+        let raw = b"POST /onvif/device_service HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET /info HTTP/1.1\r\nHost: x\r\n\r\n";
+        let mut stream = &raw[..];
+        let mut pending = Vec::new();
+
+        let first = read_request(&mut stream, &mut pending, &TIMEOUTS)
+            .unwrap()
+            .unwrap();
+        assert!(first.ends_with(b"\r\n\r\nhello"));
+        let second = read_request(&mut stream, &mut pending, &TIMEOUTS)
+            .unwrap()
+            .unwrap();
+        assert!(second.starts_with(b"GET /info"));
+        assert!(read_request(&mut stream, &mut pending, &TIMEOUTS)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_truncated_request_is_returned_on_close() {
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 100\r\n\r\nshort";
+        let mut stream = &raw[..];
+        let request = read_request(&mut stream, &mut Vec::new(), &TIMEOUTS)
+            .unwrap()
+            .unwrap();
+        assert_eq!(request, raw);
+    }
+
+    #[test]
+    fn test_oversized_request_is_rejected() {
+        let raw = vec![b'a'; MAX_REQUEST_SIZE + 4096];
+        let mut stream = &raw[..];
+        let error = read_request(&mut stream, &mut Vec::new(), &TIMEOUTS).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_ambiguous_request_length_is_rejected() {
+        for headers in [
+            "Content-Length: abc\r\n",
+            "Content-Length: -1\r\n",
+            "Content-Length: +5\r\n",
+            "Content-Length: 5\r\nContent-Length: 7\r\n",
+            "Transfer-Encoding: chunked\r\n",
+        ] {
+            let raw = format!("POST / HTTP/1.1\r\n{headers}\r\nhello");
+            let mut stream = raw.as_bytes();
+            let error = read_request(&mut stream, &mut Vec::new(), &TIMEOUTS).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{headers}");
+        }
+    }
+
+    /// Connects a client that sends `chunks` with `pause` before each, then stalls
+    fn stalled_client(chunks: Vec<&'static [u8]>, pause: Duration) -> TcpStream {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut client = TcpStream::connect(addr).unwrap();
+            for chunk in chunks {
+                std::thread::sleep(pause);
+                if client.write_all(chunk).is_err() {
+                    return;
+                }
+            }
+            std::thread::sleep(Duration::from_secs(5));
+        });
+        listener.accept().unwrap().0
+    }
+
+    #[test]
+    fn test_stalled_clients_are_dropped() {
+        let is_timeout = |e: &io::Error| {
+            matches!(
+                e.kind(),
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+            )
+        };
+
+        // A half-open connection never sends anything
+        let mut stream = stalled_client(Vec::new(), Duration::ZERO);
+        let started = Instant::now();
+        let error = read_request(&mut stream, &mut Vec::new(), &TIMEOUTS).unwrap_err();
+        assert!(is_timeout(&error), "{error}");
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        // Header bytes dribbled in faster than the per-read timeout still hit the deadline
+        let dribble = vec![b"POST / HTTP/1.1\r\n" as &[u8]; 50];
+        let mut stream = stalled_client(dribble, Duration::from_millis(50));
+        let started = Instant::now();
+        let mut pending = Vec::new();
+        let error = read_request(&mut stream, &mut pending, &TIMEOUTS).unwrap_err();
+        assert!(is_timeout(&error), "{error}");
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(!pending.is_empty());
+
+        // As does a body that never finishes
+        let body = [
+            b"POST / HTTP/1.1\r\nContent-Length: 1000\r\n\r\n" as &[u8],
+            b"a",
+            b"a",
+            b"a",
+        ];
+        let mut stream = stalled_client(body.to_vec(), Duration::from_millis(100));
+        let error = read_request(&mut stream, &mut Vec::new(), &TIMEOUTS).unwrap_err();
+        assert!(is_timeout(&error), "{error}");
+    }
+
+    #[test]
+    fn test_http_date() {
+        let time = DateTime::parse_from_rfc3339("1994-11-06T08:49:37Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+    }
+
+    #[test]
+    fn test_keep_alive_defaults() {
+        assert!(wants_keep_alive("GET / HTTP/1.1\r\nHost: x\r\n\r\n"));
+        assert!(!wants_keep_alive(
+            "GET / HTTP/1.1\r\nConnection: Close\r\n\r\n"
+        ));
+        assert!(!wants_keep_alive("GET / HTTP/1.0\r\n\r\n"));
+        assert!(wants_keep_alive(
+            "GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n"
+        ));
+    }
+}
@@ -0,0 +1,105 @@
+// ONVIF Imaging Service
+// Image settings for the single video source, fixed at neutral values. The
+// transcoder can't adjust the source picture, so SetImagingSettings is accepted
+// without effect; clients with image-adjustment UI get valid answers, not faults.
+
+use crate::onvif::endpoints::IMAGING_NAMESPACE;
+use crate::onvif::soap::SoapResponseBuilder;
+
+/// Token of the only video source, as listed by GetVideoSources
+pub const VIDEO_SOURCE_TOKEN: &str = "VideoSource_1";
+/// Brightness, colour saturation and contrast are reported mid-range
+const NEUTRAL_LEVEL: u32 = 50;
+const LEVEL_RANGE: (u32, u32) = (0, 100);
+
+fn build(body_content: &str) -> String {
+    SoapResponseBuilder::new()
+        .add_namespace("timg", IMAGING_NAMESPACE)
+        .add_namespace("tt", "http://www.onvif.org/ver10/schema")
+        .set_body(body_content)
+        .build()
+}
+
+/// Returns true if `token` names a video source this service has settings for
+///
+/// Requests without a VideoSourceToken are answered for the only source.
+pub fn is_known_video_source(token: Option<&str>) -> bool {
+    token.is_none_or(|token| token == VIDEO_SOURCE_TOKEN)
+}
+
+pub fn get_service_capabilities_response() -> String {
+    build(
+        r#"<timg:GetServiceCapabilitiesResponse>
+<timg:Capabilities ImageStabilization="false" Presets="false"/>
+</timg:GetServiceCapabilitiesResponse>"#,
+    )
+}
+
+pub fn get_imaging_settings_response() -> String {
+    build(&format!(
+        r#"<timg:GetImagingSettingsResponse>
+<timg:ImagingSettings>
+<tt:Brightness>{NEUTRAL_LEVEL}</tt:Brightness>
+<tt:ColorSaturation>{NEUTRAL_LEVEL}</tt:ColorSaturation>
+<tt:Contrast>{NEUTRAL_LEVEL}</tt:Contrast>
+</timg:ImagingSettings>
+</timg:GetImagingSettingsResponse>"#
+    ))
+}
+
+pub fn get_options_response() -> String {
+    let (min, max) = LEVEL_RANGE;
+    let range = |name: &str| {
+        format!("<tt:{name}>\n<tt:Min>{min}</tt:Min>\n<tt:Max>{max}</tt:Max>\n</tt:{name}>\n")
+    };
+    build(&format!(
+        r#"<timg:GetOptionsResponse>
+<timg:ImagingOptions>
+{}{}{}</timg:ImagingOptions>
+</timg:GetOptionsResponse>"#,
+        range("Brightness"),
+        range("ColorSaturation"),
+        range("Contrast")
+    ))
+}
+
+pub fn get_set_imaging_settings_response() -> String {
+    build("<timg:SetImagingSettingsResponse/>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_imaging_settings_are_neutral() {
+        let settings = get_imaging_settings_response();
+        assert!(settings.contains(r#"xmlns:timg="http://www.onvif.org/ver20/imaging/wsdl""#));
+        assert!(settings.contains("<timg:ImagingSettings>\n<tt:Brightness>50</tt:Brightness>"));
+        assert!(settings.contains("<tt:Contrast>50</tt:Contrast>\n</timg:ImagingSettings>"));
+    }
+
+    #[test]
+    fn test_options_cover_the_settings() {
+        let options = get_options_response();
+        for name in ["Brightness", "ColorSaturation", "Contrast"] {
+            assert!(
+                options.contains(&format!(
+                    "<tt:{name}>\n<tt:Min>0</tt:Min>\n<tt:Max>100</tt:Max>\n</tt:{name}>"
+                )),
+                "{name}"
+            );
+        }
+        assert_eq!(
+            options.matches("<timg:ImagingOptions>").count(),
+            options.matches("</timg:ImagingOptions>").count()
+        );
+    }
+
+    #[test]
+    fn test_known_video_source() {
+        assert!(is_known_video_source(None));
+        assert!(is_known_video_source(Some("VideoSource_1")));
+        assert!(!is_known_video_source(Some("VideoSource_2")));
+    }
+}
@@ -0,0 +1,159 @@
+// Metrics
+// Counters exposed in Prometheus text format at GET /metrics, so request volume
+// and error rates can be graphed.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Content type of the Prometheus text exposition format
+pub const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Distinct endpoint labels tracked; further endpoints are counted as "other"
+///
+/// Operation names come from request bodies, so this keeps a client sending
+/// made-up operations from growing the series without bound.
+const MAX_ENDPOINT_LABELS: usize = 128;
+
+/// Counters for the ONVIF service
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Connections accepted
+    pub connections: AtomicU64,
+    /// Requests rejected for missing or invalid credentials
+    pub auth_failures: AtomicU64,
+    /// Snapshot images served
+    pub snapshot_captures: AtomicU64,
+    /// Snapshot captures where ffmpeg failed, couldn't start or timed out
+    pub ffmpeg_errors: AtomicU64,
+    /// Requests handled, by endpoint (SOAP operation or GET resource)
+    requests: Mutex<BTreeMap<String, AtomicU64>>,
+}
+
+impl Metrics {
+    /// Increments `counter` by one
+    pub fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a request to `endpoint`
+    pub fn record_request(&self, endpoint: &str) {
+        let mut requests = match self.requests.lock() {
+            Ok(requests) => requests,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let endpoint = if requests.contains_key(endpoint) || requests.len() < MAX_ENDPOINT_LABELS {
+            endpoint
+        } else {
+            "other"
+        };
+        Self::increment(requests.entry(endpoint.to_string()).or_default());
+    }
+
+    /// Returns the number of requests counted for `endpoint`
+    pub fn request_count(&self, endpoint: &str) -> u64 {
+        let requests = match self.requests.lock() {
+            Ok(requests) => requests,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        requests
+            .get(endpoint)
+            .map_or(0, |count| count.load(Ordering::Relaxed))
+    }
+
+    /// Renders every counter in the Prometheus text exposition format
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "onvif_connections_total",
+                "Connections accepted by the ONVIF service",
+                &self.connections,
+            ),
+            (
+                "onvif_auth_failures_total",
+                "Requests rejected for missing or invalid credentials",
+                &self.auth_failures,
+            ),
+            (
+                "onvif_snapshot_captures_total",
+                "Snapshot images served",
+                &self.snapshot_captures,
+            ),
+            (
+                "onvif_ffmpeg_errors_total",
+                "Snapshot captures that failed in ffmpeg",
+                &self.ffmpeg_errors,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            let _ = writeln!(out, "{name} {}", counter.load(Ordering::Relaxed));
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP onvif_requests_total Requests handled, by endpoint"
+        );
+        let _ = writeln!(out, "# TYPE onvif_requests_total counter");
+        let requests = match self.requests.lock() {
+            Ok(requests) => requests,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        for (endpoint, count) in requests.iter() {
+            let _ = writeln!(
+                out,
+                "onvif_requests_total{{endpoint=\"{}\"}} {}",
+                escape_label_value(endpoint),
+                count.load(Ordering::Relaxed)
+            );
+        }
+        out
+    }
+}
+
+/// Escapes a label value as the exposition format requires
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_prometheus() {
+        let metrics = Metrics::default();
+        Metrics::increment(&metrics.connections);
+        Metrics::increment(&metrics.ffmpeg_errors);
+        metrics.record_request("GetProfiles");
+        metrics.record_request("GetProfiles");
+        metrics.record_request("odd\"name");
+
+        let text = metrics.render_prometheus();
+        assert!(
+            text.contains("# TYPE onvif_connections_total counter\nonvif_connections_total 1\n")
+        );
+        assert!(text.contains("onvif_ffmpeg_errors_total 1\n"));
+        assert!(text.contains("onvif_auth_failures_total 0\n"));
+        assert!(text.contains("onvif_requests_total{endpoint=\"GetProfiles\"} 2\n"));
+        assert!(text.contains("onvif_requests_total{endpoint=\"odd\\\"name\"} 1\n"));
+    }
+
+    #[test]
+    fn test_endpoint_labels_are_bounded() {
+        let metrics = Metrics::default();
+        for i in 0..MAX_ENDPOINT_LABELS + 5 {
+            metrics.record_request(&format!("Op{i}"));
+        }
+        metrics.record_request("Op0");
+
+        assert_eq!(metrics.request_count("Op0"), 2);
+        assert_eq!(metrics.request_count("other"), 5);
+    }
+}
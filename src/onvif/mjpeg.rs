@@ -0,0 +1,221 @@
+// MJPEG Stream
+// An HTTP multipart JPEG stream for clients that can't do RTSP, enabled with
+// --enable-mjpeg. ffmpeg transcodes the RTSP source with the same quality and
+// size limits as snapshots; its output is piped to the client until either
+// side goes away. Each viewer holds a worker thread while it watches, so at
+// most --max-mjpeg-viewers are served at once.
+
+use crate::ffmpeg::spawn_error_message;
+use crate::onvif::http::{http_date, is_disconnect, url_scheme};
+use crate::onvif::snapshot::{SnapshotError, SnapshotOptions};
+use chrono::Utc;
+use std::io::{Read, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Path the stream is served at
+pub const MJPEG_PATH: &str = "/stream.mjpeg";
+
+/// Number of clients currently watching the stream
+#[derive(Debug, Default)]
+pub struct MjpegViewers {
+    watching: AtomicUsize,
+}
+
+impl MjpegViewers {
+    /// Admits a viewer if fewer than `max` are watching; it leaves when the guard is dropped
+    pub fn admit(&self, max: usize) -> Option<ViewerGuard<'_>> {
+        self.watching
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |watching| {
+                (watching < max).then_some(watching + 1)
+            })
+            .ok()
+            .map(|_| ViewerGuard { viewers: self })
+    }
+
+    /// Number of clients watching now
+    pub fn count(&self) -> usize {
+        self.watching.load(Ordering::SeqCst)
+    }
+}
+
+/// A viewer admitted by `MjpegViewers::admit`
+pub struct ViewerGuard<'a> {
+    viewers: &'a MjpegViewers,
+}
+
+impl Drop for ViewerGuard<'_> {
+    fn drop(&mut self) {
+        self.viewers.watching.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+/// Separator between the JPEG parts of the multipart response
+const BOUNDARY: &str = "mjpegframe";
+
+/// Returns the URI GetStreamUri reports for HTTP transport
+pub fn mjpeg_stream_uri(
+    container_ip: &str,
+    onvif_port: &str,
+    tls: bool,
+    profile_token: &str,
+) -> String {
+    format!(
+        "{}://{container_ip}:{onvif_port}{MJPEG_PATH}?profile={profile_token}",
+        url_scheme(tls)
+    )
+}
+
+/// Builds the ffmpeg arguments transcoding `rtsp_stream_url` to multipart JPEG on stdout
+fn mjpeg_ffmpeg_args(rtsp_stream_url: &str, options: &SnapshotOptions) -> Vec<String> {
+    let mut args: Vec<String> = ["-i", rtsp_stream_url, "-an"]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+    if let Some(filter) = options.scale_filter() {
+        args.extend(["-vf".to_string(), filter]);
+    }
+    args.extend(
+        [
+            "-q:v",
+            &options.quality.to_string(),
+            "-f",
+            "mpjpeg",
+            "-boundary_tag",
+            BOUNDARY,
+            "-", // Output to stdout
+        ]
+        .iter()
+        .map(|arg| arg.to_string()),
+    );
+    args
+}
+
+/// Streams MJPEG from `rtsp_stream_url` to `out` until the client disconnects
+///
+/// Nothing is written until ffmpeg produces its first frame, so a source that
+/// can't be read is reported as an error the caller can answer with a status.
+pub fn stream_mjpeg(
+    out: &mut impl Write,
+    rtsp_stream_url: &str,
+    options: &SnapshotOptions,
+) -> Result<(), SnapshotError> {
+    let mut command = Command::new(&options.ffmpeg_path);
+    command.args(mjpeg_ffmpeg_args(rtsp_stream_url, options));
+    stream_command_output(&mut command, out)
+}
+
+/// Pipes the stdout of `command` to `out` as a multipart/x-mixed-replace response
+fn stream_command_output(command: &mut Command, out: &mut impl Write) -> Result<(), SnapshotError> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| {
+            let program = command.get_program().to_string_lossy();
+            SnapshotError::Unavailable(spawn_error_message("ffmpeg", &program, &e))
+        })?;
+    let result = pipe_frames(&mut child, out);
+
+    // The client is gone or ffmpeg stopped; either way the transcode is over
+    let _ = child.kill();
+    let _ = child.wait();
+    result
+}
+
+fn pipe_frames(child: &mut Child, out: &mut impl Write) -> Result<(), SnapshotError> {
+    let mut stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| SnapshotError::Unavailable("ffmpeg stdout unavailable".to_string()))?;
+    let mut buffer = [0u8; 64 * 1024];
+    let first = stdout
+        .read(&mut buffer)
+        .map_err(|e| SnapshotError::Failed(e.to_string()))?;
+    if first == 0 {
+        return Err(SnapshotError::Failed(
+            "no frames read from the source".to_string(),
+        ));
+    }
+
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nDate: {}\r\nContent-Type: multipart/x-mixed-replace; boundary={BOUNDARY}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        http_date(Utc::now())
+    );
+    let mut chunk = first;
+    let written = out.write_all(head.as_bytes()).and_then(|()| loop {
+        out.write_all(&buffer[..chunk])?;
+        chunk = stdout.read(&mut buffer)?;
+        if chunk == 0 {
+            return Ok(());
+        }
+    });
+    match written {
+        Ok(()) => Ok(()),
+        // Viewers stop watching by closing the connection
+        Err(e) if is_disconnect(e.kind()) => Ok(()),
+        Err(e) => Err(SnapshotError::Failed(e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mjpeg_ffmpeg_args() {
+        let options = SnapshotOptions {
+            quality: 5,
+            max_width: Some(640),
+            ..SnapshotOptions::default()
+        };
+        let args = mjpeg_ffmpeg_args("rtsp://cam/main", &options);
+        assert_eq!(args[..3], ["-i", "rtsp://cam/main", "-an"]);
+        assert!(args.windows(2).any(|pair| pair == ["-q:v", "5"]));
+        assert!(args.windows(2).any(|pair| pair == ["-f", "mpjpeg"]));
+        assert!(args
+            .windows(2)
+            .any(|pair| pair == ["-boundary_tag", BOUNDARY]));
+        assert!(args.contains(&"scale='min(iw,640)':-1".to_string()));
+        assert_eq!(args.last().map(String::as_str), Some("-"));
+    }
+
+    #[test]
+    fn test_command_output_is_streamed_after_headers() {
+        let mut out = Vec::new();
+        stream_command_output(Command::new("echo").arg("frame"), &mut out).unwrap();
+        let response = String::from_utf8(out).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\nDate: "));
+        assert!(
+            response.contains("Content-Type: multipart/x-mixed-replace; boundary=mjpegframe\r\n")
+        );
+        assert!(response.ends_with("\r\n\r\nframe\n"));
+
+        // A source producing nothing gets no 200 response
+        let mut out = Vec::new();
+        let result = stream_command_output(&mut Command::new("true"), &mut out);
+        assert!(matches!(result, Err(SnapshotError::Failed(_))));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_viewers_are_capped() {
+        let viewers = MjpegViewers::default();
+        let first = viewers.admit(2).unwrap();
+        let _second = viewers.admit(2).unwrap();
+        assert!(viewers.admit(2).is_none());
+
+        // A viewer leaving frees its place
+        drop(first);
+        assert_eq!(viewers.count(), 1);
+        assert!(viewers.admit(2).is_some());
+    }
+
+    #[test]
+    fn test_mjpeg_stream_uri() {
+        assert_eq!(
+            mjpeg_stream_uri("10.0.0.2", "8080", false, "HQProfile"),
+            "http://10.0.0.2:8080/stream.mjpeg?profile=HQProfile"
+        );
+    }
+}
@@ -0,0 +1,111 @@
+// ONVIF PTZ Service
+// Stub responses for a fixed camera: no PTZ configurations, no movement spaces,
+// no PTZ nodes, a status pinned at the origin, and move/stop commands that are
+// accepted but do nothing. The service isn't advertised in GetServices or
+// GetCapabilities; these answer clients that probe it during onboarding anyway.
+
+use crate::onvif::endpoints::PTZ_NAMESPACE;
+use crate::onvif::soap::SoapResponseBuilder;
+
+fn build(body_content: &str) -> String {
+    SoapResponseBuilder::new()
+        .add_namespace("tptz", PTZ_NAMESPACE)
+        .add_namespace("tt", "http://www.onvif.org/ver10/schema")
+        .set_body(body_content)
+        .build()
+}
+
+pub fn get_service_capabilities_response() -> String {
+    build(
+        r#"<tptz:GetServiceCapabilitiesResponse>
+<tptz:Capabilities EFlip="false" Reverse="false"/>
+</tptz:GetServiceCapabilitiesResponse>"#,
+    )
+}
+
+/// An empty configuration list; a configuration would have to name a node, and there are none
+pub fn get_configurations_response() -> String {
+    build("<tptz:GetConfigurationsResponse/>")
+}
+
+/// Advertises no coordinate spaces, which tells clients the camera can't move
+pub fn get_configuration_options_response() -> String {
+    build(
+        r#"<tptz:GetConfigurationOptionsResponse>
+<tptz:PTZConfigurationOptions>
+<tt:Spaces/>
+<tt:PTZTimeout>
+<tt:Min>PT0S</tt:Min>
+<tt:Max>PT0S</tt:Max>
+</tt:PTZTimeout>
+</tptz:PTZConfigurationOptions>
+</tptz:GetConfigurationOptionsResponse>"#,
+    )
+}
+
+/// An empty node list, the spec's way of saying the device has no PTZ nodes
+pub fn get_nodes_response() -> String {
+    build("<tptz:GetNodesResponse/>")
+}
+
+pub fn get_status_response() -> String {
+    let now = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+
+    build(&format!(
+        r#"<tptz:GetStatusResponse>
+<tptz:PTZStatus>
+<tt:Position>
+<tt:PanTilt x="0" y="0"/>
+<tt:Zoom x="0"/>
+</tt:Position>
+<tt:MoveStatus>
+<tt:PanTilt>IDLE</tt:PanTilt>
+<tt:Zoom>IDLE</tt:Zoom>
+</tt:MoveStatus>
+<tt:UtcTime>{now}</tt:UtcTime>
+</tptz:PTZStatus>
+</tptz:GetStatusResponse>"#
+    ))
+}
+
+pub fn get_continuous_move_response() -> String {
+    build("<tptz:ContinuousMoveResponse/>")
+}
+
+pub fn get_stop_response() -> String {
+    build("<tptz:StopResponse/>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_reports_fixed_position() {
+        let status = get_status_response();
+        assert!(status.contains(r#"<tt:PanTilt x="0" y="0"/>"#));
+        assert!(status.contains(r#"<tt:Zoom x="0"/>"#));
+        assert!(status.contains("<tt:PanTilt>IDLE</tt:PanTilt>"));
+    }
+
+    #[test]
+    fn test_nodes_list_is_empty() {
+        let nodes = get_nodes_response();
+        assert!(nodes.contains("<tptz:GetNodesResponse/>"));
+        assert!(!nodes.contains("PTZNode"));
+    }
+
+    #[test]
+    fn test_configurations_list_is_empty() {
+        let configurations = get_configurations_response();
+        assert!(configurations.contains("<tptz:GetConfigurationsResponse/>"));
+        assert!(!configurations.contains("NodeToken"));
+    }
+
+    #[test]
+    fn test_configuration_options_advertise_no_spaces() {
+        let options = get_configuration_options_response();
+        assert!(options.contains("<tt:Spaces/>"));
+        assert!(!options.contains("ContinuousPanTiltVelocitySpace"));
+    }
+}
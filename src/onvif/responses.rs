@@ -1,73 +1,133 @@
 // ONVIF Response Templates
 // This module contains all the hardcoded ONVIF SOAP responses
 
-use crate::onvif::soap::SoapResponseBuilder;
+use crate::id_generator::IdGenerator;
+use crate::onvif::date_time::DateTimeSettings;
+use crate::onvif::encoder_config::{EncoderOptions, GOV_LENGTH, MIN_BITRATE_KBPS, QUALITY_RANGE};
+use crate::onvif::endpoints::Service;
+use crate::onvif::http::url_scheme;
+use crate::onvif::profiles::ProfileConfig;
+use crate::onvif::soap::{xml_escape, SoapResponseBuilder, XmlWriter};
+use crate::scopes::Scope;
+use crate::stream_probe::{AudioEncoding, StreamParams, VideoEncoding};
 use chrono::{Datelike, Timelike};
+use std::time::Duration;
 
-pub fn get_capabilities_response(container_ip: &str, onvif_port: &str) -> String {
-    let body_content = format!(
-        r#"<tds:GetCapabilitiesResponse xmlns:tds="http://www.onvif.org/ver10/device/wsdl">
-<tds:Capabilities>
-<tt:Device xmlns:tt="http://www.onvif.org/ver10/schema">
-<tt:XAddr>http://{container_ip}:{onvif_port}/onvif/device_service</tt:XAddr>
-<tt:Network>
-<tt:IPFilter>false</tt:IPFilter>
-<tt:ZeroConfiguration>false</tt:ZeroConfiguration>
-<tt:IPVersion6>false</tt:IPVersion6>
-<tt:DynDNS>false</tt:DynDNS>
-</tt:Network>
-<tt:System>
-<tt:DiscoveryResolve>false</tt:DiscoveryResolve>
-<tt:DiscoveryBye>false</tt:DiscoveryBye>
-<tt:RemoteDiscovery>false</tt:RemoteDiscovery>
-<tt:SystemBackup>false</tt:SystemBackup>
-<tt:SystemLogging>false</tt:SystemLogging>
-<tt:FirmwareUpgrade>false</tt:FirmwareUpgrade>
-<tt:SupportedVersions>
-<tt:Major>2</tt:Major>
-<tt:Minor>60</tt:Minor>
-</tt:SupportedVersions>
-</tt:System>
-<tt:IO>
-<tt:InputConnectors>0</tt:InputConnectors>
-<tt:RelayOutputs>0</tt:RelayOutputs>
-</tt:IO>
-<tt:Security>
-<tt:TLS1.1>false</tt:TLS1.1>
-<tt:TLS1.2>false</tt:TLS1.2>
-<tt:OnboardKeyGeneration>false</tt:OnboardKeyGeneration>
-<tt:AccessPolicyConfig>false</tt:AccessPolicyConfig>
-<tt:X.509Token>false</tt:X.509Token>
-<tt:SAMLToken>false</tt:SAMLToken>
-<tt:KerberosToken>false</tt:KerberosToken>
-<tt:RELToken>false</tt:RELToken>
-<tt:UsernameToken>true</tt:UsernameToken>
-<tt:HttpDigest>true</tt:HttpDigest>
-<tt:WSUsernameToken>true</tt:WSUsernameToken>
-<tt:WSSecurityDuration>5</tt:WSSecurityDuration>
-</tt:Security>
-</tt:Device>
-<tt:Media xmlns:tt="http://www.onvif.org/ver10/schema">
-<tt:XAddr>http://{container_ip}:{onvif_port}/onvif/device_service</tt:XAddr>
-<tt:StreamingCapabilities>
-<tt:RTPMulticast>false</tt:RTPMulticast>
-<tt:RTP_TCP>true</tt:RTP_TCP>
-<tt:RTP_RTSP_TCP>true</tt:RTP_RTSP_TCP>
-</tt:StreamingCapabilities>
-</tt:Media>
-</tds:Capabilities>
-</tds:GetCapabilitiesResponse>"#
-    );
+/// Token of the only audio input, listed by GetAudioSources when the source has audio
+const AUDIO_SOURCE_TOKEN: &str = "AudioSource_1";
+/// Every profile shares one audio source configuration and one audio encoder configuration
+pub const AUDIO_SOURCE_CONFIG_TOKEN: &str = "AudioSourceConfig";
+pub const AUDIO_ENCODER_CONFIG_TOKEN: &str = "AudioEncoderConfig";
 
-    SoapResponseBuilder::new().set_body(&body_content).build()
+pub fn get_capabilities_response(
+    container_ip: &str,
+    onvif_port: &str,
+    tls: bool,
+    services: &[Service],
+) -> String {
+    const TT: (&str, &str) = ("xmlns:tt", "http://www.onvif.org/ver10/schema");
+    let base = format!("{}://{container_ip}:{onvif_port}/onvif", url_scheme(tls));
+
+    let mut xml = XmlWriter::new();
+    xml.start(
+        "tds:GetCapabilitiesResponse",
+        &[("xmlns:tds", "http://www.onvif.org/ver10/device/wsdl")],
+    )
+    .start("tds:Capabilities", &[])
+    .start("tt:Device", &[TT])
+    .text_element("tt:XAddr", &[], &format!("{base}/device_service"))
+    .start("tt:Network", &[]);
+    for flag in [
+        "tt:IPFilter",
+        "tt:ZeroConfiguration",
+        "tt:IPVersion6",
+        "tt:DynDNS",
+    ] {
+        xml.text_element(flag, &[], "false");
+    }
+    xml.end("tt:Network").start("tt:System", &[]);
+    // WS-Discovery answers Resolve for the device's endpoint reference
+    for (flag, value) in [
+        ("tt:DiscoveryResolve", "true"),
+        ("tt:DiscoveryBye", "false"),
+        ("tt:RemoteDiscovery", "false"),
+        ("tt:SystemBackup", "false"),
+        ("tt:SystemLogging", "false"),
+        ("tt:FirmwareUpgrade", "false"),
+    ] {
+        xml.text_element(flag, &[], value);
+    }
+    xml.start("tt:SupportedVersions", &[])
+        .text_element("tt:Major", &[], "2")
+        .text_element("tt:Minor", &[], "60")
+        .end("tt:SupportedVersions")
+        .end("tt:System")
+        .start("tt:IO", &[])
+        .text_element("tt:InputConnectors", &[], "0")
+        .text_element("tt:RelayOutputs", &[], "0")
+        .end("tt:IO")
+        .start("tt:Security", &[]);
+    for (flag, value) in [
+        ("tt:TLS1.1", "false"),
+        ("tt:TLS1.2", if tls { "true" } else { "false" }),
+        ("tt:OnboardKeyGeneration", "false"),
+        ("tt:AccessPolicyConfig", "false"),
+        ("tt:X.509Token", "false"),
+        ("tt:SAMLToken", "false"),
+        ("tt:KerberosToken", "false"),
+        ("tt:RELToken", "false"),
+        ("tt:UsernameToken", "true"),
+        ("tt:HttpDigest", "true"),
+        ("tt:WSUsernameToken", "true"),
+        ("tt:WSSecurityDuration", "5"),
+    ] {
+        xml.text_element(flag, &[], value);
+    }
+    xml.end("tt:Security").end("tt:Device");
+    // The schema orders the categories Analytics, Device, Events, Imaging, Media, PTZ
+    if services.contains(&Service::Events) {
+        xml.start("tt:Events", &[TT])
+            .text_element("tt:XAddr", &[], &format!("{base}/events_service"))
+            .text_element("tt:WSSubscriptionPolicySupport", &[], "false")
+            .text_element("tt:WSPullPointSupport", &[], "true")
+            .text_element(
+                "tt:WSPausableSubscriptionManagerInterfaceSupport",
+                &[],
+                "false",
+            )
+            .end("tt:Events");
+    }
+    xml.start("tt:Media", &[TT])
+        .text_element("tt:XAddr", &[], &format!("{base}/media_service"))
+        .start("tt:StreamingCapabilities", &[])
+        .text_element("tt:RTPMulticast", &[], "false")
+        .text_element("tt:RTP_TCP", &[], "true")
+        .text_element("tt:RTP_RTSP_TCP", &[], "true")
+        .end("tt:StreamingCapabilities")
+        .end("tt:Media")
+        .end("tds:Capabilities")
+        .end("tds:GetCapabilitiesResponse");
+
+    SoapResponseBuilder::new().set_body(&xml.finish()).build()
 }
 
-pub fn get_services_response(container_ip: &str, onvif_port: &str) -> String {
-    let body_content = format!(
-        r#"<tds:GetServicesResponse xmlns:tds="http://www.onvif.org/ver10/device/wsdl">
-<tds:Service>
-<tds:Namespace>http://www.onvif.org/ver10/device/wsdl</tds:Namespace>
-<tds:XAddr>http://{container_ip}:{onvif_port}/onvif/device_service</tds:XAddr>
+pub fn get_services_response(
+    container_ip: &str,
+    onvif_port: &str,
+    tls: bool,
+    services: &[Service],
+) -> String {
+    let base = xml_escape(&format!(
+        "{}://{container_ip}:{onvif_port}",
+        url_scheme(tls)
+    ));
+    let mut body_content = String::from(
+        r#"<tds:GetServicesResponse xmlns:tds="http://www.onvif.org/ver10/device/wsdl">"#,
+    );
+    for &service in services {
+        let capabilities = match service {
+            Service::Device => format!(
+                r#"
 <tds:Capabilities>
 <tds:Network>
 <tds:IPFilter>false</tds:IPFilter>
@@ -76,7 +136,7 @@ pub fn get_services_response(container_ip: &str, onvif_port: &str) -> String {
 <tds:DynDNS>false</tds:DynDNS>
 </tds:Network>
 <tds:System>
-<tds:DiscoveryResolve>false</tds:DiscoveryResolve>
+<tds:DiscoveryResolve>true</tds:DiscoveryResolve>
 <tds:DiscoveryBye>false</tds:DiscoveryBye>
 <tds:RemoteDiscovery>false</tds:RemoteDiscovery>
 <tds:SystemBackup>false</tds:SystemBackup>
@@ -93,7 +153,7 @@ pub fn get_services_response(container_ip: &str, onvif_port: &str) -> String {
 </tds:IO>
 <tds:Security>
 <tds:TLS1.1>false</tds:TLS1.1>
-<tds:TLS1.2>false</tds:TLS1.2>
+<tds:TLS1.2>{tls}</tds:TLS1.2>
 <tds:OnboardKeyGeneration>false</tds:OnboardKeyGeneration>
 <tds:AccessPolicyConfig>false</tds:AccessPolicyConfig>
 <tds:X.509Token>false</tds:X.509Token>
@@ -101,102 +161,166 @@ pub fn get_services_response(container_ip: &str, onvif_port: &str) -> String {
 <tds:KerberosToken>false</tds:KerberosToken>
 <tds:RELToken>false</tds:RELToken>
 </tds:Security>
-</tds:Capabilities>
-<tds:Version>
-<tds:Major>2</tds:Major>
-<tds:Minor>60</tds:Minor>
-</tds:Version>
-</tds:Service>
-<tds:Service>
-<tds:Namespace>http://www.onvif.org/ver10/media/wsdl</tds:Namespace>
-<tds:XAddr>http://{container_ip}:{onvif_port}/onvif/device_service</tds:XAddr>
+</tds:Capabilities>"#
+            ),
+            Service::Media => r#"
 <tds:Capabilities>
 <tds:StreamingCapabilities>
 <tds:RTPMulticast>false</tds:RTPMulticast>
 <tds:RTP_TCP>true</tds:RTP_TCP>
 <tds:RTP_RTSP_TCP>true</tds:RTP_RTSP_TCP>
 </tds:StreamingCapabilities>
-</tds:Capabilities>
+</tds:Capabilities>"#
+                .to_string(),
+            Service::Ptz | Service::Events | Service::Imaging => String::new(),
+        };
+        body_content.push_str(&format!(
+            r#"
+<tds:Service>
+<tds:Namespace>{}</tds:Namespace>
+<tds:XAddr>{base}{}</tds:XAddr>{capabilities}
 <tds:Version>
 <tds:Major>2</tds:Major>
 <tds:Minor>60</tds:Minor>
 </tds:Version>
-</tds:Service>
-</tds:GetServicesResponse>"#
+</tds:Service>"#,
+            service.namespace(),
+            service.path()
+        ));
+    }
+    body_content.push_str("\n</tds:GetServicesResponse>");
+
+    SoapResponseBuilder::new().set_body(&body_content).build()
+}
+
+/// One profile as a `trt:{element}` element (`Profiles` in GetProfiles, `Profile` in GetProfile)
+///
+/// `profile_count` is the number of advertised profiles, the use count of the
+/// audio configurations they all share.
+fn profile_block(
+    element: &str,
+    profile: &ProfileConfig,
+    params: &StreamParams,
+    profile_count: usize,
+) -> String {
+    let token = xml_escape(&profile.token);
+    let name = xml_escape(&profile.name);
+    let suffix = xml_escape(profile.config_suffix());
+    let source = video_source_configuration_fields(params);
+    // An H265 profile is left without an encoder: Media1 can't describe it
+    let encoder = if profile.encoding(params).is_media1() {
+        let fields = video_encoder_configuration_fields(profile, params);
+        format!(
+            r#"<tt:VideoEncoderConfiguration token="VideoEncoderConfig_{suffix}">
+<tt:Name>VideoEncoderConfig_{suffix}</tt:Name>
+{fields}</tt:VideoEncoderConfiguration>
+"#
+        )
+    } else {
+        String::new()
+    };
+    let audio = audio_configurations(params, profile_count);
+    format!(
+        r#"<trt:{element} token="{token}" fixed="true">
+<tt:Name xmlns:tt="http://www.onvif.org/ver10/schema">{name}</tt:Name>
+<tt:VideoSourceConfiguration token="VideoSourceConfig_{suffix}">
+<tt:Name>VideoSourceConfig_{suffix}</tt:Name>
+{source}</tt:VideoSourceConfiguration>
+{encoder}{audio}</trt:{element}>
+"#
+    )
+}
+
+/// The audio configurations a profile references, matching GetAudioSourceConfigurations
+/// and GetAudioEncoderConfigurations; empty when the source has no audio
+fn audio_configurations(params: &StreamParams, use_count: usize) -> String {
+    if !params.has_audio() {
+        return String::new();
+    }
+    let source = audio_source_configuration_fields(use_count);
+    let mut audio = format!(
+        r#"<tt:AudioSourceConfiguration token="{AUDIO_SOURCE_CONFIG_TOKEN}">
+<tt:Name>{AUDIO_SOURCE_CONFIG_TOKEN}</tt:Name>
+{source}</tt:AudioSourceConfiguration>
+"#
+    );
+    if let (Some(encoding), Some(sample_rate)) =
+        (params.audio_encoding(), params.audio_sample_rate())
+    {
+        let encoder = audio_encoder_configuration_fields(encoding, sample_rate, use_count);
+        audio.push_str(&format!(
+            r#"<tt:AudioEncoderConfiguration token="{AUDIO_ENCODER_CONFIG_TOKEN}">
+<tt:Name>{AUDIO_ENCODER_CONFIG_TOKEN}</tt:Name>
+{encoder}</tt:AudioEncoderConfiguration>
+"#
+        ));
+    }
+    audio
+}
+
+pub fn get_profiles_response(params: &StreamParams, profiles: &[ProfileConfig]) -> String {
+    let profile_blocks: String = profiles
+        .iter()
+        .map(|profile| profile_block("Profiles", profile, params, profiles.len()))
+        .collect();
+    let body_content = format!(
+        r#"<trt:GetProfilesResponse xmlns:trt="http://www.onvif.org/ver10/media/wsdl">
+{profile_blocks}</trt:GetProfilesResponse>"#
     );
 
     SoapResponseBuilder::new().set_body(&body_content).build()
 }
 
-pub fn get_profiles_response() -> String {
-    let body_content = r#"<trt:GetProfilesResponse xmlns:trt="http://www.onvif.org/ver10/media/wsdl">
-<trt:Profiles token="HQProfile" fixed="true">
-<tt:Name xmlns:tt="http://www.onvif.org/ver10/schema">HQProfile</tt:Name>
-<tt:VideoSourceConfiguration token="VideoSourceConfig_HQ">
-<tt:Name>VideoSourceConfig_HQ</tt:Name>
-<tt:UseCount>1</tt:UseCount>
-<tt:SourceToken>VideoSource_1</tt:SourceToken>
-<tt:Bounds x="0" y="0" width="960" height="540"/>
-</tt:VideoSourceConfiguration>
-<tt:VideoEncoderConfiguration token="VideoEncoderConfig_HQ">
-<tt:Name>VideoEncoderConfig_HQ</tt:Name>
-<tt:UseCount>1</tt:UseCount>
-<tt:Encoding>H264</tt:Encoding>
-<tt:Resolution>
-<tt:Width>960</tt:Width>
-<tt:Height>540</tt:Height>
-</tt:Resolution>
-<tt:Quality>4</tt:Quality>
-<tt:RateControl>
-<tt:FrameRateLimit>15</tt:FrameRateLimit>
-<tt:EncodingInterval>1</tt:EncodingInterval>
-<tt:BitrateLimit>1500</tt:BitrateLimit>
-</tt:RateControl>
-<tt:H264>
-<tt:GovLength>15</tt:GovLength>
-<tt:H264Profile>Main</tt:H264Profile>
-<tt:Level>4.1</tt:Level>
-</tt:H264>
-<tt:Multicast>
-<tt:Address>
-<tt:Type>IPv4</tt:Type>
-<tt:IPv4Address>0.0.0.0</tt:IPv4Address>
-</tt:Address>
-<tt:Port>0</tt:Port>
-<tt:TTL>1</tt:TTL>
-<tt:AutoStart>false</tt:AutoStart>
-</tt:Multicast>
-<tt:SessionTimeout>PT60S</tt:SessionTimeout>
-</tt:VideoEncoderConfiguration>
-</trt:Profiles>
-<trt:Profiles token="LQProfile" fixed="true">
-<tt:Name xmlns:tt="http://www.onvif.org/ver10/schema">LQProfile</tt:Name>
-<tt:VideoSourceConfiguration token="VideoSourceConfig_LQ">
-<tt:Name>VideoSourceConfig_LQ</tt:Name>
-<tt:UseCount>1</tt:UseCount>
+pub fn get_profile_response(
+    params: &StreamParams,
+    profile: &ProfileConfig,
+    profile_count: usize,
+) -> String {
+    let profile_block = profile_block("Profile", profile, params, profile_count);
+    let body_content = format!(
+        r#"<trt:GetProfileResponse xmlns:trt="http://www.onvif.org/ver10/media/wsdl">
+{profile_block}</trt:GetProfileResponse>"#
+    );
+
+    SoapResponseBuilder::new().set_body(&body_content).build()
+}
+
+/// Renders the fields of a video source configuration after its Name
+///
+/// Every profile shares the single video source, so the bounds are the source resolution.
+fn video_source_configuration_fields(params: &StreamParams) -> String {
+    let width = params.width;
+    let height = params.height;
+    format!(
+        r#"<tt:UseCount>1</tt:UseCount>
 <tt:SourceToken>VideoSource_1</tt:SourceToken>
-<tt:Bounds x="0" y="0" width="960" height="540"/>
-</tt:VideoSourceConfiguration>
-<tt:VideoEncoderConfiguration token="VideoEncoderConfig_LQ">
-<tt:Name>VideoEncoderConfig_LQ</tt:Name>
-<tt:UseCount>1</tt:UseCount>
-<tt:Encoding>H264</tt:Encoding>
+<tt:Bounds x="0" y="0" width="{width}" height="{height}"/>
+"#
+    )
+}
+
+/// Renders the fields of a profile's video encoder configuration after its Name
+fn video_encoder_configuration_fields(profile: &ProfileConfig, params: &StreamParams) -> String {
+    let (width, height) = profile.resolution(params);
+    let frame_rate = profile.frame_rate_limit(params);
+    let bitrate = profile.bitrate_kbps;
+    let encoding = profile.encoding(params);
+    let encoding_name = encoding.as_onvif();
+    let codec = codec_settings(encoding, &profile.h264_profile, &profile.level);
+    format!(
+        r#"<tt:UseCount>1</tt:UseCount>
+<tt:Encoding>{encoding_name}</tt:Encoding>
 <tt:Resolution>
-<tt:Width>960</tt:Width>
-<tt:Height>540</tt:Height>
+<tt:Width>{width}</tt:Width>
+<tt:Height>{height}</tt:Height>
 </tt:Resolution>
 <tt:Quality>4</tt:Quality>
 <tt:RateControl>
-<tt:FrameRateLimit>15</tt:FrameRateLimit>
+<tt:FrameRateLimit>{frame_rate}</tt:FrameRateLimit>
 <tt:EncodingInterval>1</tt:EncodingInterval>
-<tt:BitrateLimit>1500</tt:BitrateLimit>
+<tt:BitrateLimit>{bitrate}</tt:BitrateLimit>
 </tt:RateControl>
-<tt:H264>
-<tt:GovLength>15</tt:GovLength>
-<tt:H264Profile>Baseline</tt:H264Profile>
-<tt:Level>3.1</tt:Level>
-</tt:H264>
-<tt:Multicast>
+{codec}<tt:Multicast>
 <tt:Address>
 <tt:Type>IPv4</tt:Type>
 <tt:IPv4Address>0.0.0.0</tt:IPv4Address>
@@ -206,60 +330,205 @@ pub fn get_profiles_response() -> String {
 <tt:AutoStart>false</tt:AutoStart>
 </tt:Multicast>
 <tt:SessionTimeout>PT60S</tt:SessionTimeout>
-</tt:VideoEncoderConfiguration>
-</trt:Profiles>
-</trt:GetProfilesResponse>"#;
+"#
+    )
+}
 
-    SoapResponseBuilder::new().set_body(body_content).build()
+/// Renders the codec-specific block of a video encoder configuration
+///
+/// JPEG has no codec block, and H265 configurations aren't rendered at all.
+fn codec_settings(encoding: VideoEncoding, h264_profile: &str, level: &str) -> String {
+    let h264_profile = xml_escape(h264_profile);
+    let level = xml_escape(level);
+    match encoding {
+        VideoEncoding::H264 => format!(
+            "<tt:H264>\n<tt:GovLength>15</tt:GovLength>\n<tt:H264Profile>{h264_profile}</tt:H264Profile>\n<tt:Level>{level}</tt:Level>\n</tt:H264>\n"
+        ),
+        VideoEncoding::H265 | VideoEncoding::Jpeg => String::new(),
+    }
 }
 
 pub fn get_stream_uri_response(rtsp_stream: &str) -> String {
-    let body_content = format!(
-        r#"<trt:GetStreamUriResponse xmlns:trt="http://www.onvif.org/ver10/media/wsdl">
-<trt:MediaUri>
-<tt:Uri xmlns:tt="http://www.onvif.org/ver10/schema">{rtsp_stream}</tt:Uri>
-</trt:MediaUri>
-</trt:GetStreamUriResponse>"#
-    );
+    const TT: (&str, &str) = ("xmlns:tt", "http://www.onvif.org/ver10/schema");
+    let body_content = XmlWriter::new()
+        .start(
+            "trt:GetStreamUriResponse",
+            &[("xmlns:trt", "http://www.onvif.org/ver10/media/wsdl")],
+        )
+        .start("trt:MediaUri", &[])
+        .text_element("tt:Uri", &[TT], rtsp_stream)
+        .text_element("tt:InvalidAfterConnect", &[TT], "false")
+        .text_element("tt:InvalidAfterReboot", &[TT], "false")
+        .text_element("tt:Timeout", &[TT], "PT60S")
+        .end("trt:MediaUri")
+        .end("trt:GetStreamUriResponse")
+        .finish();
 
     SoapResponseBuilder::new().set_body(&body_content).build()
 }
 
-pub fn get_device_info_response(device_name: &str) -> String {
-    let body_content = format!(
-        r#"<tds:GetDeviceInformationResponse xmlns:tds="http://www.onvif.org/ver10/device/wsdl">
-<tds:Manufacturer>ONVIF Media Solutions</tds:Manufacturer>
-<tds:Model>{}</tds:Model>
-<tds:FirmwareVersion>1.0.0</tds:FirmwareVersion>
-<tds:SerialNumber>EMU-{}</tds:SerialNumber>
-<tds:HardwareId>onvif-media-transcoder</tds:HardwareId>
-</tds:GetDeviceInformationResponse>"#,
-        device_name,
-        device_name.chars().take(6).collect::<String>()
+/// Identity reported by GetDeviceInformation, GET /info and WS-Discovery
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInformation {
+    pub manufacturer: String,
+    pub model: String,
+    pub firmware_version: String,
+    pub serial_number: String,
+    pub hardware_id: String,
+}
+
+pub fn get_device_info_response(info: &DeviceInformation) -> String {
+    let body_content = XmlWriter::new()
+        .start(
+            "tds:GetDeviceInformationResponse",
+            &[("xmlns:tds", "http://www.onvif.org/ver10/device/wsdl")],
+        )
+        .text_element("tds:Manufacturer", &[], &info.manufacturer)
+        .text_element("tds:Model", &[], &info.model)
+        .text_element("tds:FirmwareVersion", &[], &info.firmware_version)
+        .text_element("tds:SerialNumber", &[], &info.serial_number)
+        .text_element("tds:HardwareId", &[], &info.hardware_id)
+        .end("tds:GetDeviceInformationResponse")
+        .finish();
+
+    SoapResponseBuilder::new().set_body(&body_content).build()
+}
+
+/// Reports the device name as a static (non-DHCP) hostname
+pub fn get_hostname_response(device_name: &str) -> String {
+    const TT: (&str, &str) = ("xmlns:tt", "http://www.onvif.org/ver10/schema");
+    let body_content = XmlWriter::new()
+        .start(
+            "tds:GetHostnameResponse",
+            &[("xmlns:tds", "http://www.onvif.org/ver10/device/wsdl")],
+        )
+        .start("tds:HostnameInformation", &[])
+        .text_element("tt:FromDHCP", &[TT], "false")
+        .text_element("tt:Name", &[TT], device_name)
+        .end("tds:HostnameInformation")
+        .end("tds:GetHostnameResponse")
+        .finish();
+
+    SoapResponseBuilder::new().set_body(&body_content).build()
+}
+
+/// The configured user, always an administrator; passwords are never listed
+pub fn get_users_response(username: &str) -> String {
+    const TT: (&str, &str) = ("xmlns:tt", "http://www.onvif.org/ver10/schema");
+    let body_content = XmlWriter::new()
+        .start(
+            "tds:GetUsersResponse",
+            &[("xmlns:tds", "http://www.onvif.org/ver10/device/wsdl")],
+        )
+        .start("tds:User", &[])
+        .text_element("tt:Username", &[TT], username)
+        .text_element("tt:UserLevel", &[TT], "Administrator")
+        .end("tds:User")
+        .end("tds:GetUsersResponse")
+        .finish();
+
+    SoapResponseBuilder::new().set_body(&body_content).build()
+}
+
+/// Reports a static DNS configuration with no search domains or servers
+///
+/// Name resolution belongs to the host; the transcoder has nothing to manage.
+pub fn get_dns_response() -> String {
+    const TT: (&str, &str) = ("xmlns:tt", "http://www.onvif.org/ver10/schema");
+    let body_content = XmlWriter::new()
+        .start(
+            "tds:GetDNSResponse",
+            &[("xmlns:tds", "http://www.onvif.org/ver10/device/wsdl")],
+        )
+        .start("tds:DNSInformation", &[])
+        .text_element("tt:FromDHCP", &[TT], "false")
+        .end("tds:DNSInformation")
+        .end("tds:GetDNSResponse")
+        .finish();
+
+    SoapResponseBuilder::new().set_body(&body_content).build()
+}
+
+pub fn get_scopes_response(scopes: &[Scope]) -> String {
+    let mut writer = XmlWriter::new();
+    writer.start(
+        "tds:GetScopesResponse",
+        &[
+            ("xmlns:tds", "http://www.onvif.org/ver10/device/wsdl"),
+            ("xmlns:tt", "http://www.onvif.org/ver10/schema"),
+        ],
     );
+    for scope in scopes {
+        writer
+            .start("tds:Scopes", &[])
+            .text_element("tt:ScopeDef", &[], scope.definition.as_onvif())
+            .text_element("tt:ScopeItem", &[], &scope.item)
+            .end("tds:Scopes");
+    }
+    let body_content = writer.end("tds:GetScopesResponse").finish();
 
     SoapResponseBuilder::new().set_body(&body_content).build()
 }
 
-pub fn get_video_sources_response() -> String {
-    let body_content = r#"<trt:GetVideoSourcesResponse xmlns:trt="http://www.onvif.org/ver10/media/wsdl">
+/// Acknowledges SetScopes or AddScopes, named by `operation`
+pub fn get_scopes_updated_response(operation: &str) -> String {
+    SoapResponseBuilder::new()
+        .add_namespace("tds", "http://www.onvif.org/ver10/device/wsdl")
+        .set_body(&format!("<tds:{operation}Response/>"))
+        .build()
+}
+
+pub fn get_system_reboot_response(message: &str) -> String {
+    SoapResponseBuilder::new()
+        .add_namespace("tds", "http://www.onvif.org/ver10/device/wsdl")
+        .set_body(&format!(
+            "<tds:SystemRebootResponse>\n<tds:Message>{}</tds:Message>\n</tds:SystemRebootResponse>",
+            xml_escape(message)
+        ))
+        .build()
+}
+
+pub fn get_video_sources_response(params: &StreamParams) -> String {
+    let width = params.width;
+    let height = params.height;
+    let frame_rate = params.source_frame_rate();
+    let body_content = format!(
+        r#"<trt:GetVideoSourcesResponse xmlns:trt="http://www.onvif.org/ver10/media/wsdl">
 <trt:VideoSources token="VideoSource_1">
-<tt:Framerate xmlns:tt="http://www.onvif.org/ver10/schema">15</tt:Framerate>
+<tt:Framerate xmlns:tt="http://www.onvif.org/ver10/schema">{frame_rate}</tt:Framerate>
 <tt:Resolution xmlns:tt="http://www.onvif.org/ver10/schema">
-<tt:Width>960</tt:Width>
-<tt:Height>540</tt:Height>
+<tt:Width>{width}</tt:Width>
+<tt:Height>{height}</tt:Height>
 </tt:Resolution>
 </trt:VideoSources>
-</trt:GetVideoSourcesResponse>"#;
+</trt:GetVideoSourcesResponse>"#
+    );
 
-    SoapResponseBuilder::new().set_body(body_content).build()
+    SoapResponseBuilder::new().set_body(&body_content).build()
 }
 
-pub fn get_service_capabilities_response() -> String {
-    let body_content = r#"<trt:GetServiceCapabilitiesResponse xmlns:trt="http://www.onvif.org/ver10/media/wsdl">
+/// GetServiceCapabilities of the device service
+pub fn get_device_service_capabilities_response(tls: bool) -> String {
+    let body_content = format!(
+        r#"<tds:GetServiceCapabilitiesResponse xmlns:tds="http://www.onvif.org/ver10/device/wsdl">
+<tds:Capabilities>
+<tds:Network IPFilter="false" ZeroConfiguration="false" IPVersion6="false" DynDNS="false"/>
+<tds:Security TLS1.1="false" TLS1.2="{tls}" HttpDigest="true" UsernameToken="true"/>
+<tds:System DiscoveryResolve="true" DiscoveryBye="false" RemoteDiscovery="false" SystemBackup="false" SystemLogging="false" FirmwareUpgrade="false"/>
+</tds:Capabilities>
+</tds:GetServiceCapabilitiesResponse>"#
+    );
+
+    SoapResponseBuilder::new().set_body(&body_content).build()
+}
+
+/// Media service capabilities; `max_profiles` is the number of configured profiles
+pub fn get_service_capabilities_response(max_profiles: usize) -> String {
+    let body_content = format!(
+        r#"<trt:GetServiceCapabilitiesResponse xmlns:trt="http://www.onvif.org/ver10/media/wsdl">
 <trt:Capabilities>
 <tt:ProfileCapabilities xmlns:tt="http://www.onvif.org/ver10/schema">
-<tt:MaximumNumberOfProfiles>2</tt:MaximumNumberOfProfiles>
+<tt:MaximumNumberOfProfiles>{max_profiles}</tt:MaximumNumberOfProfiles>
 </tt:ProfileCapabilities>
 <tt:StreamingCapabilities xmlns:tt="http://www.onvif.org/ver10/schema">
 <tt:RTPMulticast>false</tt:RTPMulticast>
@@ -267,81 +536,180 @@ pub fn get_service_capabilities_response() -> String {
 <tt:RTP_RTSP_TCP>true</tt:RTP_RTSP_TCP>
 </tt:StreamingCapabilities>
 </trt:Capabilities>
-</trt:GetServiceCapabilitiesResponse>"#;
+</trt:GetServiceCapabilitiesResponse>"#
+    );
 
-    SoapResponseBuilder::new().set_body(body_content).build()
+    SoapResponseBuilder::new().set_body(&body_content).build()
 }
 
-pub fn get_video_source_configurations_response() -> String {
-    let body_content = r#"<trt:GetVideoSourceConfigurationsResponse xmlns:trt="http://www.onvif.org/ver10/media/wsdl">
-<trt:Configurations token="VideoSourceConfig_HQ">
-<tt:Name xmlns:tt="http://www.onvif.org/ver10/schema">VideoSourceConfig_HQ</tt:Name>
-<tt:UseCount>1</tt:UseCount>
-<tt:SourceToken>VideoSource_1</tt:SourceToken>
-<tt:Bounds x="0" y="0" width="960" height="540"/>
-</trt:Configurations>
-<trt:Configurations token="VideoSourceConfig_LQ">
-<tt:Name xmlns:tt="http://www.onvif.org/ver10/schema">VideoSourceConfig_LQ</tt:Name>
-<tt:UseCount>1</tt:UseCount>
-<tt:SourceToken>VideoSource_1</tt:SourceToken>
-<tt:Bounds x="0" y="0" width="960" height="540"/>
-</trt:Configurations>
-</trt:GetVideoSourceConfigurationsResponse>"#;
+pub fn get_video_source_configurations_response(
+    params: &StreamParams,
+    profiles: &[ProfileConfig],
+) -> String {
+    let configurations: String = profiles
+        .iter()
+        .map(|profile| {
+            let suffix = xml_escape(profile.config_suffix());
+            let fields = video_source_configuration_fields(params);
+            format!(
+                r#"<trt:Configurations token="VideoSourceConfig_{suffix}">
+<tt:Name xmlns:tt="http://www.onvif.org/ver10/schema">VideoSourceConfig_{suffix}</tt:Name>
+{fields}</trt:Configurations>
+"#
+            )
+        })
+        .collect();
+    let body_content = format!(
+        r#"<trt:GetVideoSourceConfigurationsResponse xmlns:trt="http://www.onvif.org/ver10/media/wsdl">
+{configurations}</trt:GetVideoSourceConfigurationsResponse>"#
+    );
+
+    SoapResponseBuilder::new().set_body(&body_content).build()
+}
+
+pub fn get_set_video_encoder_configuration_response() -> String {
+    SoapResponseBuilder::new()
+        .add_namespace("trt", "http://www.onvif.org/ver10/media/wsdl")
+        .set_body("<trt:SetVideoEncoderConfigurationResponse/>")
+        .build()
+}
+
+pub fn get_video_encoder_configurations_response(
+    params: &StreamParams,
+    profiles: &[ProfileConfig],
+) -> String {
+    let configurations: String = profiles
+        .iter()
+        .filter(|profile| profile.encoding(params).is_media1())
+        .map(|profile| {
+            let token = xml_escape(&profile.encoder_configuration_token());
+            let fields = video_encoder_configuration_fields(profile, params);
+            format!(
+                r#"<trt:Configurations token="{token}">
+<tt:Name xmlns:tt="http://www.onvif.org/ver10/schema">{token}</tt:Name>
+{fields}</trt:Configurations>
+"#
+            )
+        })
+        .collect();
+    let body_content = format!(
+        r#"<trt:GetVideoEncoderConfigurationsResponse xmlns:trt="http://www.onvif.org/ver10/media/wsdl">
+{configurations}</trt:GetVideoEncoderConfigurationsResponse>"#
+    );
+
+    SoapResponseBuilder::new().set_body(&body_content).build()
+}
+
+/// The settings the encoder configurations could be given (see `EncoderOptions`)
+pub fn get_video_encoder_configuration_options_response(options: &EncoderOptions) -> String {
+    let range = |name: &str, min: u32, max: u32| {
+        format!("<tt:{name}>\n<tt:Min>{min}</tt:Min>\n<tt:Max>{max}</tt:Max>\n</tt:{name}>\n")
+    };
+    let resolutions: String = options
+        .resolutions
+        .iter()
+        .map(|(width, height)| {
+            format!(
+                "<tt:ResolutionsAvailable>\n<tt:Width>{width}</tt:Width>\n<tt:Height>{height}</tt:Height>\n</tt:ResolutionsAvailable>\n"
+            )
+        })
+        .collect();
+    let frame_rates = range("FrameRateRange", 1, options.max_frame_rate);
+    let encoding_intervals = range("EncodingIntervalRange", 1, 1);
+    let bitrates = range("BitrateRange", MIN_BITRATE_KBPS, options.max_bitrate_kbps);
+
+    let jpeg_fields = format!("{resolutions}{frame_rates}{encoding_intervals}");
+    let h264_fields = format!(
+        "{resolutions}{}{frame_rates}{encoding_intervals}{}",
+        range("GovLengthRange", GOV_LENGTH, GOV_LENGTH),
+        options
+            .h264_profiles
+            .iter()
+            .map(|profile| format!(
+                "<tt:H264ProfilesSupported>{}</tt:H264ProfilesSupported>\n",
+                xml_escape(profile)
+            ))
+            .collect::<String>()
+    );
+
+    // Bitrate ranges only exist in the Extension copies of the codec options
+    let (mut codecs, mut extension) = (String::new(), String::new());
+    if options.encodings.contains(&VideoEncoding::Jpeg) {
+        codecs.push_str(&format!("<tt:JPEG>\n{jpeg_fields}</tt:JPEG>\n"));
+        extension.push_str(&format!("<tt:JPEG>\n{jpeg_fields}{bitrates}</tt:JPEG>\n"));
+    }
+    if options.encodings.contains(&VideoEncoding::H264) {
+        codecs.push_str(&format!("<tt:H264>\n{h264_fields}</tt:H264>\n"));
+        extension.push_str(&format!("<tt:H264>\n{h264_fields}{bitrates}</tt:H264>\n"));
+    }
+    let quality = range("QualityRange", QUALITY_RANGE.0, QUALITY_RANGE.1);
+
+    let body_content = format!(
+        r#"<trt:GetVideoEncoderConfigurationOptionsResponse xmlns:trt="http://www.onvif.org/ver10/media/wsdl" xmlns:tt="http://www.onvif.org/ver10/schema">
+<trt:Options>
+{quality}{codecs}<tt:Extension>
+{extension}</tt:Extension>
+</trt:Options>
+</trt:GetVideoEncoderConfigurationOptionsResponse>"#
+    );
+
+    SoapResponseBuilder::new().set_body(&body_content).build()
+}
+
+/// Lists the audio inputs: one when the source carries audio, otherwise none
+pub fn get_audio_sources_response(params: &StreamParams) -> String {
+    let sources = if params.has_audio() {
+        format!(
+            r#"<trt:AudioSources token="{AUDIO_SOURCE_TOKEN}">
+<tt:Channels xmlns:tt="http://www.onvif.org/ver10/schema">1</tt:Channels>
+</trt:AudioSources>
+"#
+        )
+    } else {
+        String::new()
+    };
+    let body_content = format!(
+        r#"<trt:GetAudioSourcesResponse xmlns:trt="http://www.onvif.org/ver10/media/wsdl">
+{sources}</trt:GetAudioSourcesResponse>"#
+    );
+
+    SoapResponseBuilder::new().set_body(&body_content).build()
+}
+
+/// Lists the audio outputs; the transcoder has no backchannel, so there are none
+pub fn get_audio_outputs_response() -> String {
+    let body_content = r#"<trt:GetAudioOutputsResponse xmlns:trt="http://www.onvif.org/ver10/media/wsdl">
+</trt:GetAudioOutputsResponse>"#;
 
     SoapResponseBuilder::new().set_body(body_content).build()
 }
 
-pub fn get_video_encoder_configurations_response() -> String {
-    let body_content = r#"<trt:GetVideoEncoderConfigurationsResponse xmlns:trt="http://www.onvif.org/ver10/media/wsdl">
-<trt:Configurations token="VideoEncoderConfig_HQ">
-<tt:Name xmlns:tt="http://www.onvif.org/ver10/schema">VideoEncoderConfig_HQ</tt:Name>
-<tt:UseCount>1</tt:UseCount>
-<tt:Encoding>H264</tt:Encoding>
-<tt:Resolution>
-<tt:Width>960</tt:Width>
-<tt:Height>540</tt:Height>
-</tt:Resolution>
-<tt:Quality>4</tt:Quality>
-<tt:RateControl>
-<tt:FrameRateLimit>15</tt:FrameRateLimit>
-<tt:EncodingInterval>1</tt:EncodingInterval>
-<tt:BitrateLimit>1500</tt:BitrateLimit>
-</tt:RateControl>
-<tt:H264>
-<tt:GovLength>15</tt:GovLength>
-<tt:H264Profile>Main</tt:H264Profile>
-<tt:Level>4.1</tt:Level>
-</tt:H264>
-<tt:Multicast>
-<tt:Address>
-<tt:Type>IPv4</tt:Type>
-<tt:IPv4Address>0.0.0.0</tt:IPv4Address>
-</tt:Address>
-<tt:Port>0</tt:Port>
-<tt:TTL>1</tt:TTL>
-<tt:AutoStart>false</tt:AutoStart>
-</tt:Multicast>
-<tt:SessionTimeout>PT60S</tt:SessionTimeout>
-</trt:Configurations>
-<trt:Configurations token="VideoEncoderConfig_LQ">
-<tt:Name xmlns:tt="http://www.onvif.org/ver10/schema">VideoEncoderConfig_LQ</tt:Name>
-<tt:UseCount>1</tt:UseCount>
-<tt:Encoding>H264</tt:Encoding>
-<tt:Resolution>
-<tt:Width>960</tt:Width>
-<tt:Height>540</tt:Height>
-</tt:Resolution>
-<tt:Quality>4</tt:Quality>
-<tt:RateControl>
-<tt:FrameRateLimit>15</tt:FrameRateLimit>
-<tt:EncodingInterval>1</tt:EncodingInterval>
-<tt:BitrateLimit>1500</tt:BitrateLimit>
-</tt:RateControl>
-<tt:H264>
-<tt:GovLength>15</tt:GovLength>
-<tt:H264Profile>Main</tt:H264Profile>
-<tt:Level>4.1</tt:Level>
-</tt:H264>
+/// Renders the fields of the audio source configuration after its Name
+fn audio_source_configuration_fields(use_count: usize) -> String {
+    format!(
+        r#"<tt:UseCount>{use_count}</tt:UseCount>
+<tt:SourceToken>{AUDIO_SOURCE_TOKEN}</tt:SourceToken>
+"#
+    )
+}
+
+/// Renders the fields of the audio encoder configuration after its Name
+///
+/// The audio is passed through, so these describe the source track.
+fn audio_encoder_configuration_fields(
+    encoding: AudioEncoding,
+    sample_rate: u32,
+    use_count: usize,
+) -> String {
+    let encoding_name = encoding.as_onvif();
+    let bitrate = encoding.bitrate_kbps();
+    // SampleRate is given in kHz
+    let sample_rate = sample_rate / 1000;
+    format!(
+        r#"<tt:UseCount>{use_count}</tt:UseCount>
+<tt:Encoding>{encoding_name}</tt:Encoding>
+<tt:Bitrate>{bitrate}</tt:Bitrate>
+<tt:SampleRate>{sample_rate}</tt:SampleRate>
 <tt:Multicast>
 <tt:Address>
 <tt:Type>IPv4</tt:Type>
@@ -352,31 +720,77 @@ pub fn get_video_encoder_configurations_response() -> String {
 <tt:AutoStart>false</tt:AutoStart>
 </tt:Multicast>
 <tt:SessionTimeout>PT60S</tt:SessionTimeout>
-</trt:Configurations>
-</trt:GetVideoEncoderConfigurationsResponse>"#;
-
-    SoapResponseBuilder::new().set_body(body_content).build()
+"#
+    )
 }
 
-pub fn get_audio_source_configurations_response() -> String {
-    let body_content = r#"<trt:GetAudioSourceConfigurationsResponse xmlns:trt="http://www.onvif.org/ver10/media/wsdl">
-</trt:GetAudioSourceConfigurationsResponse>"#;
+/// Lists the audio source configuration: one shared by every profile when the
+/// source carries audio, otherwise none
+pub fn get_audio_source_configurations_response(
+    params: &StreamParams,
+    profiles: &[ProfileConfig],
+) -> String {
+    let configurations = if params.has_audio() {
+        let fields = audio_source_configuration_fields(profiles.len());
+        format!(
+            r#"<trt:Configurations token="{AUDIO_SOURCE_CONFIG_TOKEN}">
+<tt:Name xmlns:tt="http://www.onvif.org/ver10/schema">{AUDIO_SOURCE_CONFIG_TOKEN}</tt:Name>
+{fields}</trt:Configurations>
+"#
+        )
+    } else {
+        String::new()
+    };
+    let body_content = format!(
+        r#"<trt:GetAudioSourceConfigurationsResponse xmlns:trt="http://www.onvif.org/ver10/media/wsdl">
+{configurations}</trt:GetAudioSourceConfigurationsResponse>"#
+    );
 
-    SoapResponseBuilder::new().set_body(body_content).build()
+    SoapResponseBuilder::new().set_body(&body_content).build()
 }
 
-pub fn get_audio_encoder_configurations_response() -> String {
-    let body_content = r#"<trt:GetAudioEncoderConfigurationsResponse xmlns:trt="http://www.onvif.org/ver10/media/wsdl">
-</trt:GetAudioEncoderConfigurationsResponse>"#;
+/// Lists the audio encoder configuration when the source's audio codec is one
+/// ONVIF can describe (AAC or G.711/G.726), otherwise none
+pub fn get_audio_encoder_configurations_response(
+    params: &StreamParams,
+    profiles: &[ProfileConfig],
+) -> String {
+    let configurations = match (params.audio_encoding(), params.audio_sample_rate()) {
+        (Some(encoding), Some(sample_rate)) => {
+            let fields = audio_encoder_configuration_fields(encoding, sample_rate, profiles.len());
+            format!(
+                r#"<trt:Configurations token="{AUDIO_ENCODER_CONFIG_TOKEN}">
+<tt:Name xmlns:tt="http://www.onvif.org/ver10/schema">{AUDIO_ENCODER_CONFIG_TOKEN}</tt:Name>
+{fields}</trt:Configurations>
+"#
+            )
+        }
+        _ => String::new(),
+    };
+    let body_content = format!(
+        r#"<trt:GetAudioEncoderConfigurationsResponse xmlns:trt="http://www.onvif.org/ver10/media/wsdl">
+{configurations}</trt:GetAudioEncoderConfigurationsResponse>"#
+    );
+
+    SoapResponseBuilder::new().set_body(&body_content).build()
+}
 
-    SoapResponseBuilder::new().set_body(body_content).build()
+/// `WWW-Authenticate` value offering HTTP Digest authentication with a fresh nonce
+pub fn get_digest_challenge(ids: &dyn IdGenerator) -> String {
+    let nonce = ids.new_nonce();
+    format!(r#"Digest realm="ONVIF Camera", nonce="{nonce}", qop="auth", stale=false"#)
 }
 
-pub fn get_auth_required_response() -> String {
-    // Generate a fresh nonce for each authentication challenge
-    let nonce = uuid::Uuid::new_v4().to_string().replace('-', "");
+/// Body of the 401 sent with a Digest challenge
+///
+/// SOAP requests get a `ter:NotAuthorized` fault; anything else (e.g. a plain
+/// HTTP GET) gets a short text body.
+pub fn get_auth_required_response(is_soap: bool) -> String {
+    if !is_soap {
+        return "Authentication required\n".to_string();
+    }
 
-    let soap_response = SoapResponseBuilder::new()
+    SoapResponseBuilder::new()
         .set_body(
             r#"<soap:Fault>
 <soap:Code>
@@ -390,17 +804,12 @@ pub fn get_auth_required_response() -> String {
 </soap:Reason>
 </soap:Fault>"#,
         )
-        .build();
-
-    format!(
-        "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Digest realm=\"ONVIF Camera\", nonce=\"{nonce}\", qop=\"auth\", stale=false\r\nContent-Type: application/soap+xml; charset=utf-8\r\nContent-Length: {}\r\n\r\n{}",
-        soap_response.len(),
-        soap_response
-    )
+        .build()
 }
 
+/// Body of the 401 sent when a WS-Security UsernameToken is rejected
 pub fn get_ws_security_auth_fault() -> String {
-    let soap_response = SoapResponseBuilder::new()
+    SoapResponseBuilder::new()
         .add_namespace("ter", "http://www.onvif.org/ver10/error")
         .set_body(
             r#"<soap:Fault>
@@ -418,24 +827,65 @@ pub fn get_ws_security_auth_fault() -> String {
 </soap:Detail>
 </soap:Fault>"#,
         )
-        .build();
-
-    format!(
-        "HTTP/1.1 401 Unauthorized\r\nContent-Type: application/soap+xml; charset=utf-8\r\nContent-Length: {}\r\n\r\n{}",
-        soap_response.len(),
-        soap_response
-    )
+        .build()
 }
 
 pub fn get_default_response() -> String {
     "ONVIF Camera\n".to_string()
 }
 
-pub fn get_snapshot_uri_response(container_ip: &str, onvif_port: &str) -> String {
+/// Renders an uptime as an xs:duration (e.g. `P1DT2H3M4S`)
+pub fn format_uptime(uptime: Duration) -> String {
+    let total = uptime.as_secs();
+    let days = total / 86_400;
+    let hours = (total % 86_400) / 3_600;
+    let minutes = (total % 3_600) / 60;
+    let seconds = total % 60;
+    format!("P{days}DT{hours}H{minutes}M{seconds}S")
+}
+
+pub fn get_info_response(info: &DeviceInformation, uptime: Duration) -> String {
+    serde_json::json!({
+        "device_name": info.model,
+        "manufacturer": info.manufacturer,
+        "firmware_version": info.firmware_version,
+        "uptime": format_uptime(uptime),
+        "uptime_seconds": uptime.as_secs(),
+    })
+    .to_string()
+}
+
+/// JSON body of the GET /health liveness probe
+pub fn get_health_response(
+    uptime: Duration,
+    connections: u64,
+    ws_discovery: &str,
+    last_error: Option<&str>,
+) -> String {
+    serde_json::json!({
+        "status": "ok",
+        "uptime_seconds": uptime.as_secs(),
+        "connections": connections,
+        "ws_discovery": ws_discovery,
+        "last_error": last_error,
+    })
+    .to_string()
+}
+
+pub fn get_snapshot_uri_response(
+    container_ip: &str,
+    onvif_port: &str,
+    tls: bool,
+    profile_token: &str,
+) -> String {
+    let uri = xml_escape(&format!(
+        "{}://{container_ip}:{onvif_port}/snapshot.jpg?profile={profile_token}",
+        url_scheme(tls)
+    ));
     let body_content = format!(
         r#"<trt:GetSnapshotUriResponse xmlns:trt="http://www.onvif.org/ver10/media/wsdl">
 <trt:MediaUri>
-<tt:Uri xmlns:tt="http://www.onvif.org/ver10/schema">http://{container_ip}:{onvif_port}/snapshot.jpg</tt:Uri>
+<tt:Uri xmlns:tt="http://www.onvif.org/ver10/schema">{uri}</tt:Uri>
 </trt:MediaUri>
 </trt:GetSnapshotUriResponse>"#
     );
@@ -443,17 +893,21 @@ pub fn get_snapshot_uri_response(container_ip: &str, onvif_port: &str) -> String
     SoapResponseBuilder::new().set_body(&body_content).build()
 }
 
-pub fn get_system_date_time_response() -> String {
-    // Get current UTC time
-    let now = chrono::Utc::now();
+pub fn get_system_date_time_response(settings: &DateTimeSettings) -> String {
+    // Current UTC time, shifted by any manually set offset
+    let now = settings.now();
+    let (local, daylight_savings) = settings.local_time(now);
+    let date_time_type = &settings.date_time_type;
+    // POSIX TZ strings may contain angle brackets (e.g. "<+03>-3")
+    let time_zone = xml_escape(&settings.time_zone);
 
     let body_content = format!(
         r#"<tds:GetSystemDateAndTimeResponse>
 <tds:SystemDateAndTime>
-<tt:DateTimeType>NTP</tt:DateTimeType>
-<tt:DaylightSavings>false</tt:DaylightSavings>
+<tt:DateTimeType>{date_time_type}</tt:DateTimeType>
+<tt:DaylightSavings>{daylight_savings}</tt:DaylightSavings>
 <tt:TimeZone>
-<tt:TZ>UTC</tt:TZ>
+<tt:TZ>{time_zone}</tt:TZ>
 </tt:TimeZone>
 <tt:UTCDateTime>
 <tt:Time>
@@ -467,6 +921,18 @@ pub fn get_system_date_time_response() -> String {
 <tt:Day>{}</tt:Day>
 </tt:Date>
 </tt:UTCDateTime>
+<tt:LocalDateTime>
+<tt:Time>
+<tt:Hour>{}</tt:Hour>
+<tt:Minute>{}</tt:Minute>
+<tt:Second>{}</tt:Second>
+</tt:Time>
+<tt:Date>
+<tt:Year>{}</tt:Year>
+<tt:Month>{}</tt:Month>
+<tt:Day>{}</tt:Day>
+</tt:Date>
+</tt:LocalDateTime>
 </tds:SystemDateAndTime>
 </tds:GetSystemDateAndTimeResponse>"#,
         now.hour(),
@@ -474,7 +940,13 @@ pub fn get_system_date_time_response() -> String {
         now.second(),
         now.year(),
         now.month(),
-        now.day()
+        now.day(),
+        local.hour(),
+        local.minute(),
+        local.second(),
+        local.year(),
+        local.month(),
+        local.day()
     );
 
     SoapResponseBuilder::new()
@@ -484,7 +956,45 @@ pub fn get_system_date_time_response() -> String {
         .build()
 }
 
+pub fn get_set_system_date_time_response() -> String {
+    SoapResponseBuilder::new()
+        .add_namespace("tds", "http://www.onvif.org/ver10/device/wsdl")
+        .set_body("<tds:SetSystemDateAndTimeResponse/>")
+        .build()
+}
+
+/// Builds a SOAP fault with a code, nested `ter:` subcodes and a reason
+///
+/// `code` is `Sender` or `Receiver`; subcodes are given outermost first,
+/// e.g. `&["ter:InvalidArgVal", "ter:NoProfile"]`.
+pub fn get_soap_fault_response(code: &str, subcodes: &[&str], reason: &str) -> String {
+    // Reasons can quote request values, e.g. an unknown profile token
+    let reason = xml_escape(reason);
+    let mut subcode_content = String::new();
+    for subcode in subcodes.iter().rev() {
+        subcode_content = format!(
+            "\n<soap:Subcode>\n<soap:Value>{subcode}</soap:Value>{subcode_content}\n</soap:Subcode>"
+        );
+    }
+    let body_content = format!(
+        r#"<soap:Fault>
+<soap:Code>
+<soap:Value>soap:{code}</soap:Value>{subcode_content}
+</soap:Code>
+<soap:Reason>
+<soap:Text xml:lang="en">{reason}</soap:Text>
+</soap:Reason>
+</soap:Fault>"#
+    );
+
+    SoapResponseBuilder::new()
+        .add_namespace("ter", "http://www.onvif.org/ver10/error")
+        .set_body(&body_content)
+        .build()
+}
+
 pub fn get_unsupported_endpoint_response(endpoint: &str) -> String {
+    let endpoint = xml_escape(endpoint);
     let body_content = format!(
         r#"<soap:Fault>
 <soap:Code>
@@ -506,3 +1016,372 @@ pub fn get_unsupported_endpoint_response(endpoint: &str) -> String {
 
     SoapResponseBuilder::new().set_body(&body_content).build()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::id_generator::SeededIdGenerator;
+    use crate::onvif::profiles::default_profiles;
+    use crate::onvif::soap;
+
+    #[test]
+    fn test_profiles_use_probed_resolution() {
+        let params = StreamParams {
+            width: 1920,
+            height: 1080,
+            codec: "h264".to_string(),
+            frame_rate: 25.0,
+            audio_codec: None,
+            audio_sample_rate: None,
+        };
+
+        let profiles = get_profiles_response(&params, &default_profiles("", None));
+        assert!(profiles.contains("<tt:Width>1920</tt:Width>"));
+        assert!(profiles.contains("<tt:Height>1080</tt:Height>"));
+        assert!(profiles.contains(r#"width="1920" height="1080""#));
+        assert!(profiles.contains("<tt:FrameRateLimit>25</tt:FrameRateLimit>"));
+        assert!(!profiles.contains("960"));
+
+        let encoders =
+            get_video_encoder_configurations_response(&params, &default_profiles("", None));
+        assert!(encoders.contains("<tt:Width>1920</tt:Width>"));
+        assert!(encoders.contains("<tt:FrameRateLimit>25</tt:FrameRateLimit>"));
+
+        let sources =
+            get_video_source_configurations_response(&params, &default_profiles("", None));
+        assert!(sources.contains(r#"width="1920" height="1080""#));
+    }
+
+    #[test]
+    fn test_video_source_follows_probe() {
+        let params = StreamParams {
+            width: 1920,
+            height: 1080,
+            frame_rate: 30.0,
+            ..StreamParams::default()
+        };
+        let sources = get_video_sources_response(&params);
+        assert!(sources.contains(
+            "<tt:Framerate xmlns:tt=\"http://www.onvif.org/ver10/schema\">30</tt:Framerate>"
+        ));
+        assert!(sources.contains("<tt:Width>1920</tt:Width>\n<tt:Height>1080</tt:Height>"));
+
+        // NTSC rates aren't rounded
+        let ntsc = StreamParams {
+            frame_rate: 30000.0 / 1001.0,
+            ..params
+        };
+        assert!(get_video_sources_response(&ntsc).contains(">29.97</tt:Framerate>"));
+
+        // Without a probe result the defaults are advertised
+        let defaults = get_video_sources_response(&StreamParams::default());
+        assert!(defaults.contains(">15</tt:Framerate>"));
+        assert!(defaults.contains("<tt:Width>960</tt:Width>\n<tt:Height>540</tt:Height>"));
+    }
+
+    #[test]
+    fn test_profiles_generated_from_configured_list() {
+        let params = StreamParams::default();
+        let profiles = [
+            ProfileConfig::parse("token=main,name=Main,resolution=1920x1080,bitrate=4000").unwrap(),
+            ProfileConfig::parse("token=sub,resolution=640x360,framerate=10").unwrap(),
+            ProfileConfig::parse("token=mjpeg,codec=JPEG").unwrap(),
+            ProfileConfig::parse("token=hevc,codec=H265").unwrap(),
+        ];
+
+        let response = get_profiles_response(&params, &profiles);
+        assert_eq!(response.matches("<trt:Profiles ").count(), 4);
+        assert!(!response.contains("VideoEncoderConfig_hevc"));
+        assert!(response.contains(r#"<trt:Profiles token="main" fixed="true">"#));
+        assert!(response.contains(">Main</tt:Name>"));
+        assert!(
+            response.contains(r#"<tt:VideoEncoderConfiguration token="VideoEncoderConfig_sub">"#)
+        );
+        assert!(response.contains("<tt:BitrateLimit>4000</tt:BitrateLimit>"));
+        assert!(response.contains("<tt:Width>640</tt:Width>"));
+        assert!(response.contains("<tt:FrameRateLimit>10</tt:FrameRateLimit>"));
+        assert!(response.contains("<tt:Encoding>JPEG</tt:Encoding>"));
+        assert!(!response.contains("HQProfile"));
+
+        let encoders = get_video_encoder_configurations_response(&params, &profiles[..1]);
+        assert_eq!(encoders.matches("<trt:Configurations ").count(), 1);
+        assert!(encoders.contains("<tt:Width>1920</tt:Width>"));
+
+        let sources = get_video_source_configurations_response(&params, &profiles);
+        assert!(sources.contains(r#"token="VideoSourceConfig_mjpeg""#));
+    }
+
+    #[test]
+    fn test_service_capabilities_report_the_profile_count() {
+        let response = get_service_capabilities_response(4);
+        assert!(response.contains("<tt:MaximumNumberOfProfiles>4</tt:MaximumNumberOfProfiles>"));
+    }
+
+    #[test]
+    fn test_default_profiles_keep_hq_lq_output() {
+        let response = get_profiles_response(&StreamParams::default(), &default_profiles("", None));
+        assert!(response.contains(r#"<trt:Profiles token="HQProfile" fixed="true">"#));
+        assert!(response.contains(r#"<trt:Profiles token="LQProfile" fixed="true">"#));
+        assert!(response.contains(r#"token="VideoEncoderConfig_LQ""#));
+        assert!(response.contains("<tt:H264Profile>Baseline</tt:H264Profile>"));
+        assert!(response.contains("<tt:Level>4.1</tt:Level>"));
+        assert!(response.contains("<tt:BitrateLimit>1500</tt:BitrateLimit>"));
+    }
+
+    #[test]
+    fn test_profiles_default_resolution() {
+        let profiles = get_profiles_response(&StreamParams::default(), &default_profiles("", None));
+        assert!(profiles.contains("<tt:Width>960</tt:Width>"));
+        assert!(profiles.contains("<tt:Height>540</tt:Height>"));
+        assert!(profiles.contains("<tt:FrameRateLimit>15</tt:FrameRateLimit>"));
+    }
+
+    #[test]
+    fn test_encoder_configuration_follows_source_codec() {
+        let with_codec = |codec: &str| StreamParams {
+            codec: codec.to_string(),
+            ..StreamParams::default()
+        };
+
+        for body in [
+            get_profiles_response(&with_codec("h264"), &default_profiles("", None)),
+            get_video_encoder_configurations_response(
+                &with_codec("h264"),
+                &default_profiles("", None),
+            ),
+        ] {
+            assert!(body.contains("<tt:Encoding>H264</tt:Encoding>"));
+            assert!(body.contains("<tt:H264Profile>Main</tt:H264Profile>"));
+            assert!(!body.contains("<tt:H265>"));
+        }
+
+        // Media1 has no H265, so an HEVC source's profiles have no encoder configuration
+        let profiles = get_profiles_response(&with_codec("hevc"), &default_profiles("", None));
+        assert_eq!(profiles.matches("<trt:Profiles ").count(), 2);
+        assert!(!profiles.contains("VideoEncoderConfiguration"));
+        assert!(!profiles.contains("H265"));
+        let encoders = get_video_encoder_configurations_response(
+            &with_codec("hevc"),
+            &default_profiles("", None),
+        );
+        assert!(!encoders.contains("<trt:Configurations "));
+
+        for body in [
+            get_profiles_response(&with_codec("mjpeg"), &default_profiles("", None)),
+            get_video_encoder_configurations_response(
+                &with_codec("mjpeg"),
+                &default_profiles("", None),
+            ),
+        ] {
+            assert!(body.contains("<tt:Encoding>JPEG</tt:Encoding>"));
+            assert!(!body.contains("<tt:H264>"));
+            assert!(!body.contains("<tt:H265>"));
+        }
+    }
+
+    #[test]
+    fn test_interpolated_values_are_escaped() {
+        let response = get_stream_uri_response("rtsp://cam/live?user=a&pass=<b>");
+        assert!(response.contains(">rtsp://cam/live?user=a&amp;pass=&lt;b&gt;</tt:Uri>"));
+
+        let response = get_device_info_response(&DeviceInformation {
+            manufacturer: "A&B".to_string(),
+            model: "R&D \"Lab\" <1>".to_string(),
+            firmware_version: "1.0".to_string(),
+            serial_number: "EMU-R&D \"L".to_string(),
+            hardware_id: "hw".to_string(),
+        });
+        assert!(response.contains("<tds:Manufacturer>A&amp;B</tds:Manufacturer>"));
+        assert!(response.contains("<tds:Model>R&amp;D &quot;Lab&quot; &lt;1&gt;</tds:Model>"));
+        assert!(response.contains("<tds:SerialNumber>EMU-R&amp;D &quot;L</tds:SerialNumber>"));
+
+        let response = get_capabilities_response("cam&1", "80", false, &[Service::Device]);
+        assert!(response.contains("<tt:XAddr>http://cam&amp;1:80/onvif/device_service</tt:XAddr>"));
+        assert!(!response.contains("cam&1"));
+    }
+
+    #[test]
+    fn test_profile_and_fault_values_are_escaped() {
+        let profiles =
+            vec![ProfileConfig::parse("token=front,name=Front <\"door\"> & gate").unwrap()];
+        let params = StreamParams::default();
+
+        let response = get_profiles_response(&params, &profiles);
+        assert!(response.contains(">Front &lt;&quot;door&quot;&gt; &amp; gate</tt:Name>"));
+
+        let response = get_snapshot_uri_response("10.0.0.2", "8080", false, "a&b");
+        assert!(response.contains(">http://10.0.0.2:8080/snapshot.jpg?profile=a&amp;b</tt:Uri>"));
+
+        let response =
+            get_soap_fault_response("Sender", &["ter:InvalidArgVal"], "Bad '<x>' & more");
+        assert!(response.contains(">Bad &apos;&lt;x&gt;&apos; &amp; more</soap:Text>"));
+
+        let response = get_unsupported_endpoint_response("Get<Evil>");
+        assert!(response.contains("<ter:Operation>Get&lt;Evil&gt;</ter:Operation>"));
+    }
+
+    #[test]
+    fn test_media_xaddr_is_the_media_service() {
+        let services = [Service::Device, Service::Media];
+        let capabilities = get_capabilities_response("10.0.0.2", "8080", false, &services);
+        let media = soap::find_element_text(&capabilities, "Media").unwrap();
+        let xaddr = soap::find_element_text(media, "XAddr").unwrap();
+        assert!(xaddr.ends_with("/onvif/media_service"), "{xaddr}");
+        let device = soap::find_element_text(&capabilities, "Device").unwrap();
+        assert!(soap::find_element_text(device, "XAddr")
+            .unwrap()
+            .ends_with("/onvif/device_service"));
+
+        let services = get_services_response("10.0.0.2", "8080", false, &services);
+        assert!(services.contains(
+            "<tds:Namespace>http://www.onvif.org/ver10/media/wsdl</tds:Namespace>\n<tds:XAddr>http://10.0.0.2:8080/onvif/media_service</tds:XAddr>"
+        ));
+    }
+
+    #[test]
+    fn test_capabilities_follow_schema_order() {
+        let services = [Service::Device, Service::Media, Service::Events];
+        let capabilities = get_capabilities_response("10.0.0.2", "8080", false, &services);
+        let device = capabilities.find("<tt:Device").unwrap();
+        let events = capabilities.find("<tt:Events").unwrap();
+        let media = capabilities.find("<tt:Media").unwrap();
+        assert!(device < events && events < media, "{capabilities}");
+    }
+
+    #[test]
+    fn test_discovery_resolve_is_reported() {
+        let capabilities = get_capabilities_response("10.0.0.2", "8080", false, &[Service::Device]);
+        assert!(capabilities.contains(">true</tt:DiscoveryResolve>"));
+
+        let services = get_services_response("10.0.0.2", "8080", true, &[Service::Device]);
+        assert!(services.contains("<tds:DiscoveryResolve>true</tds:DiscoveryResolve>"));
+
+        let device = get_device_service_capabilities_response(false);
+        assert!(device.contains(r#"<tds:System DiscoveryResolve="true""#));
+    }
+
+    #[test]
+    fn test_hostname_is_device_name() {
+        let response = get_hostname_response("Front-Door");
+        assert!(response.contains("<tds:HostnameInformation>"));
+        assert!(response.contains(">false</tt:FromDHCP>"));
+        assert!(response.contains(">Front-Door</tt:Name>"));
+        assert!(response.contains("</tds:GetHostnameResponse>"));
+    }
+
+    #[test]
+    fn test_dns_is_static_and_empty() {
+        let response = get_dns_response();
+        assert!(response.contains("<tds:DNSInformation>"));
+        assert!(response.contains(">false</tt:FromDHCP>\n</tds:DNSInformation>"));
+        assert!(!response.contains("<tt:DNSManual"));
+    }
+
+    #[test]
+    fn test_auth_required_uses_generated_nonce() {
+        let expected = SeededIdGenerator::new(1).new_nonce();
+        let challenge = get_digest_challenge(&SeededIdGenerator::new(1));
+        assert!(challenge.starts_with("Digest "));
+        assert!(challenge.contains(&format!(r#"nonce="{expected}""#)));
+    }
+
+    #[test]
+    fn test_audio_sources_follow_probe() {
+        let with_audio = StreamParams {
+            audio_codec: Some("aac".to_string()),
+            ..StreamParams::default()
+        };
+        let present = get_audio_sources_response(&with_audio);
+        assert_eq!(present.matches("<trt:AudioSources ").count(), 1);
+        assert!(present.contains(r#"token="AudioSource_1""#));
+
+        let absent = get_audio_sources_response(&StreamParams::default());
+        assert!(absent.contains("GetAudioSourcesResponse"));
+        assert!(!absent.contains("<trt:AudioSources "));
+
+        assert!(!get_audio_outputs_response().contains("<trt:AudioOutputs"));
+    }
+
+    #[test]
+    fn test_profiles_reference_the_audio_configurations() {
+        let profiles = default_profiles("", None);
+        let with_aac = StreamParams {
+            audio_codec: Some("aac".to_string()),
+            ..StreamParams::default()
+        };
+
+        let response = get_profiles_response(&with_aac, &profiles);
+        assert_eq!(
+            response
+                .matches(r#"<tt:AudioSourceConfiguration token="AudioSourceConfig">"#)
+                .count(),
+            2
+        );
+        assert_eq!(
+            response
+                .matches(r#"<tt:AudioEncoderConfiguration token="AudioEncoderConfig">"#)
+                .count(),
+            2
+        );
+        assert!(response.contains("<tt:Encoding>AAC</tt:Encoding>"));
+        // The same tokens as the standalone audio configuration lists
+        let encoders = get_audio_encoder_configurations_response(&with_aac, &profiles);
+        assert!(encoders.contains(r#"token="AudioEncoderConfig""#));
+        let single = get_profile_response(&with_aac, &profiles[0], profiles.len());
+        assert!(single.contains(r#"<tt:AudioSourceConfiguration token="AudioSourceConfig">"#));
+
+        let silent = get_profiles_response(&StreamParams::default(), &profiles);
+        assert!(!silent.contains("AudioSourceConfiguration"));
+        assert!(!silent.contains("AudioEncoderConfiguration"));
+    }
+
+    #[test]
+    fn test_audio_configurations_describe_an_aac_track() {
+        let profiles = default_profiles("", None);
+        let with_aac = StreamParams {
+            audio_codec: Some("aac".to_string()),
+            audio_sample_rate: Some(48000),
+            ..StreamParams::default()
+        };
+
+        let sources = get_audio_source_configurations_response(&with_aac, &profiles);
+        assert!(sources.contains(r#"<trt:Configurations token="AudioSourceConfig">"#));
+        assert!(sources.contains("<tt:UseCount>2</tt:UseCount>"));
+        assert!(sources.contains("<tt:SourceToken>AudioSource_1</tt:SourceToken>"));
+
+        let encoders = get_audio_encoder_configurations_response(&with_aac, &profiles);
+        assert!(encoders.contains(r#"<trt:Configurations token="AudioEncoderConfig">"#));
+        assert!(encoders.contains("<tt:Encoding>AAC</tt:Encoding>"));
+        assert!(encoders.contains("<tt:SampleRate>48</tt:SampleRate>"));
+
+        // A source without audio keeps both lists empty
+        let silent = StreamParams::default();
+        let sources = get_audio_source_configurations_response(&silent, &profiles);
+        assert!(!sources.contains("<trt:Configurations"));
+        let encoders = get_audio_encoder_configurations_response(&silent, &profiles);
+        assert!(!encoders.contains("<trt:Configurations"));
+    }
+
+    #[test]
+    fn test_format_uptime() {
+        assert_eq!(format_uptime(Duration::from_secs(0)), "P0DT0H0M0S");
+        assert_eq!(format_uptime(Duration::from_secs(93_784)), "P1DT2H3M4S");
+    }
+
+    #[test]
+    fn test_get_info_response_reports_uptime() {
+        let info = DeviceInformation {
+            manufacturer: "Acme".to_string(),
+            model: "Cam".to_string(),
+            firmware_version: "2.0".to_string(),
+            serial_number: "SN1".to_string(),
+            hardware_id: "hw".to_string(),
+        };
+        let body = get_info_response(&info, Duration::from_secs(61));
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["device_name"], "Cam");
+        assert_eq!(json["manufacturer"], "Acme");
+        assert_eq!(json["uptime"], "P0DT0H1M1S");
+        assert_eq!(json["uptime_seconds"], 61);
+    }
+}
@@ -0,0 +1,508 @@
+// Snapshots
+// JPEG frames grabbed from the RTSP source with ffmpeg for GET /snapshot.jpg.
+// Captures are cached briefly so dashboards polling from several clients share
+// one ffmpeg run instead of each opening the camera stream.
+
+use crate::config::Config;
+use crate::ffmpeg::{spawn_error_message, CommandRunner, SystemCommandRunner};
+use crate::rtsp_health::RetryPolicy;
+use log::info;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Why a snapshot couldn't be produced
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// ffmpeg couldn't be started; holds the reason, naming ffmpeg
+    Unavailable(String),
+    /// ffmpeg ran but didn't produce an image
+    Failed(String),
+    /// ffmpeg was killed after running longer than the snapshot timeout
+    TimedOut(Duration),
+}
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::Unavailable(e) => write!(f, "{e}"),
+            SnapshotError::Failed(e) => write!(f, "ffmpeg failed to generate snapshot: {e}"),
+            SnapshotError::TimedOut(timeout) => {
+                write!(f, "ffmpeg didn't produce a snapshot within {timeout:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+/// JPEG quality and size limits applied when capturing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotOptions {
+    /// ffmpeg binary to run (`--ffmpeg-path`)
+    pub ffmpeg_path: String,
+    /// ffmpeg `-q:v` value, 2 (best) to 31 (smallest)
+    pub quality: u8,
+    /// Frames wider than this are scaled down, keeping the aspect ratio
+    pub max_width: Option<u32>,
+    /// Frames taller than this are scaled down, keeping the aspect ratio
+    pub max_height: Option<u32>,
+    /// ffmpeg is killed if the whole capture takes longer than this
+    pub timeout: Duration,
+    /// Retries when ffmpeg can't get a frame from the source
+    pub retry: RetryPolicy,
+}
+
+impl Default for SnapshotOptions {
+    fn default() -> Self {
+        Self {
+            ffmpeg_path: "ffmpeg".to_string(),
+            quality: 2,
+            max_width: None,
+            max_height: None,
+            timeout: Duration::from_secs(20),
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+impl SnapshotOptions {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            ffmpeg_path: config.ffmpeg_path.clone(),
+            quality: config.snapshot_quality,
+            max_width: config.snapshot_max_width,
+            max_height: config.snapshot_max_height,
+            timeout: Duration::from_secs(config.snapshot_timeout),
+            retry: RetryPolicy::from_config(config),
+        }
+    }
+
+    /// Also bounds the frame to a profile's `resolution`, so each profile's
+    /// snapshot matches the size it advertises
+    pub fn for_profile_resolution(self, resolution: Option<(u32, u32)>) -> Self {
+        let Some((width, height)) = resolution else {
+            return self;
+        };
+        Self {
+            max_width: Some(self.max_width.map_or(width, |max| max.min(width))),
+            max_height: Some(self.max_height.map_or(height, |max| max.min(height))),
+            ..self
+        }
+    }
+
+    /// The `scale` filter bounding the frame size, if any limit is set
+    ///
+    /// `min()` keeps smaller frames from being upscaled; with both limits the
+    /// frame is shrunk until it fits the box.
+    pub fn scale_filter(&self) -> Option<String> {
+        match (self.max_width, self.max_height) {
+            (None, None) => None,
+            (Some(width), None) => Some(format!("scale='min(iw,{width})':-1")),
+            (None, Some(height)) => Some(format!("scale=-1:'min(ih,{height})'")),
+            (Some(width), Some(height)) => Some(format!(
+                "scale='min(iw,{width})':'min(ih,{height})':force_original_aspect_ratio=decrease"
+            )),
+        }
+    }
+}
+
+/// Builds the ffmpeg arguments capturing one JPEG frame from `rtsp_stream_url` to stdout
+fn snapshot_ffmpeg_args(rtsp_stream_url: &str, options: &SnapshotOptions) -> Vec<String> {
+    let mut args: Vec<String> = ["-y", "-i", rtsp_stream_url, "-vframes", "1"]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+    if let Some(filter) = options.scale_filter() {
+        args.extend(["-vf".to_string(), filter]);
+    }
+    args.extend(
+        [
+            "-q:v",
+            &options.quality.to_string(),
+            "-f",
+            "image2",
+            "-update",
+            "1",
+            "-", // Output to stdout
+        ]
+        .iter()
+        .map(|arg| arg.to_string()),
+    );
+    args
+}
+
+/// Captures a single JPEG frame from `rtsp_stream_url`, retrying while the source is down
+pub fn capture_snapshot_from_rtsp(
+    runner: &impl CommandRunner,
+    rtsp_stream_url: &str,
+    options: &SnapshotOptions,
+) -> Result<Vec<u8>, SnapshotError> {
+    capture_with_retries(&options.retry, || {
+        capture_snapshot_once(runner, rtsp_stream_url, options)
+    })
+}
+
+/// Retries `capture` while ffmpeg fails to get a frame
+///
+/// A missing ffmpeg won't fix itself and a timed out capture has already used
+/// the whole timeout, so only `Failed` is retried.
+fn capture_with_retries<F>(retry: &RetryPolicy, capture: F) -> Result<Vec<u8>, SnapshotError>
+where
+    F: FnMut() -> Result<Vec<u8>, SnapshotError>,
+{
+    retry.run(capture, |e| matches!(e, SnapshotError::Failed(_)))
+}
+
+fn capture_snapshot_once(
+    runner: &impl CommandRunner,
+    rtsp_stream_url: &str,
+    options: &SnapshotOptions,
+) -> Result<Vec<u8>, SnapshotError> {
+    info!("Generating snapshot from RTSP stream: {rtsp_stream_url}");
+
+    let args = snapshot_ffmpeg_args(rtsp_stream_url, options);
+    let output = runner
+        .run(&options.ffmpeg_path, &args, options.timeout)
+        .map_err(|e| match e.kind() {
+            ErrorKind::TimedOut => SnapshotError::TimedOut(options.timeout),
+            _ => {
+                SnapshotError::Unavailable(spawn_error_message("ffmpeg", &options.ffmpeg_path, &e))
+            }
+        })?;
+
+    if !output.status.success() {
+        return Err(SnapshotError::Failed(
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ));
+    }
+    info!(
+        "Snapshot generated successfully ({} bytes)",
+        output.stdout.len()
+    );
+    Ok(output.stdout)
+}
+
+/// How long a failed capture is reported to new requests before ffmpeg is tried again
+///
+/// Keeps clients polling an unreachable camera from starting an ffmpeg run each.
+const FAILED_CAPTURE_TTL: Duration = Duration::from_secs(2);
+
+/// Recently captured snapshots, one per source URL and size
+///
+/// Only one capture runs per source at a time, without holding any lock:
+/// requests arriving during it wait for it and are then served its result,
+/// image or error, so concurrent requests coalesce into a single ffmpeg run.
+#[derive(Debug, Default)]
+pub struct SnapshotCache {
+    sources: Mutex<HashMap<String, Arc<SnapshotSlot>>>,
+}
+
+/// The latest capture of one source, and whether another is running
+#[derive(Debug, Default)]
+struct SnapshotSlot {
+    state: Mutex<SlotState>,
+    captured: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct SlotState {
+    latest: Option<CachedSnapshot>,
+    capturing: bool,
+}
+
+#[derive(Debug)]
+struct CachedSnapshot {
+    result: Result<Arc<Vec<u8>>, SnapshotError>,
+    at: Instant,
+}
+
+impl CachedSnapshot {
+    fn is_fresh(&self, max_age: Duration) -> bool {
+        let ttl = match self.result {
+            Ok(_) => max_age,
+            Err(_) => max_age.min(FAILED_CAPTURE_TTL),
+        };
+        self.at.elapsed() < ttl
+    }
+}
+
+/// Ends a capture, waking its waiters even if the capture panicked
+struct CaptureGuard<'a> {
+    slot: &'a SnapshotSlot,
+    result: Option<Result<Arc<Vec<u8>>, SnapshotError>>,
+}
+
+impl Drop for CaptureGuard<'_> {
+    fn drop(&mut self) {
+        let mut state = self.slot.lock();
+        state.capturing = false;
+        if let Some(result) = self.result.take() {
+            state.latest = Some(CachedSnapshot {
+                result,
+                at: Instant::now(),
+            });
+        }
+        self.slot.captured.notify_all();
+    }
+}
+
+impl SnapshotSlot {
+    fn lock(&self) -> MutexGuard<'_, SlotState> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl SnapshotCache {
+    pub fn get_or_capture(
+        &self,
+        rtsp_url: &str,
+        max_age: Duration,
+        options: &SnapshotOptions,
+    ) -> Result<Arc<Vec<u8>>, SnapshotError> {
+        let key = match options.scale_filter() {
+            Some(filter) => format!("{rtsp_url} {filter}"),
+            None => rtsp_url.to_string(),
+        };
+        self.get_or_capture_with(&key, max_age, |_| {
+            capture_snapshot_from_rtsp(&SystemCommandRunner, rtsp_url, options)
+        })
+    }
+
+    /// Returns the snapshot cached under `key`, running `capture` if it's missing or stale
+    fn get_or_capture_with<F>(
+        &self,
+        key: &str,
+        max_age: Duration,
+        capture: F,
+    ) -> Result<Arc<Vec<u8>>, SnapshotError>
+    where
+        F: FnOnce(&str) -> Result<Vec<u8>, SnapshotError>,
+    {
+        let slot = {
+            let mut sources = match self.sources.lock() {
+                Ok(sources) => sources,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            sources.entry(key.to_string()).or_default().clone()
+        };
+
+        let mut state = slot.lock();
+        if state.capturing {
+            // Share the result of the capture already running, however old
+            // the request would accept
+            while state.capturing {
+                state = match slot.captured.wait(state) {
+                    Ok(state) => state,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+            }
+            if let Some(latest) = state.latest.as_ref() {
+                return latest.result.clone();
+            }
+        } else if let Some(latest) = state.latest.as_ref().filter(|l| l.is_fresh(max_age)) {
+            return latest.result.clone();
+        }
+        state.capturing = true;
+        drop(state);
+
+        let mut guard = CaptureGuard {
+            slot: &slot,
+            result: None,
+        };
+        let result = capture(key).map(Arc::new);
+        guard.result = Some(result.clone());
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffmpeg::MockCommandRunner;
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_rapid_requests_share_one_capture() {
+        let cache = SnapshotCache::default();
+        let captures = AtomicUsize::new(0);
+        let capture = |_: &str| {
+            captures.fetch_add(1, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(100));
+            Ok(vec![0xff, 0xd8])
+        };
+
+        // Two requests arriving together: the second waits for the first capture
+        std::thread::scope(|scope| {
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    let image = cache
+                        .get_or_capture_with("rtsp://cam/main", Duration::from_secs(1), capture)
+                        .unwrap();
+                    assert_eq!(*image, [0xff, 0xd8]);
+                });
+            }
+        });
+        assert_eq!(captures.load(Ordering::SeqCst), 1);
+
+        // Another source has its own entry
+        cache
+            .get_or_capture_with("rtsp://cam/sub", Duration::from_secs(1), capture)
+            .unwrap();
+        assert_eq!(captures.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_ffmpeg_args() {
+        let args = snapshot_ffmpeg_args("rtsp://cam/main", &SnapshotOptions::default());
+        assert_eq!(
+            args.join(" "),
+            "-y -i rtsp://cam/main -vframes 1 -q:v 2 -f image2 -update 1 -"
+        );
+
+        let options = SnapshotOptions {
+            quality: 10,
+            max_width: Some(640),
+            max_height: None,
+            ..SnapshotOptions::default()
+        };
+        let args = snapshot_ffmpeg_args("rtsp://cam/main", &options);
+        let filter = args.iter().position(|arg| arg == "-vf").unwrap();
+        assert_eq!(args[filter + 1], "scale='min(iw,640)':-1");
+        assert!(args.join(" ").contains("-q:v 10"));
+
+        let options = SnapshotOptions {
+            max_width: Some(640),
+            max_height: Some(360),
+            ..SnapshotOptions::default()
+        };
+        assert_eq!(
+            options.scale_filter().unwrap(),
+            "scale='min(iw,640)':'min(ih,360)':force_original_aspect_ratio=decrease"
+        );
+    }
+
+    #[test]
+    fn test_capture_runs_ffmpeg_through_the_runner() {
+        let options = SnapshotOptions {
+            ffmpeg_path: "/opt/ffmpeg".to_string(),
+            retry: RetryPolicy {
+                retries: 0,
+                initial_delay: Duration::ZERO,
+            },
+            ..SnapshotOptions::default()
+        };
+        let timed_out = Err(io::Error::new(ErrorKind::TimedOut, "killed"));
+        let runner = MockCommandRunner::new([
+            MockCommandRunner::exited(0, &[0xff, 0xd8], b""),
+            MockCommandRunner::exited(1, b"", b"Connection refused"),
+            timed_out,
+        ]);
+
+        let image = capture_snapshot_from_rtsp(&runner, "rtsp://cam/main", &options);
+        assert_eq!(image, Ok(vec![0xff, 0xd8]));
+        let (program, args) = &runner.calls()[0];
+        assert_eq!(program, "/opt/ffmpeg");
+        assert_eq!(*args, snapshot_ffmpeg_args("rtsp://cam/main", &options));
+
+        let failed = capture_snapshot_from_rtsp(&runner, "rtsp://cam/main", &options);
+        assert_eq!(
+            failed,
+            Err(SnapshotError::Failed("Connection refused".to_string()))
+        );
+
+        let timed_out = capture_snapshot_from_rtsp(&runner, "rtsp://cam/main", &options);
+        assert_eq!(timed_out, Err(SnapshotError::TimedOut(options.timeout)));
+
+        // The mock has run out of results, so ffmpeg isn't found
+        let missing = capture_snapshot_from_rtsp(&runner, "rtsp://cam/main", &options);
+        assert!(
+            matches!(missing, Err(SnapshotError::Unavailable(e)) if e.starts_with("ffmpeg not found at '/opt/ffmpeg'"))
+        );
+        assert_eq!(runner.calls().len(), 4);
+    }
+
+    #[test]
+    fn test_capture_retries_while_source_is_down() {
+        let retry = RetryPolicy {
+            retries: 3,
+            initial_delay: Duration::from_millis(1),
+        };
+
+        // The camera is rebooting: ffmpeg fails twice, then gets a frame
+        let mut runs = 0;
+        let image = capture_with_retries(&retry, || {
+            runs += 1;
+            if runs <= 2 {
+                Err(SnapshotError::Failed("Connection refused".to_string()))
+            } else {
+                Ok(vec![0xff, 0xd8])
+            }
+        });
+        assert_eq!(image, Ok(vec![0xff, 0xd8]));
+        assert_eq!(runs, 3);
+
+        // A missing ffmpeg isn't retried
+        let mut runs = 0;
+        let result = capture_with_retries(&retry, || {
+            runs += 1;
+            Err(SnapshotError::Unavailable("not found".to_string()))
+        });
+        assert!(matches!(result, Err(SnapshotError::Unavailable(_))));
+        assert_eq!(runs, 1);
+    }
+
+    #[test]
+    fn test_waiters_share_a_failed_capture() {
+        let cache = SnapshotCache::default();
+        let captures = AtomicUsize::new(0);
+        let capture = |_: &str| {
+            captures.fetch_add(1, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(100));
+            Err(SnapshotError::Failed("no frame".to_string()))
+        };
+
+        std::thread::scope(|scope| {
+            for _ in 0..3 {
+                scope.spawn(|| {
+                    let result = cache.get_or_capture_with(
+                        "rtsp://cam/main",
+                        Duration::from_secs(5),
+                        capture,
+                    );
+                    assert_eq!(result, Err(SnapshotError::Failed("no frame".to_string())));
+                });
+            }
+        });
+        assert_eq!(captures.load(Ordering::SeqCst), 1);
+
+        // The failure is reported to new requests for a while without running ffmpeg
+        let result = cache.get_or_capture_with("rtsp://cam/main", Duration::from_secs(5), |_| {
+            panic!("a recent failure should be reused")
+        });
+        assert!(matches!(result, Err(SnapshotError::Failed(_))));
+    }
+
+    #[test]
+    fn test_stale_or_failed_snapshots_are_recaptured() {
+        let cache = SnapshotCache::default();
+        let failed = cache.get_or_capture_with("rtsp://cam/main", Duration::from_secs(1), |_| {
+            Err(SnapshotError::Failed("no frame".to_string()))
+        });
+        assert!(failed.is_err());
+
+        let first = cache
+            .get_or_capture_with("rtsp://cam/main", Duration::ZERO, |_| Ok(vec![1]))
+            .unwrap();
+        let second = cache
+            .get_or_capture_with("rtsp://cam/main", Duration::ZERO, |_| Ok(vec![2]))
+            .unwrap();
+        assert_eq!(*first, [1]);
+        assert_eq!(*second, [2]);
+    }
+}
@@ -1,9 +1,17 @@
+use crate::onvif::http::header_values;
+
 pub struct SoapResponseBuilder {
     header_content: String,
     body_content: String,
     namespaces: Vec<(String, String)>,
 }
 
+impl Default for SoapResponseBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SoapResponseBuilder {
     pub fn new() -> Self {
         Self {
@@ -55,3 +63,427 @@ impl SoapResponseBuilder {
         )
     }
 }
+
+/// Builds XML fragments element by element, escaping text and attribute values
+///
+/// Each tag goes on its own line, like the hand-written response templates.
+#[derive(Debug, Default)]
+pub struct XmlWriter {
+    xml: String,
+}
+
+impl XmlWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens `<name attr="value">`; close it with `end`
+    pub fn start(&mut self, name: &str, attributes: &[(&str, &str)]) -> &mut Self {
+        self.open_tag(name, attributes);
+        self.xml.push('\n');
+        self
+    }
+
+    pub fn end(&mut self, name: &str) -> &mut Self {
+        self.xml.push_str(&format!("</{name}>\n"));
+        self
+    }
+
+    /// Writes `<name attr="value">text</name>`
+    pub fn text_element(
+        &mut self,
+        name: &str,
+        attributes: &[(&str, &str)],
+        text: &str,
+    ) -> &mut Self {
+        self.open_tag(name, attributes);
+        self.xml.push_str(&xml_escape(text));
+        self.end(name)
+    }
+
+    /// Returns the XML written so far and resets the writer
+    pub fn finish(&mut self) -> String {
+        let mut xml = std::mem::take(&mut self.xml);
+        if xml.ends_with('\n') {
+            xml.pop();
+        }
+        xml
+    }
+
+    fn open_tag(&mut self, name: &str, attributes: &[(&str, &str)]) {
+        self.xml.push('<');
+        self.xml.push_str(name);
+        for (attribute, value) in attributes {
+            self.xml
+                .push_str(&format!(" {attribute}=\"{}\"", xml_escape(value)));
+        }
+        self.xml.push('>');
+    }
+}
+
+/// Escapes the characters that can't appear literally in XML text or attribute values
+pub fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Extracts the SOAP operation name from a request
+///
+/// Locates the first child element of the SOAP `Body`, strips its namespace
+/// prefix and returns the local name (e.g. `GetProfiles`). Comments and
+/// processing instructions inside the body are skipped.
+pub fn parse_soap_action(request: &str) -> Option<String> {
+    let tag_start = find_operation_start(request)?;
+    let name = element_name(&request[tag_start + 1..]);
+    Some(local_name(name).to_string())
+}
+
+/// Resolves the namespace URI of the SOAP operation element
+///
+/// The element's prefix (or the default namespace when unprefixed) is looked up
+/// on the operation tag itself first, then on the nearest enclosing declaration.
+/// Returns `None` when the namespace isn't declared anywhere in the request.
+pub fn parse_soap_operation_namespace(request: &str) -> Option<String> {
+    let tag_start = find_operation_start(request)?;
+    let name = element_name(&request[tag_start + 1..]);
+    let attribute = match name.rsplit_once(':') {
+        Some((prefix, _)) => format!("xmlns:{prefix}="),
+        None => "xmlns=".to_string(),
+    };
+
+    let tag_end = tag_start + request[tag_start..].find('>')?;
+    if let Some(uri) = find_namespace_declaration(&request[tag_start..tag_end], &attribute, false) {
+        return Some(uri);
+    }
+    find_namespace_declaration(&request[..tag_start], &attribute, true)
+}
+
+/// Returns the trimmed text of the first element with the given local name
+///
+/// The namespace prefix is ignored, so `tt:Protocol` and `Protocol` both match
+/// `"Protocol"`. Self-closing elements yield an empty string.
+pub fn find_element_text<'a>(xml: &'a str, local: &str) -> Option<&'a str> {
+    let (tag_start, tag_end) = find_start_tag(xml, local)?;
+    if xml[..tag_end].ends_with('/') {
+        return Some("");
+    }
+    let name = element_name(&xml[tag_start + 1..]);
+    let content_start = tag_end + 1;
+    let close = format!("</{name}>");
+    let content_end = content_start + xml[content_start..].find(&close)?;
+    Some(xml[content_start..content_end].trim())
+}
+
+/// Returns the trimmed text of every element with the given local name, in order
+pub fn find_all_element_texts<'a>(xml: &'a str, local: &str) -> Vec<&'a str> {
+    let mut texts = Vec::new();
+    let mut rest = xml;
+    while let Some((tag_start, _)) = find_start_tag(rest, local) {
+        if let Some(text) = find_element_text(&rest[tag_start..], local) {
+            texts.push(text);
+        }
+        rest = &rest[tag_start + 1..];
+    }
+    texts
+}
+
+/// Returns the value of an attribute on the first element with the given local name
+///
+/// Like `find_element_text`, the element's namespace prefix is ignored; the
+/// attribute name must match exactly (e.g. `"token"`).
+pub fn find_element_attribute<'a>(xml: &'a str, local: &str, attribute: &str) -> Option<&'a str> {
+    let (tag_start, tag_end) = find_start_tag(xml, local)?;
+    let tag = &xml[tag_start..tag_end];
+    let mut search_start = 0;
+    while let Some(pos) = tag[search_start..].find(attribute) {
+        let name_start = search_start + pos;
+        search_start = name_start + 1;
+        // Require a word boundary so `token=` doesn't match inside `ProfileToken=`
+        if !tag[..name_start].ends_with(char::is_whitespace) {
+            continue;
+        }
+        let rest = tag[name_start + attribute.len()..].trim_start();
+        let Some(value) = rest.strip_prefix('=').map(str::trim_start) else {
+            continue;
+        };
+        let quote = value.chars().next().filter(|c| matches!(c, '"' | '\''))?;
+        let value = &value[1..];
+        return value.find(quote).map(|end| &value[..end]);
+    }
+    None
+}
+
+/// Returns the byte offsets of the `<` and `>` of the first start tag with the given local name
+fn find_start_tag(xml: &str, local: &str) -> Option<(usize, usize)> {
+    let mut search_start = 0;
+    while let Some(pos) = xml[search_start..].find('<') {
+        let tag_start = search_start + pos;
+        search_start = tag_start + 1;
+
+        let name = element_name(&xml[tag_start + 1..]);
+        if name.starts_with(['/', '!', '?']) || local_name(name) != local {
+            continue;
+        }
+        let tag_end = tag_start + xml[tag_start..].find('>')?;
+        return Some((tag_start, tag_end));
+    }
+    None
+}
+
+/// Returns true if an HTTP request claims to carry SOAP
+///
+/// That is a SOAP content type (`application/soap+xml`, or SOAP 1.1's `text/xml`),
+/// or a body whose root element is an `Envelope`. Such a request without an
+/// operation `parse_soap_action` can find is malformed rather than plain HTTP.
+pub fn is_soap_request(request: &str) -> bool {
+    let soap_content_type = header_values(request, "content-type").iter().any(|value| {
+        let media_type = value.split(';').next().unwrap_or_default().trim();
+        media_type.eq_ignore_ascii_case("application/soap+xml")
+            || media_type.eq_ignore_ascii_case("text/xml")
+    });
+    if soap_content_type {
+        return true;
+    }
+
+    let Some((_, body)) = request.split_once("\r\n\r\n") else {
+        return false;
+    };
+    let mut body = body.trim_start();
+    if body.starts_with("<?") {
+        body = body
+            .find("?>")
+            .map_or("", |end| body[end + 2..].trim_start());
+    }
+    body.strip_prefix('<')
+        .is_some_and(|root| local_name(element_name(root)) == "Envelope")
+}
+
+/// Returns the byte offset of the `<` opening the first child element of the `Body`
+fn find_operation_start(request: &str) -> Option<usize> {
+    let mut pos = find_body_content_start(request)?;
+
+    loop {
+        pos += request[pos..].find('<')?;
+        let rest = &request[pos..];
+
+        if let Some(after_comment) = rest.strip_prefix("<!--") {
+            pos += 4 + after_comment.find("-->")? + 3;
+            continue;
+        }
+        if rest.starts_with("<?") {
+            pos += rest.find("?>")? + 2;
+            continue;
+        }
+        if rest.starts_with("</") {
+            // Body closed without any child element
+            return None;
+        }
+
+        if element_name(&rest[1..]).is_empty() {
+            return None;
+        }
+        return Some(pos);
+    }
+}
+
+/// Finds the value of an `xmlns` attribute such as `xmlns:trt=`, searching from the end when `last` is set
+fn find_namespace_declaration(text: &str, attribute: &str, last: bool) -> Option<String> {
+    let mut search = text;
+    loop {
+        let found = if last {
+            search.rfind(attribute)?
+        } else {
+            search.find(attribute)?
+        };
+
+        // Require a word boundary so `xmlns:tt=` doesn't match inside `xmlns:ptt=`
+        let boundary = search[..found]
+            .chars()
+            .next_back()
+            .is_none_or(|c| c.is_whitespace());
+        if boundary {
+            let value = &search[found + attribute.len()..];
+            let quote = value.chars().next()?;
+            if quote == '"' || quote == '\'' {
+                let end = value[1..].find(quote)?;
+                return Some(value[1..end + 1].to_string());
+            }
+        }
+
+        search = if last {
+            &search[..found]
+        } else {
+            &search[found + attribute.len()..]
+        };
+    }
+}
+
+/// Returns the byte offset just after the opening `Body` tag
+fn find_body_content_start(request: &str) -> Option<usize> {
+    let mut search_start = 0;
+    while let Some(pos) = request[search_start..].find('<') {
+        let tag_start = search_start + pos;
+        let name = element_name(&request[tag_start + 1..]);
+        if local_name(name) == "Body" {
+            let tag_end = request[tag_start..].find('>')?;
+            return Some(tag_start + tag_end + 1);
+        }
+        search_start = tag_start + 1;
+    }
+    None
+}
+
+/// Reads a qualified element name up to whitespace, `/` or `>`
+fn element_name(tag: &str) -> &str {
+    let end = tag
+        .find(|c: char| c.is_whitespace() || c == '/' || c == '>')
+        .unwrap_or(tag.len());
+    &tag[..end]
+}
+
+/// Strips the namespace prefix from a qualified name
+fn local_name(qualified: &str) -> &str {
+    qualified.rsplit(':').next().unwrap_or(qualified)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xml_writer_escapes_text_and_attributes() {
+        let xml = XmlWriter::new()
+            .start("tt:Device", &[("token", "a\"b")])
+            .text_element("tt:Name", &[], "Tom & Jerry <cam>")
+            .end("tt:Device")
+            .finish();
+        assert_eq!(
+            xml,
+            "<tt:Device token=\"a&quot;b\">\n<tt:Name>Tom &amp; Jerry &lt;cam&gt;</tt:Name>\n</tt:Device>"
+        );
+        assert_eq!(xml_escape("it's"), "it&apos;s");
+    }
+
+    #[test]
+    fn test_parse_soap_action_with_prefix() {
+        let request = r#"POST /onvif/media_service HTTP/1.1\r\n\r\n<s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope"><s:Body><trt:GetProfiles xmlns:trt="http://www.onvif.org/ver10/media/wsdl"/></s:Body></s:Envelope>"#;
+        assert_eq!(parse_soap_action(request), Some("GetProfiles".to_string()));
+    }
+
+    #[test]
+    fn test_parse_soap_action_without_prefix() {
+        let request = r#"<Envelope><Body>
+<GetCapabilities><Category>All</Category></GetCapabilities>
+</Body></Envelope>"#;
+        assert_eq!(
+            parse_soap_action(request),
+            Some("GetCapabilities".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_soap_action_ignores_mentions_outside_operation() {
+        // GetStreamUri appears in the header, a comment and a scope string, but the operation is GetProfiles
+        let request = r#"<soap:Envelope xmlns:soap="http://www.w3.org/2003/05/soap-envelope">
+<soap:Header><wsa:Action>http://www.onvif.org/ver10/media/wsdl/GetStreamUri</wsa:Action></soap:Header>
+<soap:Body>
+<!-- GetStreamUri -->
+<trt:GetProfiles><tt:Scope>onvif://www.onvif.org/GetStreamUri</tt:Scope></trt:GetProfiles>
+</soap:Body>
+</soap:Envelope>"#;
+        assert_eq!(parse_soap_action(request), Some("GetProfiles".to_string()));
+    }
+
+    #[test]
+    fn test_parse_soap_operation_namespace() {
+        // Declared on the operation element itself
+        let request = r#"<s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope"><s:Body><trt:GetProfiles xmlns:trt="http://www.onvif.org/ver10/media/wsdl"/></s:Body></s:Envelope>"#;
+        assert_eq!(
+            parse_soap_operation_namespace(request).as_deref(),
+            Some("http://www.onvif.org/ver10/media/wsdl")
+        );
+
+        // Declared on the envelope
+        let request = r#"<s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope" xmlns:tds='http://www.onvif.org/ver10/device/wsdl'><s:Body><tds:GetCapabilities/></s:Body></s:Envelope>"#;
+        assert_eq!(
+            parse_soap_operation_namespace(request).as_deref(),
+            Some("http://www.onvif.org/ver10/device/wsdl")
+        );
+
+        // Default namespace on the operation
+        let request = r#"<s:Envelope><s:Body><GetServices xmlns="http://www.onvif.org/ver10/device/wsdl"/></s:Body></s:Envelope>"#;
+        assert_eq!(
+            parse_soap_operation_namespace(request).as_deref(),
+            Some("http://www.onvif.org/ver10/device/wsdl")
+        );
+
+        // Undeclared prefix
+        let request = r#"<s:Envelope><s:Body><trt:GetProfiles/></s:Body></s:Envelope>"#;
+        assert_eq!(parse_soap_operation_namespace(request), None);
+    }
+
+    #[test]
+    fn test_find_element_text() {
+        let xml = r#"<trt:StreamSetup><tt:Stream>RTP-Unicast</tt:Stream><tt:Transport><tt:Protocol> RTSP </tt:Protocol><tt:Tunnel/></tt:Transport></trt:StreamSetup>"#;
+        assert_eq!(find_element_text(xml, "Stream"), Some("RTP-Unicast"));
+        assert_eq!(find_element_text(xml, "Protocol"), Some("RTSP"));
+        assert_eq!(find_element_text(xml, "Tunnel"), Some(""));
+        assert_eq!(find_element_text(xml, "ProfileToken"), None);
+
+        let xml =
+            "<tds:SetScopes><tds:Scopes>a</tds:Scopes><tds:Scopes> b </tds:Scopes></tds:SetScopes>";
+        assert_eq!(find_all_element_texts(xml, "Scopes"), vec!["a", "b"]);
+        assert!(find_all_element_texts(xml, "Profile").is_empty());
+    }
+
+    #[test]
+    fn test_find_element_attribute() {
+        let xml = r#"<trt:SetVideoEncoderConfiguration><trt:Configuration xmlns:x="urn:token" token = 'VideoEncoderConfig_HQ'><tt:Name>n</tt:Name></trt:Configuration></trt:SetVideoEncoderConfiguration>"#;
+        assert_eq!(
+            find_element_attribute(xml, "Configuration", "token"),
+            Some("VideoEncoderConfig_HQ")
+        );
+        assert_eq!(find_element_attribute(xml, "Name", "token"), None);
+        assert_eq!(
+            find_element_attribute(
+                r#"<Profile ProfileToken="a" token="b"/>"#,
+                "Profile",
+                "token"
+            ),
+            Some("b")
+        );
+    }
+
+    #[test]
+    fn test_parse_soap_action_missing_body() {
+        assert_eq!(parse_soap_action("GET /snapshot.jpg HTTP/1.1"), None);
+        assert_eq!(
+            parse_soap_action("<s:Envelope><s:Body></s:Body></s:Envelope>"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_is_soap_request() {
+        assert!(is_soap_request(
+            "POST /onvif/device_service HTTP/1.1\r\nContent-Type: application/soap+xml; charset=utf-8\r\n\r\n<broken"
+        ));
+        assert!(is_soap_request(
+            "POST / HTTP/1.1\r\nHost: cam\r\n\r\n<?xml version=\"1.0\"?>\n<s:Envelope><s:Body>"
+        ));
+        assert!(!is_soap_request("GET / HTTP/1.1\r\nHost: cam\r\n\r\n"));
+        assert!(!is_soap_request(
+            "POST / HTTP/1.1\r\nContent-Type: text/plain\r\n\r\nhello"
+        ));
+    }
+}
@@ -0,0 +1,354 @@
+// ONVIF Service State
+// Runtime state shared by every request handled by the ONVIF service
+
+use crate::config::Config;
+use crate::id_generator::{IdGenerator, RandomIdGenerator};
+use crate::onvif::connections::ActiveConnections;
+use crate::onvif::date_time::DateTimeSettings;
+use crate::onvif::encoder_config::EncoderSettings;
+#[cfg(feature = "events")]
+use crate::onvif::events::{HeldPulls, Subscriptions};
+use crate::onvif::metrics::Metrics;
+use crate::onvif::mjpeg::MjpegViewers;
+use crate::onvif::profiles::ProfileConfig;
+use crate::onvif::snapshot::SnapshotCache;
+use crate::relay::RtspRelay;
+use crate::scopes::DeviceScopes;
+use crate::service_status::ServiceStatus;
+use crate::stream_probe::StreamParamsCache;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Shared runtime state for the ONVIF service
+#[derive(Debug)]
+pub struct OnvifState {
+    /// Recently accepted WS-Security nonces, used to reject replayed tokens
+    pub nonce_cache: NonceCache,
+    /// Recent authentication failures by client IP
+    pub auth_throttle: AuthThrottle,
+    /// Probed parameters of the source stream
+    pub stream_params: StreamParamsCache,
+    /// Source of challenge nonces and message IDs
+    pub ids: Arc<dyn IdGenerator>,
+    /// Date/time settings applied by SetSystemDateAndTime
+    pub date_time: Mutex<DateTimeSettings>,
+    /// Encoder settings applied by SetVideoEncoderConfiguration, by configuration token
+    pub encoder_settings: Mutex<HashMap<String, EncoderSettings>>,
+    /// Recently captured snapshot images
+    pub snapshots: SnapshotCache,
+    /// Clients watching the MJPEG stream
+    pub mjpeg_viewers: MjpegViewers,
+    /// Open pull-point subscriptions of the events service
+    #[cfg(feature = "events")]
+    pub subscriptions: Subscriptions,
+    /// PullMessages requests currently held open waiting for an event
+    #[cfg(feature = "events")]
+    pub held_pulls: HeldPulls,
+    /// Request and error counters, also exposed at /metrics
+    pub metrics: Metrics,
+    /// RTSP relay, started by the first GetStreamUri when --enable-relay is set
+    relay: OnceLock<Arc<RtspRelay>>,
+    /// Connections being served, drained on shutdown
+    pub connections: ActiveConnections,
+    /// Scopes reported by GetScopes, shared with WS-Discovery
+    pub scopes: DeviceScopes,
+    /// Most recent error while serving a request, reported by /health
+    last_error: Mutex<Option<String>>,
+    /// Status shared with the other services, e.g. whether WS-Discovery is running
+    pub service_status: ServiceStatus,
+}
+
+impl Default for OnvifState {
+    fn default() -> Self {
+        Self {
+            nonce_cache: NonceCache::default(),
+            auth_throttle: AuthThrottle::default(),
+            stream_params: StreamParamsCache::default(),
+            ids: Arc::new(RandomIdGenerator),
+            date_time: Mutex::new(DateTimeSettings::default()),
+            encoder_settings: Mutex::new(HashMap::new()),
+            snapshots: SnapshotCache::default(),
+            mjpeg_viewers: MjpegViewers::default(),
+            #[cfg(feature = "events")]
+            subscriptions: Subscriptions::default(),
+            #[cfg(feature = "events")]
+            held_pulls: HeldPulls::default(),
+            metrics: Metrics::default(),
+            relay: OnceLock::new(),
+            connections: ActiveConnections::default(),
+            scopes: DeviceScopes::default(),
+            last_error: Mutex::new(None),
+            service_status: ServiceStatus::default(),
+        }
+    }
+}
+
+impl OnvifState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a copy of the current date/time settings
+    pub fn date_time_settings(&self) -> DateTimeSettings {
+        match self.date_time.lock() {
+            Ok(settings) => settings.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// Returns `profiles` with the encoder settings applied by SetVideoEncoderConfiguration
+    pub fn apply_encoder_settings(&self, mut profiles: Vec<ProfileConfig>) -> Vec<ProfileConfig> {
+        let settings = match self.encoder_settings.lock() {
+            Ok(settings) => settings,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        for profile in &mut profiles {
+            if let Some(settings) = settings.get(&profile.encoder_configuration_token()) {
+                settings.apply(profile);
+            }
+        }
+        profiles
+    }
+
+    /// Keeps `settings` for the configuration they name
+    pub fn set_encoder_settings(&self, settings: EncoderSettings) {
+        let mut all = match self.encoder_settings.lock() {
+            Ok(all) => all,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        all.insert(settings.token.clone(), settings);
+    }
+
+    /// Counts a newly accepted connection
+    pub fn record_connection(&self) {
+        Metrics::increment(&self.metrics.connections);
+    }
+
+    /// Number of connections handled since startup
+    pub fn connection_count(&self) -> u64 {
+        self.metrics.connections.load(Ordering::Relaxed)
+    }
+
+    /// Remembers `error` as the most recent one
+    pub fn record_error(&self, error: impl std::fmt::Display) {
+        let mut last_error = match self.last_error.lock() {
+            Ok(last_error) => last_error,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *last_error = Some(error.to_string());
+    }
+
+    /// Returns the most recent error, if any occurred
+    pub fn last_error(&self) -> Option<String> {
+        match self.last_error.lock() {
+            Ok(last_error) => last_error.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// Starts from the given date/time settings, e.g. the configured time zone
+    pub fn with_date_time_settings(mut self, settings: DateTimeSettings) -> Self {
+        self.date_time = Mutex::new(settings);
+        self
+    }
+
+    /// Returns the RTSP relay if --enable-relay or --transcode is set, creating it on first use
+    pub fn relay(&self, config: &Config) -> Option<&Arc<RtspRelay>> {
+        (config.enable_relay || config.transcode).then(|| {
+            self.relay.get_or_init(|| {
+                Arc::new(RtspRelay::from_config(config, self.service_status.clone()))
+            })
+        })
+    }
+
+    /// Uses `relay` instead of one created from the configuration, e.g. in tests
+    pub fn with_relay(mut self, relay: RtspRelay) -> Self {
+        self.relay = OnceLock::from(Arc::new(relay));
+        self
+    }
+
+    /// Shares the scope list WS-Discovery advertises, so SetScopes updates both
+    pub fn with_scopes(mut self, scopes: DeviceScopes) -> Self {
+        self.scopes = scopes;
+        self
+    }
+
+    /// Shares the status of the other services, so /health can report them
+    pub fn with_service_status(mut self, status: ServiceStatus) -> Self {
+        self.service_status = status;
+        self
+    }
+
+    /// Replaces the ID generator, e.g. with a seeded one in tests
+    pub fn with_id_generator(mut self, ids: Arc<dyn IdGenerator>) -> Self {
+        self.ids = ids;
+        self
+    }
+}
+
+/// Cache of accepted WS-Security nonce/created pairs
+///
+/// Entries expire after the freshness window: once a token is too old to pass
+/// the `Created` check it can no longer be replayed, so it no longer needs tracking.
+#[derive(Debug, Default)]
+pub struct NonceCache {
+    seen: Mutex<HashMap<String, Instant>>,
+}
+
+impl NonceCache {
+    /// Records a nonce/created pair, returning false if it was already seen within `ttl`
+    pub fn check_and_insert(&self, nonce: &str, created: &str, ttl: Duration) -> bool {
+        self.check_and_insert_at(nonce, created, ttl, Instant::now())
+    }
+
+    fn check_and_insert_at(&self, nonce: &str, created: &str, ttl: Duration, now: Instant) -> bool {
+        let mut seen = match self.seen.lock() {
+            Ok(seen) => seen,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        // Drop expired entries so memory stays bounded by the request rate within the window
+        seen.retain(|_, accepted_at| now.duration_since(*accepted_at) < ttl);
+
+        let key = format!("{nonce}|{created}");
+        if seen.contains_key(&key) {
+            return false;
+        }
+        seen.insert(key, now);
+        true
+    }
+
+    /// Returns the number of tracked nonces
+    pub fn len(&self) -> usize {
+        self.seen.lock().map(|seen| seen.len()).unwrap_or(0)
+    }
+
+    /// Returns true if no nonces are tracked
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Recent authentication failures by client IP, for brute-force throttling
+///
+/// A client that failed `max_failures` times within the window is refused until
+/// its oldest counted failure leaves the window. A successful login clears its record.
+#[derive(Debug, Default)]
+pub struct AuthThrottle {
+    failures: Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+}
+
+impl AuthThrottle {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<IpAddr, VecDeque<Instant>>> {
+        match self.failures.lock() {
+            Ok(failures) => failures,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Returns how long `ip` must wait before its credentials are checked again,
+    /// or `None` if it isn't throttled
+    pub fn retry_after(&self, ip: IpAddr, max_failures: u32, window: Duration) -> Option<Duration> {
+        self.retry_after_at(ip, max_failures, window, Instant::now())
+    }
+
+    fn retry_after_at(
+        &self,
+        ip: IpAddr,
+        max_failures: u32,
+        window: Duration,
+        now: Instant,
+    ) -> Option<Duration> {
+        if max_failures == 0 {
+            return None;
+        }
+        let mut failures = self.lock();
+        let recent = failures.get_mut(&ip)?;
+        recent.retain(|failed_at| now.duration_since(*failed_at) < window);
+        if recent.len() < max_failures as usize {
+            return None;
+        }
+        let oldest = *recent.front()?;
+        Some(window.saturating_sub(now.duration_since(oldest)))
+    }
+
+    /// Counts a failed login from `ip`
+    pub fn record_failure(&self, ip: IpAddr, window: Duration) {
+        self.record_failure_at(ip, window, Instant::now());
+    }
+
+    fn record_failure_at(&self, ip: IpAddr, window: Duration, now: Instant) {
+        let mut failures = self.lock();
+        // Forget clients whose failures have all expired, so the map stays bounded
+        failures.retain(|_, recent| {
+            recent
+                .back()
+                .is_some_and(|failed_at| now.duration_since(*failed_at) < window)
+        });
+        failures.entry(ip).or_default().push_back(now);
+    }
+
+    /// Clears the failures of `ip` after it logged in
+    pub fn record_success(&self, ip: IpAddr) {
+        self.lock().remove(&ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auth_throttle_counts_failures_within_window() {
+        let throttle = AuthThrottle::default();
+        let ip: IpAddr = "192.0.2.7".parse().unwrap();
+        let other: IpAddr = "192.0.2.8".parse().unwrap();
+        let window = Duration::from_secs(60);
+        let start = Instant::now();
+
+        for i in 0..3 {
+            assert_eq!(throttle.retry_after_at(ip, 3, window, start), None);
+            throttle.record_failure_at(ip, window, start + Duration::from_secs(i));
+        }
+        let now = start + Duration::from_secs(10);
+        assert_eq!(
+            throttle.retry_after_at(ip, 3, window, now),
+            Some(Duration::from_secs(50))
+        );
+        assert_eq!(throttle.retry_after_at(other, 3, window, now), None);
+        assert_eq!(throttle.retry_after_at(ip, 0, window, now), None);
+
+        // Once the first failure leaves the window the client may try again
+        let later = start + Duration::from_secs(60);
+        assert_eq!(throttle.retry_after_at(ip, 3, window, later), None);
+
+        throttle.record_success(ip);
+        assert!(throttle.lock().is_empty());
+    }
+
+    #[test]
+    fn test_nonce_cache_rejects_reuse() {
+        let cache = NonceCache::default();
+        let ttl = Duration::from_secs(300);
+        assert!(cache.check_and_insert("nonce", "2024-01-01T00:00:00Z", ttl));
+        assert!(!cache.check_and_insert("nonce", "2024-01-01T00:00:00Z", ttl));
+        // Same nonce with a different timestamp is a different token
+        assert!(cache.check_and_insert("nonce", "2024-01-01T00:00:01Z", ttl));
+    }
+
+    #[test]
+    fn test_nonce_cache_expires_entries() {
+        let cache = NonceCache::default();
+        let ttl = Duration::from_secs(300);
+        let start = Instant::now();
+        assert!(cache.check_and_insert_at("nonce", "created", ttl, start));
+        assert_eq!(cache.len(), 1);
+
+        let later = start + Duration::from_secs(301);
+        assert!(cache.check_and_insert_at("other", "created", ttl, later));
+        assert_eq!(cache.len(), 1);
+    }
+}
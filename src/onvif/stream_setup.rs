@@ -0,0 +1,136 @@
+// GetStreamUri StreamSetup
+// Typed view of the stream type and transport a client asks for in GetStreamUri
+
+use crate::onvif::soap::find_element_text;
+
+/// Requested stream type (`tt:StreamType`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StreamType {
+    #[default]
+    RtpUnicast,
+    RtpMulticast,
+}
+
+/// Requested transport protocol (`tt:TransportProtocol`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TransportProtocol {
+    Udp,
+    /// Deprecated by ONVIF in favour of RTSP, still sent by older clients
+    Tcp,
+    #[default]
+    Rtsp,
+    Http,
+}
+
+/// The `StreamSetup` of a GetStreamUri request
+///
+/// Missing or unrecognised values fall back to RTP unicast over RTSP, which is
+/// what clients get when they don't ask for anything specific.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StreamSetup {
+    pub stream: StreamType,
+    pub protocol: TransportProtocol,
+}
+
+impl std::fmt::Display for StreamSetup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} over {:?}", self.stream, self.protocol)
+    }
+}
+
+impl StreamSetup {
+    /// Returns true if the stream URI can serve this setup
+    ///
+    /// RTSP clients pick UDP or interleaved TCP during SETUP, so unicast over
+    /// RTSP, UDP or TCP all use the same URI. Multicast and RTSP-over-HTTP
+    /// tunnelling aren't available.
+    pub fn is_supported(&self) -> bool {
+        self.stream == StreamType::RtpUnicast && self.protocol != TransportProtocol::Http
+    }
+
+    /// Returns true for unicast over HTTP, served as MJPEG when that's enabled
+    pub fn is_http_unicast(&self) -> bool {
+        self.stream == StreamType::RtpUnicast && self.protocol == TransportProtocol::Http
+    }
+}
+
+/// Parses the `StreamSetup` element of a GetStreamUri request
+pub fn parse_stream_setup(request: &str) -> StreamSetup {
+    let Some(setup) = find_element_text(request, "StreamSetup") else {
+        return StreamSetup::default();
+    };
+
+    let stream = match find_element_text(setup, "Stream") {
+        Some(stream) if stream.eq_ignore_ascii_case("RTP-Multicast") => StreamType::RtpMulticast,
+        _ => StreamType::RtpUnicast,
+    };
+
+    let protocol = match find_element_text(setup, "Protocol").map(str::to_ascii_uppercase) {
+        Some(protocol) if protocol == "UDP" => TransportProtocol::Udp,
+        Some(protocol) if protocol == "TCP" => TransportProtocol::Tcp,
+        Some(protocol) if protocol == "HTTP" => TransportProtocol::Http,
+        _ => TransportProtocol::Rtsp,
+    };
+
+    StreamSetup { stream, protocol }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_unicast_rtsp() {
+        let request = r#"<trt:GetStreamUri><trt:StreamSetup><tt:Stream>RTP-Unicast</tt:Stream><tt:Transport><tt:Protocol>RTSP</tt:Protocol></tt:Transport></trt:StreamSetup><trt:ProfileToken>HQProfile</trt:ProfileToken></trt:GetStreamUri>"#;
+        assert_eq!(
+            parse_stream_setup(request),
+            StreamSetup {
+                stream: StreamType::RtpUnicast,
+                protocol: TransportProtocol::Rtsp,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_multicast_udp_and_http() {
+        let request = r#"<GetStreamUri><StreamSetup><Stream>RTP-Multicast</Stream><Transport><Protocol>UDP</Protocol></Transport></StreamSetup></GetStreamUri>"#;
+        assert_eq!(
+            parse_stream_setup(request),
+            StreamSetup {
+                stream: StreamType::RtpMulticast,
+                protocol: TransportProtocol::Udp,
+            }
+        );
+
+        let request = r#"<trt:StreamSetup><tt:Stream>RTP-Unicast</tt:Stream><tt:Transport><tt:Protocol>HTTP</tt:Protocol><tt:Tunnel/></tt:Transport></trt:StreamSetup>"#;
+        assert_eq!(
+            parse_stream_setup(request).protocol,
+            TransportProtocol::Http
+        );
+
+        let request = r#"<trt:StreamSetup><tt:Transport><tt:Protocol>TCP</tt:Protocol></tt:Transport></trt:StreamSetup>"#;
+        assert_eq!(parse_stream_setup(request).protocol, TransportProtocol::Tcp);
+    }
+
+    #[test]
+    fn test_parse_missing_fields_use_defaults() {
+        assert_eq!(
+            parse_stream_setup("<trt:GetStreamUri/>"),
+            StreamSetup::default()
+        );
+
+        // Stream given, transport missing
+        let request = r#"<trt:StreamSetup><tt:Stream>RTP-Multicast</tt:Stream></trt:StreamSetup>"#;
+        assert_eq!(
+            parse_stream_setup(request),
+            StreamSetup {
+                stream: StreamType::RtpMulticast,
+                protocol: TransportProtocol::Rtsp,
+            }
+        );
+
+        // Unknown values
+        let request = r#"<trt:StreamSetup><tt:Stream>Bogus</tt:Stream><tt:Transport><tt:Protocol>QUIC</tt:Protocol></tt:Transport></trt:StreamSetup>"#;
+        assert_eq!(parse_stream_setup(request), StreamSetup::default());
+    }
+}
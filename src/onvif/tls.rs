@@ -0,0 +1,127 @@
+// TLS
+// With --tls-cert and --tls-key the ONVIF service speaks HTTPS. The certificate
+// chain and key are loaded once at startup; each accepted connection is wrapped
+// in a rustls session, and the handshake runs as the first request is read, so
+// it is bounded by the same timeouts as a slow request.
+
+use crate::config::Config;
+use crate::onvif::http::TimedRead;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Loads the server certificate chain and private key from PEM files
+pub fn load_server_config(
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<Arc<ServerConfig>, Box<dyn std::error::Error>> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| {
+            format!(
+                "Failed to read TLS certificate {}: {e}",
+                cert_path.display()
+            )
+        })?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", cert_path.display()).into());
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("Failed to read TLS key {}: {e}", key_path.display()))?;
+
+    let config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_protocol_versions(&[&rustls::version::TLS12, &rustls::version::TLS13])?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| format!("Invalid TLS certificate or key: {e}"))?;
+    Ok(Arc::new(config))
+}
+
+/// The TLS settings for `config`, or `None` when it serves plain HTTP
+pub fn server_config(
+    config: &Config,
+) -> Result<Option<Arc<ServerConfig>>, Box<dyn std::error::Error>> {
+    match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => load_server_config(cert, key).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// An accepted client connection, plain or wrapped in TLS
+#[derive(Debug)]
+pub enum ClientStream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl ClientStream {
+    /// Wraps `stream` in a TLS session when `tls` is set
+    pub fn accept(stream: TcpStream, tls: Option<&Arc<ServerConfig>>) -> io::Result<Self> {
+        match tls {
+            Some(tls) => {
+                let session = ServerConnection::new(Arc::clone(tls)).map_err(io::Error::other)?;
+                Ok(ClientStream::Tls(Box::new(StreamOwned::new(
+                    session, stream,
+                ))))
+            }
+            None => Ok(ClientStream::Plain(stream)),
+        }
+    }
+
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            ClientStream::Plain(stream) => stream,
+            ClientStream::Tls(tls) => tls.get_ref(),
+        }
+    }
+
+    /// Another handle to the underlying socket, e.g. to close it from another thread
+    pub fn try_clone_socket(&self) -> io::Result<TcpStream> {
+        self.tcp().try_clone()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().peer_addr()
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.tcp().set_write_timeout(timeout)
+    }
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Plain(stream) => stream.read(buf),
+            ClientStream::Tls(tls) => tls.read(buf),
+        }
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Plain(stream) => stream.write(buf),
+            ClientStream::Tls(tls) => tls.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ClientStream::Plain(stream) => stream.flush(),
+            ClientStream::Tls(tls) => tls.flush(),
+        }
+    }
+}
+
+impl TimedRead for ClientStream {
+    fn set_read_limit(&mut self, timeout: Duration) -> io::Result<()> {
+        self.tcp().set_read_timeout(Some(timeout))
+    }
+}
@@ -0,0 +1,357 @@
+// RTSP Relay
+// With --enable-relay, GetStreamUri advertises a stream that ffmpeg republishes
+// to the local RTSP server instead of the upstream source URL, so clients never
+// see the camera's address or credentials. Each profile's relay starts on its
+// first stream request, and a watchdog restarts ffmpeg whenever it exits.
+// With --transcode, sources that aren't H264 are relayed through libx264
+// instead of being copied, for clients that can only decode H264.
+
+use crate::config::Config;
+use crate::ffmpeg::spawn_error_message;
+use crate::service_status::ServiceStatus;
+use crate::stream_output::{ffmpeg_frame_rate_args, StreamOutput};
+use crate::stream_probe::VideoEncoding;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// How often the watchdog checks the relay processes
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The ffmpeg process relaying one profile
+#[derive(Debug)]
+struct RelayProcess {
+    source_url: String,
+    /// Re-encode to H264 instead of copying the source
+    transcode: bool,
+    child: Option<Child>,
+    /// Times ffmpeg was restarted after exiting
+    restarts: u64,
+}
+
+/// libx264 settings for relayed streams that are transcoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TranscodeSettings {
+    pub bitrate_kbps: u32,
+    pub preset: String,
+    /// Output frame rate arguments (`--frame-rate`), empty to follow the source
+    pub frame_rate_args: Vec<String>,
+}
+
+impl TranscodeSettings {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            bitrate_kbps: config.transcode_bitrate,
+            preset: config.transcode_preset.clone(),
+            frame_rate_args: ffmpeg_frame_rate_args(config),
+        }
+    }
+
+    /// ffmpeg codec arguments encoding the video as H264 and copying any audio
+    fn codec_args(&self) -> Vec<String> {
+        let bitrate = format!("{}k", self.bitrate_kbps);
+        let mut args: Vec<String> = [
+            "-c:v",
+            "libx264",
+            "-preset",
+            &self.preset,
+            "-tune",
+            "zerolatency",
+            "-b:v",
+            &bitrate,
+            "-maxrate",
+            &bitrate,
+            "-bufsize",
+            &format!("{}k", self.bitrate_kbps * 2),
+            // MJPEG sources decode to full-range 4:2:2, which baseline decoders reject
+            "-pix_fmt",
+            "yuv420p",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        args.extend(self.frame_rate_args.iter().cloned());
+        args.extend(["-c:a", "copy"].map(String::from));
+        args
+    }
+}
+
+/// Returns true if a source with the ffprobe codec name `codec` must be
+/// transcoded for H264-only clients
+pub fn needs_transcode(codec: &str) -> bool {
+    VideoEncoding::from_codec_name(codec) != Some(VideoEncoding::H264)
+}
+
+/// Relays profile sources through the local RTSP server
+#[derive(Debug)]
+pub struct RtspRelay {
+    program: String,
+    /// Host advertised to clients
+    host: String,
+    port: u16,
+    path: String,
+    /// Set with --transcode; used for the profiles whose source isn't H264
+    transcode: Option<TranscodeSettings>,
+    processes: Mutex<HashMap<String, RelayProcess>>,
+    watchdog_started: AtomicBool,
+    status: ServiceStatus,
+}
+
+impl RtspRelay {
+    pub fn from_config(config: &Config, status: ServiceStatus) -> Self {
+        Self {
+            program: config.ffmpeg_path.clone(),
+            host: config.url_host(),
+            port: config.relay_port,
+            path: config.relay_path.trim_end_matches('/').to_string(),
+            transcode: config
+                .transcode
+                .then(|| TranscodeSettings::from_config(config)),
+            processes: Mutex::default(),
+            watchdog_started: AtomicBool::new(false),
+            status,
+        }
+    }
+
+    /// Runs `program` instead of ffmpeg, e.g. a stand-in in tests
+    pub fn with_program(mut self, program: &str) -> Self {
+        self.program = program.to_string();
+        self
+    }
+
+    /// URL clients are given for `profile_token`'s relayed stream
+    pub fn advertised_url(&self, profile_token: &str) -> String {
+        self.url_on_host(&self.host, profile_token)
+    }
+
+    /// `profile_token`'s relayed stream as reached through `host` rather than CONTAINER_IP
+    pub fn url_on_host(&self, host: &str, profile_token: &str) -> String {
+        format!("rtsp://{host}:{}{}/{profile_token}", self.port, self.path)
+    }
+
+    /// URL ffmpeg publishes `profile_token`'s stream to
+    fn publish_url(&self, profile_token: &str) -> String {
+        format!(
+            "rtsp://127.0.0.1:{}{}/{profile_token}",
+            self.port, self.path
+        )
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, RelayProcess>> {
+        match self.processes.lock() {
+            Ok(processes) => processes,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Returns true if relayed sources with the ffprobe codec name `codec` are
+    /// transcoded rather than copied
+    pub fn transcodes(&self, codec: &str) -> bool {
+        self.transcode.is_some() && needs_transcode(codec)
+    }
+
+    /// Starts relaying `source_url` for `profile_token` unless it already is,
+    /// returning the URL to advertise
+    ///
+    /// `transcode` re-encodes the stream to H264; it has no effect without --transcode.
+    ///
+    /// If ffmpeg can't be started the URL is still returned: the watchdog keeps
+    /// trying, and the stream appears once the relay comes up.
+    pub fn ensure_running(
+        self: &Arc<Self>,
+        profile_token: &str,
+        source_url: &str,
+        transcode: bool,
+    ) -> String {
+        {
+            let mut processes = self.lock();
+            let process = processes
+                .entry(profile_token.to_string())
+                .or_insert_with(|| RelayProcess {
+                    source_url: source_url.to_string(),
+                    transcode: transcode && self.transcode.is_some(),
+                    child: None,
+                    restarts: 0,
+                });
+            if process.child.is_none() {
+                info!("Starting RTSP relay for profile {profile_token}");
+                process.child = self.spawn(profile_token, source_url, process.transcode);
+            }
+        }
+        self.start_watchdog();
+        self.advertised_url(profile_token)
+    }
+
+    /// Builds the ffmpeg arguments relaying `source_url` to the local server
+    fn ffmpeg_args(&self, profile_token: &str, source_url: &str, transcode: bool) -> Vec<String> {
+        let output = StreamOutput::Serve {
+            publish_url: self.publish_url(profile_token),
+            pull_url: self.advertised_url(profile_token),
+        };
+        let mut args: Vec<String> = ["-rtsp_transport", "tcp", "-i", source_url]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        match self.transcode.as_ref().filter(|_| transcode) {
+            Some(settings) => args.extend(settings.codec_args()),
+            None => args.extend(["-c", "copy"].map(String::from)),
+        }
+        args.extend(output.ffmpeg_output_args());
+        args
+    }
+
+    fn spawn(&self, profile_token: &str, source_url: &str, transcode: bool) -> Option<Child> {
+        match Command::new(&self.program)
+            .args(self.ffmpeg_args(profile_token, source_url, transcode))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(child) => Some(child),
+            Err(e) => {
+                let reason = spawn_error_message("ffmpeg", &self.program, &e);
+                warn!("Failed to start RTSP relay for profile {profile_token}: {reason}");
+                None
+            }
+        }
+    }
+
+    /// Restarts every relay whose ffmpeg has exited or never started
+    fn restart_exited(&self) {
+        let mut processes = self.lock();
+        for (profile_token, process) in processes.iter_mut() {
+            let running = match process.child.as_mut().map(Child::try_wait) {
+                Some(Ok(None)) => true,
+                Some(Ok(Some(exit))) => {
+                    warn!("RTSP relay for profile {profile_token} exited ({exit}) - restarting");
+                    false
+                }
+                Some(Err(e)) => {
+                    warn!("RTSP relay for profile {profile_token} can't be checked ({e}) - restarting");
+                    false
+                }
+                None => false,
+            };
+            if !running {
+                process.restarts += 1;
+                process.child = self.spawn(profile_token, &process.source_url, process.transcode);
+            }
+        }
+    }
+
+    /// Kills every relay process
+    fn stop_all(&self) {
+        for process in self.lock().values_mut() {
+            if let Some(mut child) = process.child.take() {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+    }
+
+    fn start_watchdog(self: &Arc<Self>) {
+        if self.watchdog_started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let watchdog = Arc::clone(self);
+        std::thread::spawn(move || {
+            while !watchdog.status.is_shutdown_requested() {
+                std::thread::sleep(WATCHDOG_INTERVAL);
+                watchdog.restart_exited();
+            }
+            info!("Shutdown requested - stopping RTSP relays");
+            watchdog.stop_all();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn relay(args: &[&str], status: ServiceStatus) -> RtspRelay {
+        let mut argv = vec!["test", "--enable-relay"];
+        argv.extend_from_slice(args);
+        let config = Config::try_parse_from(argv).unwrap();
+        RtspRelay::from_config(&config, status)
+    }
+
+    #[test]
+    fn test_relay_urls() {
+        let relay = relay(
+            &["--relay-port", "9554", "--relay-path", "/proxy/"],
+            ServiceStatus::new(),
+        );
+        assert_eq!(
+            relay.advertised_url("HQProfile"),
+            format!("rtsp://{}:9554/proxy/HQProfile", relay.host)
+        );
+        assert_eq!(
+            relay.publish_url("HQProfile"),
+            "rtsp://127.0.0.1:9554/proxy/HQProfile"
+        );
+        assert_eq!(relay.program, "ffmpeg");
+
+        let configured = self::relay(
+            &["--ffmpeg-path", "/opt/ffmpeg/bin/ffmpeg"],
+            ServiceStatus::new(),
+        );
+        assert_eq!(configured.program, "/opt/ffmpeg/bin/ffmpeg");
+    }
+
+    #[test]
+    fn test_exited_relay_is_restarted() {
+        let status = ServiceStatus::new();
+        let relay = Arc::new(relay(&[], status.clone()).with_program("true"));
+        let uri = relay.ensure_running("HQProfile", "rtsp://10.0.0.5/cam", false);
+        assert!(uri.ends_with(":8554/relay/HQProfile"));
+        std::thread::sleep(Duration::from_millis(200));
+
+        relay.restart_exited();
+        assert_eq!(relay.lock()["HQProfile"].restarts, 1);
+        assert!(relay.lock()["HQProfile"].child.is_some());
+
+        status.request_shutdown();
+        relay.stop_all();
+        assert!(relay.lock()["HQProfile"].child.is_none());
+    }
+
+    #[test]
+    fn test_hevc_source_is_transcoded() {
+        let relay = relay(
+            &[
+                "--transcode",
+                "--transcode-bitrate",
+                "1500",
+                "--frame-rate",
+                "15",
+            ],
+            ServiceStatus::new(),
+        );
+        assert!(relay.transcodes("hevc"));
+        assert!(relay.transcodes("mjpeg"));
+        assert!(!relay.transcodes("h264"));
+
+        let args = relay.ffmpeg_args("HQProfile", "rtsp://10.0.0.5/cam", relay.transcodes("hevc"));
+        assert!(args.windows(2).any(|pair| pair == ["-c:v", "libx264"]));
+        assert!(args.windows(2).any(|pair| pair == ["-preset", "veryfast"]));
+        assert!(args.windows(2).any(|pair| pair == ["-b:v", "1500k"]));
+        assert!(args.windows(2).any(|pair| pair == ["-r", "15"]));
+        assert!(args.windows(2).any(|pair| pair == ["-c:a", "copy"]));
+        assert!(!args.windows(2).any(|pair| pair == ["-c", "copy"]));
+        assert_eq!(
+            args.last().map(String::as_str),
+            Some("rtsp://127.0.0.1:8554/relay/HQProfile")
+        );
+
+        // H264 sources are copied as before
+        let args = relay.ffmpeg_args("HQProfile", "rtsp://10.0.0.5/cam", relay.transcodes("h264"));
+        assert!(args.windows(2).any(|pair| pair == ["-c", "copy"]));
+        assert!(!args.contains(&"libx264".to_string()));
+    }
+}
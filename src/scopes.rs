@@ -0,0 +1,246 @@
+// Device Scopes
+// The scope URIs advertised by WS-Discovery and managed through the device
+// service's GetScopes, SetScopes and AddScopes. Fixed scopes describe what the
+// device is; configurable ones (name, location) may be replaced by clients.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Scope URI prefix defined by the ONVIF core specification
+const ONVIF_SCOPE_PREFIX: &str = "onvif://www.onvif.org/";
+/// Categories of configurable scopes; a configured scope in one replaces the default
+const CONFIGURABLE_CATEGORIES: [&str; 2] = ["name", "location"];
+
+/// Whether a client may replace a scope, as reported in `tt:ScopeDef`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScopeDef {
+    Fixed,
+    Configurable,
+}
+
+impl ScopeDef {
+    pub fn as_onvif(self) -> &'static str {
+        match self {
+            ScopeDef::Fixed => "Fixed",
+            ScopeDef::Configurable => "Configurable",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scope {
+    pub definition: ScopeDef,
+    pub item: String,
+}
+
+#[derive(Debug, Default)]
+struct ScopeList {
+    scopes: Vec<Scope>,
+    /// Incremented on every change, so WS-Discovery can tell it must re-announce
+    generation: u64,
+}
+
+/// Scope list shared between the device service and WS-Discovery
+///
+/// Clones share the same list, so a change made through SetScopes is seen by
+/// every discovery listener.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceScopes {
+    inner: Arc<Mutex<ScopeList>>,
+}
+
+impl DeviceScopes {
+    /// The default scopes for a device called `device_name`, plus the operator's `configured` ones
+    ///
+    /// A configured name or location scope replaces the default one and stays
+    /// configurable; any other configured scope is fixed.
+    pub fn for_device(device_name: &str, configured: &[String]) -> Self {
+        let scope = |definition, path: String| Scope {
+            definition,
+            item: format!("{ONVIF_SCOPE_PREFIX}{path}"),
+        };
+        let mut list = vec![
+            scope(ScopeDef::Fixed, "type/NetworkVideoTransmitter".to_string()),
+            scope(ScopeDef::Fixed, "Profile/Streaming".to_string()),
+            scope(ScopeDef::Configurable, format!("name/{device_name}")),
+            scope(ScopeDef::Fixed, format!("hardware/{device_name}")),
+            scope(ScopeDef::Configurable, "location/Unknown".to_string()),
+        ];
+
+        let replaced: Vec<&str> = configured
+            .iter()
+            .filter_map(|item| configurable_category(item))
+            .collect();
+        list.retain(|scope| {
+            configurable_category(&scope.item).is_none_or(|category| !replaced.contains(&category))
+        });
+        for item in configured {
+            if list.iter().any(|scope| scope.item == *item) {
+                continue;
+            }
+            let definition = match configurable_category(item) {
+                Some(_) => ScopeDef::Configurable,
+                None => ScopeDef::Fixed,
+            };
+            list.push(Scope {
+                definition,
+                item: item.clone(),
+            });
+        }
+
+        let scopes = DeviceScopes::default();
+        scopes.lock().scopes = list;
+        scopes
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ScopeList> {
+        match self.inner.lock() {
+            Ok(list) => list,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Returns every scope, fixed ones included
+    pub fn list(&self) -> Vec<Scope> {
+        self.lock().scopes.clone()
+    }
+
+    /// Returns the scopes as the space-separated list WS-Discovery advertises
+    pub fn to_discovery_string(&self) -> String {
+        let list = self.lock();
+        let items: Vec<&str> = list
+            .scopes
+            .iter()
+            .map(|scope| scope.item.as_str())
+            .collect();
+        items.join(" ")
+    }
+
+    /// Returns a counter that changes whenever the scopes do
+    pub fn generation(&self) -> u64 {
+        self.lock().generation
+    }
+
+    /// Replaces every configurable scope with `items` (SetScopes)
+    pub fn set_configurable(&self, items: &[&str]) -> Result<(), String> {
+        validate_items(items)?;
+        let mut list = self.lock();
+        list.scopes
+            .retain(|scope| scope.definition == ScopeDef::Fixed);
+        push_configurable(&mut list.scopes, items);
+        list.generation += 1;
+        Ok(())
+    }
+
+    /// Adds `items` as configurable scopes, skipping ones already present (AddScopes)
+    pub fn add_configurable(&self, items: &[&str]) -> Result<(), String> {
+        validate_items(items)?;
+        let mut list = self.lock();
+        push_configurable(&mut list.scopes, items);
+        list.generation += 1;
+        Ok(())
+    }
+}
+
+/// Returns the category of an ONVIF name or location scope (e.g. `location`)
+fn configurable_category(item: &str) -> Option<&str> {
+    let (category, _) = item.strip_prefix(ONVIF_SCOPE_PREFIX)?.split_once('/')?;
+    CONFIGURABLE_CATEGORIES
+        .iter()
+        .find(|configurable| **configurable == category)
+        .copied()
+}
+
+/// Checks the scopes given with `--scope`; none at all is fine
+pub fn validate_configured(items: &[String]) -> Result<(), String> {
+    if items.is_empty() {
+        return Ok(());
+    }
+    let items: Vec<&str> = items.iter().map(String::as_str).collect();
+    validate_items(&items)
+}
+
+/// Scopes are advertised as a space-separated list, so an item can't hold whitespace
+fn validate_items(items: &[&str]) -> Result<(), String> {
+    if items.is_empty() {
+        return Err("No scopes given".to_string());
+    }
+    match items
+        .iter()
+        .find(|item| item.is_empty() || item.contains(char::is_whitespace))
+    {
+        Some(item) => Err(format!("Invalid scope '{item}'")),
+        None => Ok(()),
+    }
+}
+
+fn push_configurable(scopes: &mut Vec<Scope>, items: &[&str]) {
+    for item in items {
+        if !scopes.iter().any(|scope| scope.item == *item) {
+            scopes.push(Scope {
+                definition: ScopeDef::Configurable,
+                item: item.to_string(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_scopes() {
+        let scopes = DeviceScopes::for_device("Cam", &[]);
+        assert_eq!(
+            scopes.to_discovery_string(),
+            "onvif://www.onvif.org/type/NetworkVideoTransmitter onvif://www.onvif.org/Profile/Streaming onvif://www.onvif.org/name/Cam onvif://www.onvif.org/hardware/Cam onvif://www.onvif.org/location/Unknown"
+        );
+        assert_eq!(scopes.list()[0].definition, ScopeDef::Fixed);
+        assert_eq!(scopes.list()[2].definition, ScopeDef::Configurable);
+    }
+
+    #[test]
+    fn test_configured_scopes_replace_name_and_location() {
+        let configured = [
+            "onvif://www.onvif.org/location/Lobby".to_string(),
+            "onvif://www.onvif.org/location/Building1".to_string(),
+            "onvif://www.onvif.org/hardware/Cam".to_string(),
+            "https://vms.example/group/entrances".to_string(),
+        ];
+        let scopes = DeviceScopes::for_device("Cam", &configured);
+        assert_eq!(
+            scopes.to_discovery_string(),
+            "onvif://www.onvif.org/type/NetworkVideoTransmitter onvif://www.onvif.org/Profile/Streaming onvif://www.onvif.org/name/Cam onvif://www.onvif.org/hardware/Cam onvif://www.onvif.org/location/Lobby onvif://www.onvif.org/location/Building1 https://vms.example/group/entrances"
+        );
+        let list = scopes.list();
+        assert_eq!(list[4].definition, ScopeDef::Configurable);
+        assert_eq!(list[6].definition, ScopeDef::Fixed);
+
+        assert!(validate_configured(&[]).is_ok());
+        assert!(validate_configured(&["has space".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_set_keeps_fixed_scopes_and_bumps_generation() {
+        let scopes = DeviceScopes::for_device("Cam", &[]);
+        let shared = scopes.clone();
+        shared
+            .set_configurable(&["onvif://www.onvif.org/location/Lobby"])
+            .unwrap();
+
+        assert_eq!(scopes.generation(), 1);
+        assert_eq!(
+            scopes.to_discovery_string(),
+            "onvif://www.onvif.org/type/NetworkVideoTransmitter onvif://www.onvif.org/Profile/Streaming onvif://www.onvif.org/hardware/Cam onvif://www.onvif.org/location/Lobby"
+        );
+
+        scopes
+            .add_configurable(&["onvif://www.onvif.org/location/Lobby", "x:extra"])
+            .unwrap();
+        assert_eq!(scopes.list().len(), 5);
+
+        assert!(scopes.set_configurable(&["has space"]).is_err());
+        assert!(scopes.add_configurable(&[]).is_err());
+        assert_eq!(scopes.generation(), 2);
+    }
+}
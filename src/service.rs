@@ -0,0 +1,262 @@
+// Service Orchestration
+// Runs the ONVIF web service and, when enabled, the WS-Discovery servers until
+// shutdown is requested. `main` calls `run` after loading the configuration and
+// installing signal handlers; programs embedding the transcoder call it the same
+// way with their own ServiceStatus.
+
+use crate::config::Config;
+use crate::ffmpeg;
+use crate::onvif::date_time::DateTimeSettings;
+use crate::onvif::state::OnvifState;
+use crate::onvif::{self, device_uptime};
+use crate::rtsp_health::{
+    check_rtsp_source, spawn_rtsp_health_monitor, validate_stream_at_startup, RetryPolicy,
+};
+use crate::scopes::DeviceScopes;
+use crate::service_status::ServiceStatus;
+use crate::stream_output;
+use crate::ws_discovery::{DeviceInfo, WSDiscoveryServer};
+use log::{error, info};
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How often the RTSP source is checked when discovery follows its health
+const RTSP_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Runs the transcoder's services until shutdown is requested on `status`
+///
+/// Blocks until the ONVIF service and every WS-Discovery server have stopped;
+/// discovery sends Bye on the way out. Signal handlers aren't installed here,
+/// since they would replace the host program's own: call
+/// `service_status::setup_signal_handlers` first to stop on SIGTERM/SIGINT.
+pub fn run(config: Config, status: ServiceStatus) -> Result<(), Box<dyn std::error::Error>> {
+    // Start the uptime clock if the host hasn't already
+    let _ = device_uptime();
+
+    // Warn once now rather than on every snapshot or probe
+    ffmpeg::check_tools(&config);
+
+    // Catch an unreachable source before an NVR fails to pull video from it
+    let retry = RetryPolicy::from_config(&config);
+    validate_stream_at_startup(&config.rtsp_stream_url, config.validate_stream, &retry)
+        .map_err(|_| "--validate-stream is set and the RTSP stream is unreachable")?;
+
+    // GetScopes/SetScopes and WS-Discovery share one scope list
+    let scopes = DeviceScopes::for_device(&config.device_name, &config.scopes);
+
+    // In push mode, ffmpeg sends the stream to --output-url alongside the services
+    let push = stream_output::spawn_push(&config, status.clone());
+
+    let result = if config.ws_discovery_enabled {
+        info!("WS-Discovery is enabled - starting discovery service alongside ONVIF...");
+
+        // Start both WS-Discovery and ONVIF services concurrently
+        start_services_with_ws_discovery(&config, &status, &scopes)
+    } else {
+        info!("WS-Discovery disabled - continuing with direct ONVIF connections only");
+
+        // Start ONVIF web service only (this will block)
+        info!("Starting ONVIF web service...");
+        start_onvif_service(&config, &status, &scopes)
+    };
+
+    if let Some(push) = push {
+        // The ONVIF service may have stopped on its own
+        status.request_shutdown();
+        if let Err(e) = push.join() {
+            error!("Stream push thread panicked: {e:?}");
+        }
+    }
+    result
+}
+
+fn start_onvif_service(
+    config: &Config,
+    status: &ServiceStatus,
+    scopes: &DeviceScopes,
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Starting ONVIF web service on port {}", config.onvif_port);
+    info!("Exposing RTSP stream: {}", config.rtsp_stream_url);
+    info!("Device Name: {}", config.device_name);
+    info!("Authentication: {} / [HIDDEN]", config.onvif_username);
+
+    // The port was validated with the rest of the configuration
+    let port: u16 = config.onvif_port.parse()?;
+    let bind_addr = SocketAddr::new(config.bind_address, port);
+    info!("Attempting to bind to address: {bind_addr}");
+
+    let listener = match TcpListener::bind(bind_addr) {
+        Ok(listener) => {
+            info!("Successfully bound to {bind_addr}");
+            listener
+        }
+        Err(e) => {
+            let error_msg = format!("Failed to bind to ONVIF port {}: {}", config.onvif_port, e);
+            error!("{error_msg}");
+            return Err(error_msg.into());
+        }
+    };
+
+    info!("ONVIF Camera service running on port {}", config.onvif_port);
+    info!("Stream URI: {}", config.rtsp_stream_url);
+
+    let state = OnvifState::new()
+        .with_service_status(status.clone())
+        .with_scopes(scopes.clone())
+        .with_date_time_settings(DateTimeSettings::from_config(config));
+    // Start probing the source now, so the first GetProfiles already sees its parameters
+    state
+        .stream_params
+        .get_or_probe(&config.ffprobe_path, &config.rtsp_stream_url);
+    onvif::serve(listener, config, &state, status)
+}
+
+fn start_services_with_ws_discovery(
+    config: &Config,
+    status: &ServiceStatus,
+    scopes: &DeviceScopes,
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Starting services with WS-Discovery enabled...");
+
+    // Create device info for WS-Discovery; every listener shares the endpoint reference
+    let endpoint_reference = format!("urn:uuid:{}", config.device_uuid());
+    let interfaces = config.discovery_interfaces();
+    let device_info = discovery_device_info(config, &endpoint_reference, &interfaces);
+
+    // Start WS-Discovery server
+    info!("Creating WS-Discovery server...");
+    let mut ws_discovery_server = WSDiscoveryServer::new(device_info, &interfaces, config.debug)?;
+    ws_discovery_server.set_windows_wsd(config.enable_windows_wsd);
+    ws_discovery_server.set_hello_interval(Duration::from_secs(config.hello_interval));
+    ws_discovery_server
+        .set_max_response_delay(Duration::from_millis(config.discovery_max_delay_ms));
+    ws_discovery_server.set_service_status(status.clone());
+    ws_discovery_server.set_scopes(scopes.clone());
+
+    // Optional unicast listener on the management network, with its own XAddrs
+    let mut management_server = match config.discovery_management_ip {
+        Some(ip) => {
+            let device_info = discovery_device_info(config, &endpoint_reference, &[ip]);
+            let bind_addr = SocketAddr::new(ip, 3702);
+            Some(WSDiscoveryServer::new_unicast(
+                device_info,
+                bind_addr,
+                config.debug,
+            )?)
+        }
+        None => None,
+    };
+
+    let mut health_handle = None;
+    if config.discovery_follow_rtsp {
+        let rtsp_healthy = Arc::new(AtomicBool::new(check_rtsp_source(&config.rtsp_stream_url)));
+        health_handle = Some(spawn_rtsp_health_monitor(
+            config.rtsp_stream_url.clone(),
+            rtsp_healthy.clone(),
+            RTSP_HEALTH_CHECK_INTERVAL,
+            status.clone(),
+        ));
+        if let Some(server) = management_server.as_mut() {
+            server.set_rtsp_health(rtsp_healthy.clone());
+        }
+        ws_discovery_server.set_rtsp_health(rtsp_healthy);
+    }
+
+    let management_handle = management_server.map(|mut server| {
+        server.set_windows_wsd(config.enable_windows_wsd);
+        server.set_service_status(status.clone());
+        server.set_scopes(scopes.clone());
+        thread::spawn(move || {
+            info!("Starting WS-Discovery management listener thread...");
+            if let Err(e) = server.start() {
+                error!("WS-Discovery management listener error: {e}");
+            }
+        })
+    });
+
+    let config_clone = config.clone();
+    let onvif_status = status.clone();
+    let onvif_scopes = scopes.clone();
+    let onvif_handle = thread::spawn(move || {
+        info!("Starting ONVIF service thread...");
+        if let Err(e) = start_onvif_service(&config_clone, &onvif_status, &onvif_scopes) {
+            error!("ONVIF service error: {e}");
+        }
+    });
+
+    let ws_handle = thread::spawn(move || {
+        info!("Starting WS-Discovery service thread...");
+        if let Err(e) = ws_discovery_server.start() {
+            error!("WS-Discovery service error: {e}");
+        }
+    });
+
+    info!("Both services started successfully!");
+    info!("WS-Discovery: Listening on {}:3702", config.container_ip);
+    info!(
+        "ONVIF HTTP: Listening on {}:{}",
+        config.url_host(),
+        config.onvif_port
+    );
+
+    // Wait for the threads to finish; they run until shutdown is requested
+    if let Err(e) = onvif_handle.join() {
+        error!("ONVIF thread panicked: {e:?}");
+    }
+    // If the ONVIF service failed on its own, don't leave discovery advertising it
+    status.request_shutdown();
+    if let Err(e) = ws_handle.join() {
+        error!("WS-Discovery thread panicked: {e:?}");
+    }
+    if let Some(handle) = management_handle {
+        if let Err(e) = handle.join() {
+            error!("WS-Discovery management thread panicked: {e:?}");
+        }
+    }
+    if let Some(handle) = health_handle {
+        if let Err(e) = handle.join() {
+            error!("RTSP health monitor thread panicked: {e:?}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Builds the WS-Discovery device info advertising the ONVIF service on each of `hosts`
+fn discovery_device_info(
+    config: &Config,
+    endpoint_reference: &str,
+    hosts: &[IpAddr],
+) -> DeviceInfo {
+    // XAddrs is a space-separated list; IPv6 hosts need brackets in URLs
+    let scheme = onvif::http::url_scheme(config.tls_enabled());
+    let xaddrs: Vec<String> = hosts
+        .iter()
+        .map(|host| match host {
+            IpAddr::V4(ip) => format!(
+                "{scheme}://{}:{}/onvif/device_service",
+                ip, config.onvif_port
+            ),
+            IpAddr::V6(ip) => format!(
+                "{scheme}://[{}]:{}/onvif/device_service",
+                ip, config.onvif_port
+            ),
+        })
+        .collect();
+    let info = config.device_information();
+    DeviceInfo {
+        endpoint_reference: endpoint_reference.to_string(),
+        types: "tdn:NetworkVideoTransmitter".to_string(),
+        // Replaced by the shared scope list in WSDiscoveryServer::set_scopes
+        scopes: String::new(),
+        xaddrs: xaddrs.join(" "),
+        manufacturer: info.manufacturer,
+        model_name: info.model,
+        friendly_name: config.device_name.clone(),
+        firmware_version: info.firmware_version,
+        serial_number: info.serial_number,
+    }
+}
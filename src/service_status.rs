@@ -0,0 +1,233 @@
+use log::warn;
+use mio::unix::SourceFd;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::SigId;
+use std::io::Write;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Shared shutdown flag checked by the long-running service loops
+///
+/// Clones share the same flags, so one handle can be given to each service
+/// and another to the signal handlers. The WS-Discovery server also reports
+/// whether it is running, for the /health endpoint.
+#[derive(Debug, Clone)]
+pub struct ServiceStatus {
+    shutdown: Arc<AtomicBool>,
+    restart: Arc<AtomicBool>,
+    discovery_running: Arc<AtomicBool>,
+    /// Socket pair written to once shutdown is requested, so services can wait
+    /// for the read end to become readable instead of polling the flag
+    shutdown_wake: Arc<(UnixStream, UnixStream)>,
+}
+
+impl Default for ServiceStatus {
+    fn default() -> Self {
+        let (write, read) = UnixStream::pair().expect("failed to create the shutdown socket pair");
+        // Never block a shutdown request (or a signal handler) on a full buffer
+        write
+            .set_nonblocking(true)
+            .expect("failed to make the shutdown socket non-blocking");
+        Self {
+            shutdown: Arc::default(),
+            restart: Arc::default(),
+            discovery_running: Arc::default(),
+            shutdown_wake: Arc::new((write, read)),
+        }
+    }
+}
+
+impl ServiceStatus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks every service holding this status to stop
+    pub fn request_shutdown(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // The byte is never read, so the read end stays readable for every waiter
+        let _ = (&self.shutdown_wake.0).write(&[1]);
+    }
+
+    /// A descriptor that becomes readable once shutdown is requested
+    ///
+    /// Lets a service block in poll() on its own sockets and this one, rather
+    /// than waking up periodically to check `is_shutdown_requested`.
+    pub fn shutdown_fd(&self) -> BorrowedFd<'_> {
+        self.shutdown_wake.1.as_fd()
+    }
+
+    /// Returns true once SIGTERM/SIGINT arrived or `request_shutdown` was called
+    pub fn is_shutdown_requested(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    /// Blocks for up to `timeout`, returning true as soon as shutdown is requested
+    pub fn wait_for_shutdown(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let fd = self.shutdown_fd().as_raw_fd();
+        let poll = mio::Poll::new().and_then(|poll| {
+            poll.registry()
+                .register(&mut SourceFd(&fd), mio::Token(0), mio::Interest::READABLE)?;
+            Ok(poll)
+        });
+        let mut poll = match poll {
+            Ok(poll) => poll,
+            Err(e) => {
+                warn!("Failed to wait on the shutdown descriptor: {e}");
+                std::thread::sleep(timeout);
+                return self.is_shutdown_requested();
+            }
+        };
+
+        let mut events = mio::Events::with_capacity(1);
+        while !self.is_shutdown_requested() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return false;
+            }
+            if let Err(e) = poll.poll(&mut events, Some(remaining)) {
+                if e.kind() != std::io::ErrorKind::Interrupted {
+                    warn!("Failed to wait on the shutdown descriptor: {e}");
+                    std::thread::sleep(remaining);
+                }
+            }
+        }
+        true
+    }
+
+    /// Stops the services like `request_shutdown`, asking for them to be started again
+    pub fn request_restart(&self) {
+        self.restart.store(true, Ordering::SeqCst);
+        self.request_shutdown();
+    }
+
+    /// Returns true if the services stopped to be restarted (SystemReboot)
+    pub fn is_restart_requested(&self) -> bool {
+        self.restart.load(Ordering::SeqCst)
+    }
+
+    /// Records whether the WS-Discovery server is listening
+    pub fn set_discovery_running(&self, running: bool) {
+        self.discovery_running.store(running, Ordering::SeqCst);
+    }
+
+    /// Returns true while the WS-Discovery server is listening
+    pub fn is_discovery_running(&self) -> bool {
+        self.discovery_running.load(Ordering::SeqCst)
+    }
+}
+
+/// Signal handlers installed by `setup_signal_handlers`, removed again when dropped
+#[must_use = "the signal handlers are removed when this is dropped"]
+#[derive(Debug, Default)]
+pub struct SignalHandlers {
+    ids: Vec<SigId>,
+}
+
+impl Drop for SignalHandlers {
+    fn drop(&mut self) {
+        for id in self.ids.drain(..) {
+            signal_hook::low_level::unregister(id);
+        }
+    }
+}
+
+/// Requests shutdown on `status` when the process receives SIGTERM or SIGINT
+///
+/// The handlers stay installed until the returned `SignalHandlers` is dropped,
+/// so each service restart can replace them instead of piling up more.
+pub fn setup_signal_handlers(
+    status: &ServiceStatus,
+) -> Result<SignalHandlers, Box<dyn std::error::Error>> {
+    let mut handlers = SignalHandlers::default();
+    for signal in [SIGTERM, SIGINT] {
+        let id = signal_hook::flag::register(signal, status.shutdown.clone())
+            .map_err(|e| format!("Failed to register handler for signal {signal}: {e}"))?;
+        handlers.ids.push(id);
+        // Registered after the flag, so a woken service sees it set
+        let wake = status.shutdown_wake.0.try_clone()?;
+        let id = signal_hook::low_level::pipe::register(signal, wake)
+            .map_err(|e| format!("Failed to register wake-up for signal {signal}: {e}"))?;
+        handlers.ids.push(id);
+    }
+    Ok(handlers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clones_share_the_shutdown_flag() {
+        let status = ServiceStatus::new();
+        let service = status.clone();
+        assert!(!service.is_shutdown_requested());
+
+        status.request_shutdown();
+        assert!(service.is_shutdown_requested());
+
+        assert!(!status.is_restart_requested());
+        service.request_restart();
+        assert!(status.is_restart_requested());
+
+        assert!(!status.is_discovery_running());
+        service.set_discovery_running(true);
+        assert!(status.is_discovery_running());
+    }
+
+    #[test]
+    fn test_wait_for_shutdown_wakes_on_request() {
+        let status = ServiceStatus::new();
+        let started = Instant::now();
+        assert!(!status.wait_for_shutdown(Duration::from_millis(50)));
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        let requester = status.clone();
+        let request = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            requester.request_shutdown();
+        });
+        let started = Instant::now();
+        assert!(status.wait_for_shutdown(Duration::from_secs(10)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        request.join().unwrap();
+    }
+
+    #[test]
+    fn test_signal_requests_shutdown() {
+        let status = ServiceStatus::new();
+        let handlers = setup_signal_handlers(&status).unwrap();
+
+        signal_hook::low_level::raise(SIGINT).unwrap();
+        assert!(status.is_shutdown_requested());
+        assert!(is_readable(status.shutdown_fd()));
+
+        // A restart replaces the handlers; the old status no longer hears signals
+        let restarted = ServiceStatus::new();
+        let _restarted_handlers = setup_signal_handlers(&restarted).unwrap();
+        drop(handlers);
+        signal_hook::low_level::raise(SIGTERM).unwrap();
+        assert!(restarted.is_shutdown_requested());
+        assert!(!is_readable(status.shutdown_fd()));
+    }
+
+    /// Reads the wake-up byte, if there is one
+    fn is_readable(fd: BorrowedFd<'_>) -> bool {
+        use std::io::Read;
+        let mut stream = UnixStream::from(fd.try_clone_to_owned().unwrap());
+        stream.set_nonblocking(true).unwrap();
+        stream.read(&mut [0]).is_ok()
+    }
+
+    #[test]
+    fn test_shutdown_wakes_the_descriptor() {
+        let status = ServiceStatus::new();
+        assert!(!is_readable(status.shutdown_fd()));
+        status.clone().request_shutdown();
+        assert!(is_readable(status.shutdown_fd()));
+    }
+}
@@ -0,0 +1,266 @@
+use crate::config::Config;
+use crate::ffmpeg::spawn_error_message;
+use crate::service_status::ServiceStatus;
+use log::{info, warn};
+use std::process::{Child, Command, Stdio};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// How often the push process is checked and restarted once it has exited
+const PUSH_WATCHDOG_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Destination for the transcoded stream
+///
+/// In `Serve` mode the stream is published to the local RTSP server and clients
+/// pull it from there. In `Push` mode ffmpeg also sends the stream to an
+/// external RTSP/RTMP endpoint; clients are still pointed at the RTSP stream
+/// they can pull, since the endpoint may not be one an ONVIF client can read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamOutput {
+    /// Publish to the local RTSP server and let clients pull from it
+    Serve {
+        /// URL the transcoder publishes to
+        publish_url: String,
+        /// URL advertised to clients
+        pull_url: String,
+    },
+    /// Push the stream to an external RTSP or RTMP endpoint
+    Push {
+        /// External endpoint URL
+        url: String,
+        /// URL advertised to clients
+        pull_url: String,
+    },
+}
+
+impl StreamOutput {
+    /// Selects the output mode from the configuration
+    ///
+    /// `--output-url` switches to push mode; otherwise the local RTSP stream is served.
+    pub fn from_config(config: &Config) -> Self {
+        match &config.output_url {
+            Some(url) => StreamOutput::Push {
+                url: url.clone(),
+                pull_url: config.rtsp_stream_url.clone(),
+            },
+            None => StreamOutput::Serve {
+                publish_url: config.rtsp_stream_url.clone(),
+                pull_url: config.rtsp_stream_url.clone(),
+            },
+        }
+    }
+
+    /// Returns the URI clients should use in GetStreamUri
+    pub fn stream_uri(&self) -> &str {
+        match self {
+            StreamOutput::Serve { pull_url, .. } | StreamOutput::Push { pull_url, .. } => pull_url,
+        }
+    }
+
+    /// Returns the ffmpeg output arguments (format, transport and target URL)
+    pub fn ffmpeg_output_args(&self) -> Vec<String> {
+        let url = match self {
+            StreamOutput::Serve { publish_url, .. } => publish_url,
+            StreamOutput::Push { url, .. } => url,
+        };
+
+        let mut args = Vec::new();
+        if is_rtmp_url(url) {
+            args.extend(["-f", "flv"].map(String::from));
+        } else {
+            args.extend(["-f", "rtsp", "-rtsp_transport", "tcp"].map(String::from));
+        }
+        args.push(url.clone());
+        args
+    }
+}
+
+/// ffmpeg arguments copying `source_url` to the push-mode endpoint, `None` in serve mode
+pub fn push_args(output: &StreamOutput, source_url: &str) -> Option<Vec<String>> {
+    if !matches!(output, StreamOutput::Push { .. }) {
+        return None;
+    }
+    let mut args: Vec<String> = ["-rtsp_transport", "tcp", "-i", source_url, "-c", "copy"]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+    args.extend(output.ffmpeg_output_args());
+    Some(args)
+}
+
+/// In push mode, runs ffmpeg pushing the source to `--output-url` until
+/// shutdown, restarting it whenever it exits
+///
+/// Returns `None` in serve mode, where nothing is pushed.
+pub fn spawn_push(config: &Config, status: ServiceStatus) -> Option<JoinHandle<()>> {
+    let output = StreamOutput::from_config(config);
+    let args = push_args(&output, &config.rtsp_stream_url)?;
+    let program = config.ffmpeg_path.clone();
+    info!(
+        "Pushing the stream to {}",
+        config.output_url.as_deref().unwrap_or_default()
+    );
+    Some(std::thread::spawn(move || {
+        let mut child: Option<Child> = None;
+        while !status.is_shutdown_requested() {
+            let running = match child.as_mut().map(Child::try_wait) {
+                Some(Ok(None)) => true,
+                Some(Ok(Some(exit))) => {
+                    warn!("Stream push exited ({exit}) - restarting");
+                    false
+                }
+                Some(Err(e)) => {
+                    warn!("Stream push can't be checked ({e}) - restarting");
+                    false
+                }
+                None => false,
+            };
+            if !running {
+                child = spawn_ffmpeg(&program, &args);
+            }
+            std::thread::sleep(PUSH_WATCHDOG_INTERVAL);
+        }
+        info!("Shutdown requested - stopping the stream push");
+        if let Some(mut child) = child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }))
+}
+
+fn spawn_ffmpeg(program: &str, args: &[String]) -> Option<Child> {
+    match Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+    {
+        Ok(child) => Some(child),
+        Err(e) => {
+            warn!(
+                "Failed to start the stream push: {}",
+                spawn_error_message("ffmpeg", program, &e)
+            );
+            None
+        }
+    }
+}
+
+/// Returns the ffmpeg arguments that cap the transcoded frame rate
+///
+/// Empty when `--frame-rate` is unset, so the output follows the source.
+pub fn ffmpeg_frame_rate_args(config: &Config) -> Vec<String> {
+    match config.frame_rate {
+        Some(frame_rate) => vec!["-r".to_string(), frame_rate.to_string()],
+        None => Vec::new(),
+    }
+}
+
+/// Returns true if the URL uses one of the supported output schemes
+pub fn is_supported_output_url(url: &str) -> bool {
+    ["rtsp://", "rtsps://", "rtmp://", "rtmps://"]
+        .iter()
+        .any(|scheme| url.starts_with(scheme))
+}
+
+fn is_rtmp_url(url: &str) -> bool {
+    url.starts_with("rtmp://") || url.starts_with("rtmps://")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn test_serve_mode_args_and_uri() {
+        let config = Config::try_parse_from(["test", "-r", "rtsp://127.0.0.1:8554/cam"]).unwrap();
+        let output = StreamOutput::from_config(&config);
+
+        assert_eq!(output.stream_uri(), "rtsp://127.0.0.1:8554/cam");
+        assert_eq!(
+            output.ffmpeg_output_args(),
+            vec![
+                "-f",
+                "rtsp",
+                "-rtsp_transport",
+                "tcp",
+                "rtsp://127.0.0.1:8554/cam"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_push_mode_args_and_uri() {
+        let config = Config::try_parse_from([
+            "test",
+            "-r",
+            "rtsp://127.0.0.1:8554/cam",
+            "--output-url",
+            "rtmp://media.example.com/live/cam",
+        ])
+        .unwrap();
+        let output = StreamOutput::from_config(&config);
+
+        // Clients keep pulling the RTSP stream; the RTMP endpoint is only pushed to
+        assert_eq!(output.stream_uri(), "rtsp://127.0.0.1:8554/cam");
+        assert_eq!(
+            output.ffmpeg_output_args(),
+            vec!["-f", "flv", "rtmp://media.example.com/live/cam"]
+        );
+        assert_eq!(
+            push_args(&output, &config.rtsp_stream_url).unwrap(),
+            vec![
+                "-rtsp_transport",
+                "tcp",
+                "-i",
+                "rtsp://127.0.0.1:8554/cam",
+                "-c",
+                "copy",
+                "-f",
+                "flv",
+                "rtmp://media.example.com/live/cam"
+            ]
+        );
+
+        let serve = StreamOutput::from_config(&Config::try_parse_from(["test"]).unwrap());
+        assert_eq!(push_args(&serve, "rtsp://127.0.0.1:8554/cam"), None);
+    }
+
+    #[test]
+    fn test_push_runs_ffmpeg_until_shutdown() {
+        let config = Config::try_parse_from([
+            "test",
+            "--output-url",
+            "rtsp://media.example.com/live/cam",
+            "--ffmpeg-path",
+            "sleep",
+        ])
+        .unwrap();
+        let status = ServiceStatus::new();
+        let push = spawn_push(&config, status.clone()).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        status.request_shutdown();
+        push.join().unwrap();
+
+        let serve = Config::try_parse_from(["test"]).unwrap();
+        assert!(spawn_push(&serve, ServiceStatus::new()).is_none());
+    }
+
+    #[test]
+    fn test_frame_rate_args() {
+        let config = Config::try_parse_from(["test"]).unwrap();
+        assert!(ffmpeg_frame_rate_args(&config).is_empty());
+
+        let config = Config::try_parse_from(["test", "--frame-rate", "10"]).unwrap();
+        assert_eq!(ffmpeg_frame_rate_args(&config), vec!["-r", "10"]);
+    }
+
+    #[test]
+    fn test_is_supported_output_url() {
+        assert!(is_supported_output_url("rtsp://host/stream"));
+        assert!(is_supported_output_url("rtmps://host/live"));
+        assert!(!is_supported_output_url("http://host/stream"));
+    }
+}
@@ -0,0 +1,537 @@
+use crate::ffmpeg::{spawn_error_message, CommandRunner, SystemCommandRunner};
+use log::{info, warn};
+use serde::Deserialize;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a failed probe is remembered before the stream is probed again
+const PROBE_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+/// ffprobe socket timeout in microseconds
+const PROBE_TIMEOUT_US: &str = "5000000";
+/// ffprobe is killed if the whole probe takes longer than this
+const PROBE_DEADLINE: Duration = Duration::from_secs(20);
+
+/// Video parameters of the source stream
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamParams {
+    /// Frame width in pixels
+    pub width: u32,
+    /// Frame height in pixels
+    pub height: u32,
+    /// ffprobe codec name (e.g. "h264", "hevc")
+    pub codec: String,
+    /// Average frame rate in frames per second
+    pub frame_rate: f64,
+    /// ffprobe codec name of the first audio stream, if the source carries audio
+    pub audio_codec: Option<String>,
+    /// Sample rate of the first audio stream in Hz, when ffprobe reports one
+    pub audio_sample_rate: Option<u32>,
+}
+
+impl Default for StreamParams {
+    /// The parameters advertised when the stream can't be probed
+    fn default() -> Self {
+        Self {
+            width: 960,
+            height: 540,
+            codec: "h264".to_string(),
+            frame_rate: 15.0,
+            audio_codec: None,
+            audio_sample_rate: None,
+        }
+    }
+}
+
+/// Audio encodings ONVIF audio encoder configurations can describe
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioEncoding {
+    G711,
+    G726,
+    Aac,
+}
+
+impl AudioEncoding {
+    /// Maps an ffprobe audio codec name to an encoding
+    pub fn from_codec_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "pcm_mulaw" | "pcm_alaw" | "g711" => Some(AudioEncoding::G711),
+            "adpcm_g726" | "adpcm_g726le" | "g726" => Some(AudioEncoding::G726),
+            "aac" => Some(AudioEncoding::Aac),
+            _ => None,
+        }
+    }
+
+    /// Value of the ONVIF `Encoding` element
+    pub fn as_onvif(&self) -> &'static str {
+        match self {
+            AudioEncoding::G711 => "G711",
+            AudioEncoding::G726 => "G726",
+            AudioEncoding::Aac => "AAC",
+        }
+    }
+
+    /// Typical bitrate in kbit/s; ffprobe rarely reports one for RTSP sources
+    pub fn bitrate_kbps(&self) -> u32 {
+        match self {
+            AudioEncoding::G711 => 64,
+            AudioEncoding::G726 => 32,
+            AudioEncoding::Aac => 64,
+        }
+    }
+
+    /// Sample rate in Hz assumed when the probe doesn't report one
+    fn default_sample_rate(&self) -> u32 {
+        match self {
+            AudioEncoding::G711 | AudioEncoding::G726 => 8000,
+            AudioEncoding::Aac => 16000,
+        }
+    }
+}
+
+/// Video encodings advertised in ONVIF encoder configurations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoEncoding {
+    H264,
+    H265,
+    Jpeg,
+}
+
+impl VideoEncoding {
+    /// Maps a codec name (ffprobe or ONVIF spelling) to an encoding
+    pub fn from_codec_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "h264" | "avc" => Some(VideoEncoding::H264),
+            "hevc" | "h265" => Some(VideoEncoding::H265),
+            "mjpeg" | "jpeg" => Some(VideoEncoding::Jpeg),
+            _ => None,
+        }
+    }
+
+    /// Value of the ONVIF `Encoding` element
+    pub fn as_onvif(&self) -> &'static str {
+        match self {
+            VideoEncoding::H264 => "H264",
+            VideoEncoding::H265 => "H265",
+            VideoEncoding::Jpeg => "JPEG",
+        }
+    }
+
+    /// Whether the Media (ver10) service can describe the encoding; its schema has no H265
+    pub fn is_media1(&self) -> bool {
+        !matches!(self, VideoEncoding::H265)
+    }
+}
+
+impl StreamParams {
+    /// Frame rate rounded to a whole number for ONVIF `FrameRateLimit` fields
+    pub fn frame_rate_limit(&self) -> u32 {
+        self.frame_rate.round().max(1.0) as u32
+    }
+
+    /// Frame rate for the `xs:float` `Framerate` of a video source, e.g. `29.97`
+    ///
+    /// Unlike `frame_rate_limit` this isn't rounded, since NVRs time recordings by it.
+    pub fn source_frame_rate(&self) -> String {
+        if !self.frame_rate.is_finite() || self.frame_rate <= 0.0 {
+            return StreamParams::default().source_frame_rate();
+        }
+        let rate = format!("{:.2}", self.frame_rate);
+        rate.trim_end_matches('0').trim_end_matches('.').to_string()
+    }
+
+    /// Returns true if the source carries an audio stream
+    pub fn has_audio(&self) -> bool {
+        self.audio_codec.is_some()
+    }
+
+    /// Maps the ffprobe codec name to an ONVIF encoding, defaulting to H264 when unknown
+    pub fn encoding(&self) -> VideoEncoding {
+        VideoEncoding::from_codec_name(&self.codec).unwrap_or(VideoEncoding::H264)
+    }
+
+    /// Maps the audio codec to an ONVIF encoding; `None` without audio or for
+    /// codecs ONVIF can't describe (e.g. Opus)
+    pub fn audio_encoding(&self) -> Option<AudioEncoding> {
+        self.audio_codec
+            .as_deref()
+            .and_then(AudioEncoding::from_codec_name)
+    }
+
+    /// Audio sample rate in Hz, falling back to the encoding's usual rate
+    pub fn audio_sample_rate(&self) -> Option<u32> {
+        let encoding = self.audio_encoding()?;
+        Some(
+            self.audio_sample_rate
+                .unwrap_or_else(|| encoding.default_sample_rate()),
+        )
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct FfprobeOutput {
+    #[serde(default)]
+    streams: Vec<FfprobeStream>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FfprobeStream {
+    codec_type: Option<String>,
+    codec_name: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    avg_frame_rate: Option<String>,
+    r_frame_rate: Option<String>,
+    /// Audio streams only; ffprobe reports it as a string
+    sample_rate: Option<String>,
+}
+
+/// Probes the RTSP stream with the ffprobe at `ffprobe_path` and returns its video (and audio) parameters
+pub fn probe_stream_parameters(
+    runner: &impl CommandRunner,
+    ffprobe_path: &str,
+    rtsp_url: &str,
+) -> Result<StreamParams, Box<dyn std::error::Error>> {
+    info!("Probing stream parameters: {rtsp_url}");
+
+    let args = [
+        "-v",
+        "error",
+        "-rtsp_transport",
+        "tcp",
+        "-timeout",
+        PROBE_TIMEOUT_US,
+        "-show_entries",
+        "stream=codec_type,codec_name,width,height,avg_frame_rate,r_frame_rate,sample_rate",
+        "-of",
+        "json",
+        rtsp_url,
+    ]
+    .map(String::from);
+    let output = runner
+        .run(ffprobe_path, &args, PROBE_DEADLINE)
+        .map_err(|e| spawn_error_message("ffprobe", ffprobe_path, &e))?;
+
+    if !output.status.success() {
+        let error_msg = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("ffprobe failed: {}", error_msg.trim()).into());
+    }
+
+    parse_ffprobe_output(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Parses `ffprobe -of json` output for the first video stream and first audio stream
+pub fn parse_ffprobe_output(json: &str) -> Result<StreamParams, Box<dyn std::error::Error>> {
+    let output: FfprobeOutput =
+        serde_json::from_str(json).map_err(|e| format!("Invalid ffprobe output: {e}"))?;
+
+    let audio = output
+        .streams
+        .iter()
+        .find(|s| s.codec_type.as_deref() == Some("audio"));
+    let audio_codec = audio.map(|s| {
+        s.codec_name
+            .clone()
+            .unwrap_or_else(|| "unknown".to_string())
+    });
+    let audio_sample_rate = audio
+        .and_then(|s| s.sample_rate.as_deref())
+        .and_then(|rate| rate.trim().parse().ok());
+
+    let stream = output
+        .streams
+        .into_iter()
+        .find(|s| {
+            s.codec_type.as_deref() != Some("audio") && s.width.is_some() && s.height.is_some()
+        })
+        .ok_or("ffprobe output contains no video stream")?;
+
+    let defaults = StreamParams::default();
+    let frame_rate = stream
+        .avg_frame_rate
+        .as_deref()
+        .and_then(parse_frame_rate)
+        .or_else(|| stream.r_frame_rate.as_deref().and_then(parse_frame_rate))
+        .unwrap_or(defaults.frame_rate);
+
+    Ok(StreamParams {
+        width: stream.width.unwrap_or(defaults.width),
+        height: stream.height.unwrap_or(defaults.height),
+        codec: stream.codec_name.unwrap_or(defaults.codec),
+        frame_rate,
+        audio_codec,
+        audio_sample_rate,
+    })
+}
+
+/// Parses an ffprobe rational frame rate such as "30000/1001" or "25/1"
+fn parse_frame_rate(rate: &str) -> Option<f64> {
+    let (num, den) = match rate.split_once('/') {
+        Some((num, den)) => (
+            num.trim().parse::<f64>().ok()?,
+            den.trim().parse::<f64>().ok()?,
+        ),
+        None => (rate.trim().parse::<f64>().ok()?, 1.0),
+    };
+    if num <= 0.0 || den <= 0.0 {
+        return None;
+    }
+    Some(num / den)
+}
+
+/// Caches the probed stream parameters so ffprobe runs once, not per request
+///
+/// Probes run on a background thread, so a slow or unreachable source never
+/// holds up the requests asking for its parameters: they get the defaults
+/// until the first probe finishes. A successful probe is kept for the life of
+/// the service. A failed probe is retried after `PROBE_RETRY_INTERVAL`.
+#[derive(Debug, Default)]
+pub struct StreamParamsCache {
+    entry: Arc<Mutex<ProbeState>>,
+}
+
+#[derive(Debug, Default)]
+struct ProbeState {
+    cached: Option<CachedProbe>,
+    /// Whether a probe is running, so concurrent requests don't start another
+    probing: bool,
+}
+
+#[derive(Debug)]
+struct CachedProbe {
+    params: StreamParams,
+    probed: bool,
+    at: Instant,
+}
+
+impl StreamParamsCache {
+    /// Returns the cached parameters, starting a probe of the stream with
+    /// `ffprobe_path` in the background if none has succeeded yet
+    pub fn get_or_probe(&self, ffprobe_path: &str, rtsp_url: &str) -> StreamParams {
+        let ffprobe_path = ffprobe_path.to_string();
+        self.get_or_probe_with(rtsp_url, move |url| {
+            probe_stream_parameters(&SystemCommandRunner, &ffprobe_path, url)
+        })
+    }
+
+    fn get_or_probe_with<F>(&self, rtsp_url: &str, probe: F) -> StreamParams
+    where
+        F: FnOnce(&str) -> Result<StreamParams, Box<dyn std::error::Error>> + Send + 'static,
+    {
+        let mut state = lock(&self.entry);
+        let params = state
+            .cached
+            .as_ref()
+            .map(|cached| cached.params.clone())
+            .unwrap_or_default();
+        let stale = state
+            .cached
+            .as_ref()
+            .is_none_or(|cached| !cached.probed && cached.at.elapsed() >= PROBE_RETRY_INTERVAL);
+        if !stale || state.probing {
+            return params;
+        }
+        state.probing = true;
+        drop(state);
+
+        let entry = Arc::clone(&self.entry);
+        let rtsp_url = rtsp_url.to_string();
+        let spawned = thread::Builder::new()
+            .name("stream-probe".to_string())
+            .spawn(move || {
+                let cached = match probe(&rtsp_url) {
+                    Ok(params) => {
+                        info!(
+                            "Stream parameters: {}x{} {} @ {:.2} fps",
+                            params.width, params.height, params.codec, params.frame_rate
+                        );
+                        CachedProbe {
+                            params,
+                            probed: true,
+                            at: Instant::now(),
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Stream probe failed, using default parameters: {e}");
+                        CachedProbe {
+                            params: StreamParams::default(),
+                            probed: false,
+                            at: Instant::now(),
+                        }
+                    }
+                };
+                let mut state = lock(&entry);
+                state.cached = Some(cached);
+                state.probing = false;
+            });
+        if let Err(e) = spawned {
+            warn!("Failed to start stream probe: {e}");
+            lock(&self.entry).probing = false;
+        }
+        params
+    }
+}
+
+fn lock(entry: &Mutex<ProbeState>) -> MutexGuard<'_, ProbeState> {
+    match entry.lock() {
+        Ok(state) => state,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffmpeg::MockCommandRunner;
+
+    const HEVC_1080P: &str = r#"{
+    "programs": [],
+    "streams": [
+        {
+            "codec_name": "hevc",
+            "width": 1920,
+            "height": 1080,
+            "r_frame_rate": "25/1",
+            "avg_frame_rate": "25/1"
+        }
+    ]
+}"#;
+
+    #[test]
+    fn test_parse_ffprobe_output_hevc_1080p() {
+        let params = parse_ffprobe_output(HEVC_1080P).unwrap();
+        assert_eq!(params.width, 1920);
+        assert_eq!(params.height, 1080);
+        assert_eq!(params.codec, "hevc");
+        assert_eq!(params.frame_rate_limit(), 25);
+    }
+
+    #[test]
+    fn test_parse_ffprobe_output_with_audio() {
+        let json = r#"{
+    "streams": [
+        {"codec_type": "video", "codec_name": "h264", "width": 1280, "height": 720, "avg_frame_rate": "30/1"},
+        {"codec_type": "audio", "codec_name": "aac", "sample_rate": "48000"}
+    ]
+}"#;
+        let params = parse_ffprobe_output(json).unwrap();
+        assert_eq!(params.width, 1280);
+        assert_eq!(params.audio_codec.as_deref(), Some("aac"));
+        assert!(params.has_audio());
+        assert_eq!(params.audio_encoding(), Some(AudioEncoding::Aac));
+        assert_eq!(params.audio_sample_rate(), Some(48000));
+
+        assert!(!parse_ffprobe_output(HEVC_1080P).unwrap().has_audio());
+    }
+
+    #[test]
+    fn test_parse_ffprobe_output_without_video() {
+        assert!(parse_ffprobe_output(r#"{"streams": []}"#).is_err());
+        assert!(parse_ffprobe_output("not json").is_err());
+    }
+
+    #[test]
+    fn test_probe_runs_ffprobe_through_the_runner() {
+        let runner = MockCommandRunner::new([
+            MockCommandRunner::exited(0, HEVC_1080P.as_bytes(), b""),
+            MockCommandRunner::exited(1, b"", b"Connection refused\n"),
+        ]);
+
+        let params = probe_stream_parameters(&runner, "/opt/ffprobe", "rtsp://cam/main").unwrap();
+        assert_eq!(params.codec, "hevc");
+        let (program, args) = &runner.calls()[0];
+        assert_eq!(program, "/opt/ffprobe");
+        assert_eq!(args.last().map(String::as_str), Some("rtsp://cam/main"));
+
+        let error = probe_stream_parameters(&runner, "/opt/ffprobe", "rtsp://cam/main");
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "ffprobe failed: Connection refused"
+        );
+
+        // The mock has run out of results, so ffprobe isn't found
+        let error = probe_stream_parameters(&runner, "/opt/ffprobe", "rtsp://cam/main");
+        assert!(error
+            .unwrap_err()
+            .to_string()
+            .starts_with("ffprobe not found at '/opt/ffprobe'"));
+    }
+
+    #[test]
+    fn test_encoding_from_codec() {
+        let mut params = StreamParams::default();
+        assert_eq!(params.encoding(), VideoEncoding::H264);
+        params.codec = "hevc".to_string();
+        assert_eq!(params.encoding(), VideoEncoding::H265);
+        params.codec = "mjpeg".to_string();
+        assert_eq!(params.encoding(), VideoEncoding::Jpeg);
+        params.codec = "vp9".to_string();
+        assert_eq!(params.encoding(), VideoEncoding::H264);
+    }
+
+    #[test]
+    fn test_parse_frame_rate() {
+        assert_eq!(parse_frame_rate("25/1"), Some(25.0));
+        assert!((parse_frame_rate("30000/1001").unwrap() - 29.97).abs() < 0.01);
+        assert_eq!(parse_frame_rate("0/0"), None);
+        assert_eq!(parse_frame_rate("15"), Some(15.0));
+    }
+
+    /// Polls `cache` until its background probe has stored a result
+    fn wait_for_probe(cache: &StreamParamsCache) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while lock(&cache.entry).probing {
+            assert!(Instant::now() < deadline, "probe did not finish");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_cache_probes_once_and_falls_back() {
+        let cache = StreamParamsCache::default();
+        let params =
+            cache.get_or_probe_with("rtsp://camera/stream", |_| parse_ffprobe_output(HEVC_1080P));
+        // The first request gets the defaults while the probe runs
+        assert_eq!(params, StreamParams::default());
+        wait_for_probe(&cache);
+
+        // A cached success is reused without probing again
+        let params = cache.get_or_probe_with("rtsp://camera/stream", |_| {
+            panic!("stream should not be probed twice")
+        });
+        assert_eq!(params.width, 1920);
+        assert_eq!(params.height, 1080);
+
+        let failed = StreamParamsCache::default();
+        failed.get_or_probe_with("rtsp://camera/stream", |_| Err("offline".into()));
+        wait_for_probe(&failed);
+        let params = failed.get_or_probe_with("rtsp://camera/stream", |_| {
+            panic!("a failed probe should not be retried immediately")
+        });
+        assert_eq!(params, StreamParams::default());
+    }
+
+    #[test]
+    fn test_slow_probe_does_not_block_requests() {
+        let cache = StreamParamsCache::default();
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        cache.get_or_probe_with("rtsp://camera/stream", move |_| {
+            let _ = released.recv();
+            parse_ffprobe_output(HEVC_1080P)
+        });
+
+        // Requests made while the probe is stuck return at once, without
+        // starting a second probe
+        let started = Instant::now();
+        let params = cache.get_or_probe_with("rtsp://camera/stream", |_| {
+            panic!("only one probe should run at a time")
+        });
+        assert!(started.elapsed() < Duration::from_millis(100));
+        assert_eq!(params, StreamParams::default());
+
+        release.send(()).unwrap();
+        wait_for_probe(&cache);
+        let params = cache.get_or_probe("ffprobe", "rtsp://camera/stream");
+        assert_eq!(params.codec, "hevc");
+    }
+}
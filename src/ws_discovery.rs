@@ -1,15 +1,58 @@
-use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
-use uuid::Uuid;
+use crate::id_generator::{IdGenerator, RandomIdGenerator};
+use crate::onvif::soap::xml_escape;
+use crate::scopes::DeviceScopes;
+use crate::service_status::ServiceStatus;
+use log::{debug, error, info, warn};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-/// WS-Discovery multicast address and port
-const WS_DISCOVERY_MULTICAST_ADDR: &str = "239.255.255.250:3702";
+/// WS-Discovery port
+const WS_DISCOVERY_PORT: u16 = 3702;
+/// WS-Discovery IPv4 multicast group
+const WS_DISCOVERY_MULTICAST_V4: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+/// WS-Discovery IPv6 multicast group (link-local scope)
+const WS_DISCOVERY_MULTICAST_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xc);
+/// Default upper bound of the random delay before answering a multicast probe (APP_MAX_DELAY)
+const APP_MAX_DELAY: Duration = Duration::from_millis(500);
+/// Largest UDP payload; any datagram fits whole, so none is read truncated
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+/// Default interval between periodic Hello announcements
+const DEFAULT_HELLO_INTERVAL: Duration = Duration::from_secs(60);
 /// WS-Discovery namespace URI
 const WS_DISCOVERY_NAMESPACE: &str = "http://schemas.xmlsoap.org/ws/2005/04/discovery";
+/// `wsa:To` of messages sent to the multicast group (WS-Discovery 2005 and 1.1)
+const MULTICAST_DISCOVERY_TARGETS: [&str; 2] = [
+    "urn:schemas-xmlsoap-org:ws:2005:04:discovery",
+    "urn:docs-oasis-open-org:ws-dd:ns:discovery:2009:01",
+];
 /// WS-Addressing namespace URI
 const WS_ADDRESSING_NAMESPACE: &str = "http://www.w3.org/2005/08/addressing";
+/// Devices Profile for Web Services namespace, used by Windows network discovery
+const WS_DEVPROF_NAMESPACE: &str = "http://schemas.xmlsoap.org/ws/2006/02/devprof";
+/// Device type Windows probes for
+const WINDOWS_WSD_DEVICE_TYPE: &str = "wsdp:Device";
+
+/// Source of the current time for the periodic Hello schedule
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The system monotonic clock
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
 
 /// Device information for WS-Discovery announcements and responses
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct DeviceInfo {
     /// Unique endpoint reference for the device
     pub endpoint_reference: String,
@@ -40,11 +83,87 @@ pub struct DeviceInfo {
 ///
 /// This server handles multicast UDP communication for device discovery
 /// according to the WS-Discovery specification. It responds to probe requests
-/// and sends hello/bye announcements.
+/// and sends hello/bye announcements. A unicast server (see `new_unicast`)
+/// only answers probes sent directly to its address and never announces.
 pub struct WSDiscoveryServer {
     device_info: DeviceInfo,
     socket: UdpSocket,
     debug: bool,
+    /// Whether Hello/Bye are multicast (false for unicast listeners)
+    announce: bool,
+    windows_wsd: bool,
+    health_gate: HealthGate,
+    ids: Arc<dyn IdGenerator>,
+    status: ServiceStatus,
+    /// Group Hello and Bye are sent to
+    multicast_addr: SocketAddr,
+    /// Interfaces that joined the multicast group; Hello and Bye go out on each
+    interfaces: Vec<IpAddr>,
+    /// Responses are sent after a random delay up to this long
+    max_response_delay: Duration,
+    /// Interval between periodic Hello announcements
+    hello_interval: Duration,
+    clock: Arc<dyn Clock>,
+    /// Queue of the sender thread, while the main loop runs
+    responses: Option<Sender<PendingResponse>>,
+    /// Advertised MetadataVersion, bumped whenever the device info changes
+    metadata_version: AtomicU32,
+    /// Scope list managed through the device service, with the generation last advertised
+    scopes: Option<(DeviceScopes, u64)>,
+}
+
+/// A ProbeMatch or ResolveMatches waiting on the sender thread for its random delay
+struct PendingResponse {
+    message: String,
+    dest: SocketAddr,
+    kind: &'static str,
+    send_at: Instant,
+}
+
+/// Change in whether the device is advertised
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HealthTransition {
+    /// The source recovered: announce with Hello
+    Up,
+    /// The source went down: withdraw with Bye
+    Down,
+}
+
+/// Decides whether the device is advertised, following the RTSP source health when enabled
+#[derive(Debug)]
+struct HealthGate {
+    rtsp_healthy: Option<Arc<AtomicBool>>,
+    advertising: bool,
+}
+
+impl HealthGate {
+    fn new() -> Self {
+        Self {
+            rtsp_healthy: None,
+            advertising: true,
+        }
+    }
+
+    fn is_advertising(&self) -> bool {
+        self.advertising
+    }
+
+    /// Reads the health flag and returns the announcement needed if the advertised state changed
+    fn poll(&mut self) -> Option<HealthTransition> {
+        let healthy = self
+            .rtsp_healthy
+            .as_ref()
+            .is_none_or(|flag| flag.load(Ordering::SeqCst));
+        if healthy == self.advertising {
+            return None;
+        }
+        self.advertising = healthy;
+        Some(if healthy {
+            HealthTransition::Up
+        } else {
+            HealthTransition::Down
+        })
+    }
 }
 
 impl WSDiscoveryServer {
@@ -52,79 +171,295 @@ impl WSDiscoveryServer {
     ///
     /// # Arguments
     /// * `device_info` - Device information for announcements
-    /// * `interface_addr` - Local interface IP address to bind to
+    /// * `interfaces` - Local interface IP addresses to join the multicast group on;
+    ///   IPv6 addresses make the server use the IPv6 group ff02::c instead
     /// * `debug` - Enable verbose logging
     ///
     /// # Returns
     /// * `Result<Self, Box<dyn std::error::Error>>` - Server instance or error
     pub fn new(
         device_info: DeviceInfo,
-        interface_addr: &str,
+        interfaces: &[IpAddr],
         debug: bool,
     ) -> Result<Self, Box<dyn std::error::Error>> {
-        // Bind to 0.0.0.0:3702 to listen on all interfaces for multicast
-        let bind_addr = "0.0.0.0:3702";
-        let socket = UdpSocket::bind(bind_addr)
-            .map_err(|e| format!("Failed to bind to {bind_addr}: {e}"))?;
+        let first = *interfaces
+            .first()
+            .ok_or("At least one WS-Discovery interface is required")?;
+        if interfaces.iter().any(|ip| ip.is_ipv4() != first.is_ipv4()) {
+            return Err("WS-Discovery interfaces must all be IPv4 or all IPv6".into());
+        }
 
-        // Set socket options for better multicast handling
-        socket
-            .set_broadcast(true)
-            .map_err(|e| format!("Failed to set broadcast: {e}"))?;
+        // Bind to the wildcard address to listen on all interfaces for multicast
+        let (bind_addr, multicast_addr) = discovery_addresses(first);
+        let socket = bind_reusable(bind_addr)?;
+        info!("WS-Discovery server bound to {bind_addr}");
 
-        // Join the multicast group
-        let multicast_addr: Ipv4Addr = "239.255.255.250"
-            .parse()
-            .map_err(|e| format!("Invalid multicast address: {e}"))?;
-        let interface_addr: Ipv4Addr = interface_addr
-            .parse()
-            .map_err(|e| format!("Invalid interface address: {e}"))?;
+        let joined = match multicast_addr.ip() {
+            IpAddr::V4(group) => {
+                // Set socket options for better multicast handling
+                socket
+                    .set_broadcast(true)
+                    .map_err(|e| format!("Failed to set broadcast: {e}"))?;
+                join_multicast_groups(interfaces, |interface| match interface {
+                    IpAddr::V4(interface) => socket.join_multicast_v4(&group, &interface),
+                    IpAddr::V6(_) => unreachable!("interfaces share one address family"),
+                })?
+            }
+            IpAddr::V6(group) => {
+                // IPv6 groups are joined by interface index rather than address
+                join_multicast_groups(interfaces, |interface| match interface {
+                    IpAddr::V6(interface) => {
+                        socket.join_multicast_v6(&group, ipv6_interface_index(interface)?)
+                    }
+                    IpAddr::V4(_) => unreachable!("interfaces share one address family"),
+                })?
+            }
+        };
+        for interface in &joined {
+            info!("Joined multicast group {multicast_addr} on interface {interface}");
+        }
 
-        socket
-            .join_multicast_v4(&multicast_addr, &interface_addr)
-            .map_err(|e| format!("Failed to join multicast group: {e}"))?;
+        let mut server = Self::from_socket(device_info, socket, debug, true);
+        server.multicast_addr = multicast_addr;
+        server.interfaces = joined;
+        Ok(server)
+    }
 
-        println!("WS-Discovery server bound to {bind_addr}");
-        println!(
-            "Joined multicast group {WS_DISCOVERY_MULTICAST_ADDR} on interface {interface_addr}"
+    /// Creates a server that answers probes sent directly to `bind_addr`
+    ///
+    /// Used for a second network (e.g. a management VLAN) where the device should
+    /// be found with that network's XAddrs. It coexists with the multicast server
+    /// on port 3702 and doesn't send Hello or Bye.
+    pub fn new_unicast(
+        device_info: DeviceInfo,
+        bind_addr: SocketAddr,
+        debug: bool,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let socket = bind_reusable(bind_addr)?;
+        info!(
+            "WS-Discovery unicast listener bound to {}",
+            socket.local_addr()?
         );
+        Ok(Self::from_socket(device_info, socket, debug, false))
+    }
 
-        Ok(WSDiscoveryServer {
+    fn from_socket(
+        device_info: DeviceInfo,
+        socket: UdpSocket,
+        debug: bool,
+        announce: bool,
+    ) -> Self {
+        WSDiscoveryServer {
             device_info,
             socket,
             debug,
-        })
+            announce,
+            windows_wsd: false,
+            health_gate: HealthGate::new(),
+            ids: Arc::new(RandomIdGenerator),
+            status: ServiceStatus::new(),
+            multicast_addr: discovery_addresses(Ipv4Addr::UNSPECIFIED.into()).1,
+            interfaces: Vec::new(),
+            // Probes sent directly to a unicast listener needn't be spread out
+            max_response_delay: if announce {
+                APP_MAX_DELAY
+            } else {
+                Duration::ZERO
+            },
+            hello_interval: DEFAULT_HELLO_INTERVAL,
+            clock: Arc::new(SystemClock),
+            responses: None,
+            metadata_version: AtomicU32::new(1),
+            scopes: None,
+        }
+    }
+
+    /// Address the server's socket is bound to
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Enables answering Windows network discovery probes for `wsdp:Device`
+    ///
+    /// Windows Explorer only lists devices whose ProbeMatch carries the
+    /// Devices Profile device type, so this adds it to matching responses.
+    pub fn set_windows_wsd(&mut self, enabled: bool) {
+        self.windows_wsd = enabled;
+    }
+
+    /// Sets the upper bound of the random delay before ProbeMatch and ResolveMatches
+    ///
+    /// WS-Discovery asks devices to wait up to APP_MAX_DELAY before answering a
+    /// multicast request so replies from many devices don't arrive at once.
+    pub fn set_max_response_delay(&mut self, max_delay: Duration) {
+        self.max_response_delay = max_delay;
+    }
+
+    /// Sets how often Hello is repeated while the device is advertised
+    pub fn set_hello_interval(&mut self, interval: Duration) {
+        self.hello_interval = interval;
+    }
+
+    /// Uses `clock` to schedule periodic Hello, e.g. a manual clock in tests
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// MetadataVersion currently advertised in Hello, Bye and matches
+    pub fn metadata_version(&self) -> u32 {
+        self.metadata_version.load(Ordering::SeqCst)
+    }
+
+    /// Replaces the advertised device information
+    ///
+    /// A change bumps the MetadataVersion and is announced with a Hello, so
+    /// clients know to refresh what they cached about the device.
+    pub fn update_device_info(&mut self, device_info: DeviceInfo) {
+        if device_info == self.device_info {
+            return;
+        }
+        self.device_info = device_info;
+        let version = self.metadata_version.fetch_add(1, Ordering::SeqCst) + 1;
+        info!("WS-Discovery device info changed - MetadataVersion is now {version}");
+        if self.health_gate.is_advertising() {
+            if let Err(e) = self.send_hello() {
+                error!("Failed to send Hello message: {e}");
+            }
+        }
+    }
+
+    /// Advertises the scopes in `scopes`, following later SetScopes/AddScopes changes
+    pub fn set_scopes(&mut self, scopes: DeviceScopes) {
+        self.device_info.scopes = scopes.to_discovery_string();
+        self.scopes = Some((scopes.clone(), scopes.generation()));
+    }
+
+    /// Re-announces the device when its scopes were changed over SOAP
+    fn follow_scopes(&mut self) {
+        let Some((scopes, advertised)) = &self.scopes else {
+            return;
+        };
+        let generation = scopes.generation();
+        if generation == *advertised {
+            return;
+        }
+        let device_info = DeviceInfo {
+            scopes: scopes.to_discovery_string(),
+            ..self.device_info.clone()
+        };
+        self.scopes = Some((scopes.clone(), generation));
+        self.update_device_info(device_info);
+    }
+
+    /// Replaces the generator used for message IDs, e.g. with a seeded one in tests
+    pub fn set_id_generator(&mut self, ids: Arc<dyn IdGenerator>) {
+        self.ids = ids;
+    }
+
+    /// Stops the main loop (sending Bye) once shutdown is requested on `status`
+    pub fn set_service_status(&mut self, status: ServiceStatus) {
+        self.status = status;
+    }
+
+    /// Only advertises the device while `rtsp_healthy` is set
+    ///
+    /// While the flag is clear, probes go unanswered and Hello is withheld. A Bye
+    /// is sent when the flag clears and a Hello when it is set again, so NVRs
+    /// don't add a camera that can't stream.
+    pub fn set_rtsp_health(&mut self, rtsp_healthy: Arc<AtomicBool>) {
+        self.health_gate.advertising = rtsp_healthy.load(Ordering::SeqCst);
+        self.health_gate.rtsp_healthy = Some(rtsp_healthy);
+    }
+
+    /// Sends Hello or Bye when the RTSP source health changes
+    fn follow_rtsp_health(&mut self) {
+        match self.health_gate.poll() {
+            Some(HealthTransition::Up) => {
+                info!("RTSP source recovered - resuming WS-Discovery advertisement");
+                if let Err(e) = self.send_hello() {
+                    error!("Failed to send Hello message: {e}");
+                }
+            }
+            Some(HealthTransition::Down) => {
+                info!("RTSP source is down - withdrawing WS-Discovery advertisement");
+                if let Err(e) = self.send_bye() {
+                    error!("Failed to send Bye message: {e}");
+                }
+            }
+            None => {}
+        }
     }
 
     /// Starts the WS-Discovery server main loop
     ///
     /// This method sends a hello message and then listens for incoming probe requests.
-    /// It will continue running until an unrecoverable error occurs.
+    /// It runs until shutdown is requested, then sends Bye, or until an unrecoverable
+    /// error occurs. Answers are sent by a separate thread, and the periodic Hello
+    /// is checked after every message as well as on receive timeouts, so a steady
+    /// stream of probes can't hold announcements back.
     ///
     /// # Returns
     /// * `Result<(), Box<dyn std::error::Error>>` - Ok if server stops gracefully, Err on error
     pub fn start(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         // Send Hello message on startup
-        self.send_hello()?;
+        if self.health_gate.is_advertising() {
+            self.send_hello()?;
+        } else {
+            info!("RTSP source is down - withholding WS-Discovery Hello until it recovers");
+        }
 
-        println!("WS-Discovery server started, listening for probe requests...");
+        info!("WS-Discovery server started, listening for probe requests...");
 
         // Set a reasonable receive timeout to avoid blocking indefinitely
         let timeout = std::time::Duration::from_secs(1);
         self.socket.set_read_timeout(Some(timeout))?;
 
-        let mut buffer = [0; 4096];
+        // Only the announcing server reports discovery status; the unicast one shares the handle
+        if self.announce {
+            self.status.set_discovery_running(true);
+        }
+
+        let sender_socket = self
+            .socket
+            .try_clone()
+            .map_err(|e| format!("Failed to clone WS-Discovery socket: {e}"))?;
+        let (responses, queue) = mpsc::channel();
+        let debug = self.debug;
+        let sender = std::thread::spawn(move || run_response_sender(&sender_socket, &queue, debug));
+        self.responses = Some(responses);
+
+        let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
         let mut message_count = 0u32;
-        let mut last_hello = std::time::Instant::now();
-        let hello_interval = std::time::Duration::from_secs(60); // Send Hello every 60 seconds
+        let mut last_hello = self.clock.now();
+
+        while !self.status.is_shutdown_requested() {
+            self.follow_rtsp_health();
+            self.follow_scopes();
+
+            let received = self.socket.recv_from(&mut buffer);
+
+            // Check if we should send a periodic Hello message, whether or not a
+            // message arrived
+            let now = self.clock.now();
+            if self.health_gate.is_advertising()
+                && now.saturating_duration_since(last_hello) >= self.hello_interval
+            {
+                if let Err(e) = self.send_hello() {
+                    error!("Failed to send periodic Hello message: {e}");
+                }
+                last_hello = now;
+            }
 
-        loop {
-            match self.socket.recv_from(&mut buffer) {
+            match received {
                 Ok((size, src)) => {
                     message_count += 1;
-                    let message = String::from_utf8_lossy(&buffer[..size]);
-                    if let Err(e) = self.handle_message(&message, src) {
-                        eprintln!(
+                    // SOAP messages are UTF-8 XML; anything else is junk sent to the port
+                    let Ok(message) = std::str::from_utf8(&buffer[..size]) else {
+                        debug!("Ignoring {size}-byte WS-Discovery datagram from {src}: not UTF-8");
+                        continue;
+                    };
+                    if let Err(e) = self.handle_message(message, src) {
+                        error!(
                             "Error handling WS-Discovery message #{message_count} from {src}: {e}"
                         );
                     }
@@ -134,32 +469,45 @@ impl WSDiscoveryServer {
                     if e.kind() == std::io::ErrorKind::WouldBlock
                         || e.kind() == std::io::ErrorKind::TimedOut
                     {
-                        // Check if we should send a periodic Hello message
-                        if last_hello.elapsed() >= hello_interval {
-                            if let Err(e) = self.send_hello() {
-                                eprintln!("Failed to send periodic Hello message: {e}");
-                            }
-                            last_hello = std::time::Instant::now();
-                        }
-
                         // Periodic status update every ~10 seconds
-                        if message_count % 10 == 0 && message_count > 0 && self.debug {
-                            println!(
+                        if message_count.is_multiple_of(10) && message_count > 0 && self.debug {
+                            debug!(
                                 "WS-Discovery: Processed {message_count} messages, still listening..."
                             );
                         }
                         continue;
                     } else {
-                        eprintln!("Error receiving WS-Discovery message: {e}");
+                        error!("Error receiving WS-Discovery message: {e}");
                         break;
                     }
                 }
             }
         }
 
+        if self.status.is_shutdown_requested() {
+            info!("Shutdown requested - stopping WS-Discovery server");
+        }
+        if self.announce {
+            self.status.set_discovery_running(false);
+        }
+        // Closing the queue lets the sender thread finish the answers still pending
+        self.responses = None;
+        let _ = sender.join();
+        self.withdraw();
         Ok(())
     }
 
+    /// Sends Bye unless the device is already withdrawn, so it goes out only once
+    fn withdraw(&mut self) {
+        if !self.health_gate.is_advertising() {
+            return;
+        }
+        self.health_gate.advertising = false;
+        if let Err(e) = self.send_bye() {
+            error!("Failed to send Bye message: {e}");
+        }
+    }
+
     /// Handles incoming WS-Discovery messages
     ///
     /// # Arguments
@@ -177,18 +525,59 @@ impl WSDiscoveryServer {
         if self.debug {
             let first_line = message.lines().next().unwrap_or("").trim();
             if !first_line.is_empty() {
-                println!("Received WS-Discovery message from {src}: {first_line}");
+                debug!("Received WS-Discovery message from {src}: {first_line}");
             }
         }
 
-        if is_probe_request(message) {
+        if is_resolve_request(message) {
+            if !self.health_gate.is_advertising() {
+                if self.debug {
+                    debug!("Ignoring Resolve from {src}: RTSP source is down");
+                }
+            } else if resolve_target(message).as_deref()
+                == Some(self.device_info.endpoint_reference.as_str())
+            {
+                if self.debug {
+                    debug!("Detected Resolve request from {src}, sending ResolveMatches response");
+                }
+                let message_id = extract_message_id(message, self.ids.as_ref());
+                self.send_resolve_match(src, &message_id)?;
+            } else if self.debug {
+                debug!("Ignoring Resolve from {src}: it is for another endpoint");
+            }
+        } else if is_probe_request(message) && !self.health_gate.is_advertising() {
+            if self.debug {
+                debug!("Ignoring Probe from {src}: RTSP source is down");
+            }
+        } else if is_probe_request(message) {
+            let windows_info;
+            let device_info = if self.windows_wsd && is_windows_wsd_probe(message) {
+                if self.debug {
+                    debug!("Probe from {src} is a Windows WSD probe, answering as wsdp:Device");
+                }
+                windows_info = windows_device_info(&self.device_info);
+                &windows_info
+            } else {
+                &self.device_info
+            };
+
+            if !probe_matches_device(message, device_info) {
+                if self.debug {
+                    debug!("Ignoring Probe from {src}: its Types/Scopes don't match this device");
+                }
+                return Ok(());
+            }
+            let directed = is_directed_probe(message);
             if self.debug {
-                println!("Detected Probe request from {src}, sending ProbeMatch response");
+                debug!(
+                    "Detected {} Probe request from {src}, sending ProbeMatch response",
+                    if directed { "directed" } else { "multicast" }
+                );
             }
-            let message_id = extract_message_id(message);
-            self.send_probe_match(src, &message_id)?;
+            let message_id = extract_message_id(message, self.ids.as_ref());
+            self.send_probe_match(device_info, src, &message_id, directed)?;
         } else if self.debug {
-            println!("Received non-probe message from {src} (ignoring)");
+            debug!("Received non-probe message from {src} (ignoring)");
         }
 
         Ok(())
@@ -199,27 +588,28 @@ impl WSDiscoveryServer {
     /// # Returns
     /// * `Result<(), Box<dyn std::error::Error>>` - Ok if sent successfully, Err on error
     fn send_hello(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let message_id = generate_uuid();
-        let hello_message = create_hello_message(&self.device_info, &message_id);
+        if !self.announce {
+            return Ok(());
+        }
+        let message_id = self.ids.new_id();
+        let hello_message =
+            create_hello_message(&self.device_info, &message_id, self.metadata_version());
 
-        let multicast_addr: SocketAddr = WS_DISCOVERY_MULTICAST_ADDR
-            .parse()
-            .map_err(|e| format!("Invalid multicast address: {e}"))?;
+        let multicast_addr = self.multicast_addr;
 
-        println!("Sending Hello message to {multicast_addr}");
+        info!("Sending Hello message to {multicast_addr}");
         if self.debug {
-            println!("Hello message details:");
-            println!("  - Device Name: {}", self.device_info.friendly_name);
-            println!("  - Types: {}", self.device_info.types);
-            println!("  - XAddrs: {}", self.device_info.xaddrs);
-            println!("  - Scopes: {}", self.device_info.scopes);
+            debug!("Hello message details:");
+            debug!("  - Device Name: {}", self.device_info.friendly_name);
+            debug!("  - Types: {}", self.device_info.types);
+            debug!("  - XAddrs: {}", self.device_info.xaddrs);
+            debug!("  - Scopes: {}", self.device_info.scopes);
         }
 
-        self.socket
-            .send_to(hello_message.as_bytes(), multicast_addr)
+        self.send_multicast(&hello_message)
             .map_err(|e| format!("Failed to send Hello message: {e}"))?;
 
-        println!("Hello message sent successfully (MessageID: {message_id})");
+        info!("Hello message sent successfully (MessageID: {message_id})");
         Ok(())
     }
 
@@ -230,62 +620,310 @@ impl WSDiscoveryServer {
     /// # Returns
     /// * `Result<(), Box<dyn std::error::Error>>` - Ok if sent successfully, Err on error
     pub fn send_bye(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let message_id = generate_uuid();
-        let bye_message = create_bye_message(&self.device_info, &message_id);
-
-        let multicast_addr: SocketAddr = WS_DISCOVERY_MULTICAST_ADDR
-            .parse()
-            .map_err(|e| format!("Invalid multicast address: {e}"))?;
+        if !self.announce {
+            return Ok(());
+        }
+        let message_id = self.ids.new_id();
+        let bye_message =
+            create_bye_message(&self.device_info, &message_id, self.metadata_version());
 
-        self.socket
-            .send_to(bye_message.as_bytes(), multicast_addr)
+        self.send_multicast(&bye_message)
             .map_err(|e| format!("Failed to send Bye message: {e}"))?;
 
-        println!("Sent Bye message");
+        info!("Sent Bye message");
+        Ok(())
+    }
+
+    /// Sends `message` to the multicast group out of every joined interface
+    ///
+    /// Without choosing the interface the kernel would only announce on the one
+    /// holding the default route; for IPv6 link-local groups that may not be the
+    /// configured network at all. Probe replies are unicast and need no such care.
+    fn send_multicast(&self, message: &str) -> std::io::Result<()> {
+        if let [] | [IpAddr::V4(_)] = self.interfaces.as_slice() {
+            self.socket
+                .send_to(message.as_bytes(), self.multicast_addr)?;
+            return Ok(());
+        }
+        let socket = SockRef::from(&self.socket);
+        for interface in &self.interfaces {
+            match *interface {
+                IpAddr::V4(interface) => socket.set_multicast_if_v4(&interface)?,
+                IpAddr::V6(interface) => {
+                    socket.set_multicast_if_v6(ipv6_interface_index(interface)?)?
+                }
+            }
+            self.socket
+                .send_to(message.as_bytes(), self.multicast_addr)?;
+        }
         Ok(())
     }
 
-    /// Sends a ProbeMatch response to a specific client
+    /// Sends a ProbeMatch response describing `device_info` to a specific client
+    ///
+    /// The answer always goes to the probe's source address, so clients on
+    /// routed or NATed segments that probe the device directly are reached too.
     ///
     /// # Arguments
+    /// * `device_info` - Device information to answer with
     /// * `dest` - Destination address to send the response to
     /// * `relates_to` - MessageID from the original Probe request
+    /// * `directed` - The probe was sent to this device only, so it is answered without delay
     ///
     /// # Returns
     /// * `Result<(), Box<dyn std::error::Error>>` - Ok if sent successfully, Err on error
     fn send_probe_match(
         &self,
+        device_info: &DeviceInfo,
         dest: SocketAddr,
         relates_to: &str,
+        directed: bool,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let message_id = generate_uuid();
-        let probe_match = create_probe_match_message(&self.device_info, &message_id, relates_to);
+        let message_id = self.ids.new_id();
+        let probe_match = create_probe_match_message(
+            device_info,
+            &message_id,
+            relates_to,
+            self.metadata_version(),
+        );
 
         if self.debug {
-            println!("Sending ProbeMatch response to {dest}");
-            println!("  - RelatesTo: {relates_to}");
-            println!("  - MessageID: {message_id}");
-            println!("  - XAddrs: {}", self.device_info.xaddrs);
+            debug!("Sending ProbeMatch response to {dest}");
+            debug!("  - RelatesTo: {relates_to}");
+            debug!("  - MessageID: {message_id}");
+            debug!("  - Types: {}", device_info.types);
+            debug!("  - XAddrs: {}", device_info.xaddrs);
         }
 
-        self.socket
-            .send_to(probe_match.as_bytes(), dest)
-            .map_err(|e| format!("Failed to send ProbeMatch to {dest}: {e}"))?;
+        let max_delay = if directed {
+            Duration::ZERO
+        } else {
+            self.max_response_delay
+        };
+        self.send_response(probe_match, dest, "ProbeMatch", max_delay)
+    }
+
+    /// Sends a ResolveMatches response with the device's XAddrs to a specific client
+    ///
+    /// # Arguments
+    /// * `dest` - Destination address to send the response to
+    /// * `relates_to` - MessageID from the original Resolve request
+    ///
+    /// # Returns
+    /// * `Result<(), Box<dyn std::error::Error>>` - Ok if sent successfully, Err on error
+    fn send_resolve_match(
+        &self,
+        dest: SocketAddr,
+        relates_to: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let message_id = self.ids.new_id();
+        let resolve_match = create_resolve_match_message(
+            &self.device_info,
+            &message_id,
+            relates_to,
+            self.metadata_version(),
+        );
 
         if self.debug {
-            println!("ProbeMatch sent successfully to {dest}");
+            debug!("Sending ResolveMatches response to {dest}");
+            debug!("  - RelatesTo: {relates_to}");
+            debug!("  - MessageID: {message_id}");
+            debug!("  - XAddrs: {}", self.device_info.xaddrs);
+        }
+
+        self.send_response(
+            resolve_match,
+            dest,
+            "ResolveMatches",
+            self.max_response_delay,
+        )
+    }
+
+    /// Sends a response to `dest` after a random delay of up to `max_delay`
+    ///
+    /// While the main loop runs the response is queued to the sender thread, so
+    /// the receive loop keeps draining requests in the meantime; otherwise it is
+    /// sent straight away.
+    fn send_response(
+        &self,
+        message: String,
+        dest: SocketAddr,
+        kind: &'static str,
+        max_delay: Duration,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let delay = response_delay(max_delay, uuid::Uuid::new_v4().as_u64_pair().0);
+        let response = PendingResponse {
+            message,
+            dest,
+            kind,
+            send_at: Instant::now() + delay,
+        };
+        match &self.responses {
+            Some(responses) => responses
+                .send(response)
+                .map_err(|_| format!("WS-Discovery sender thread stopped; dropping {kind}").into()),
+            None => {
+                send_pending(&self.socket, &response, self.debug);
+                Ok(())
+            }
         }
-        Ok(())
+    }
+}
+
+/// Sends queued responses once their delay has passed, until the queue is closed
+/// and every pending response is out
+fn run_response_sender(socket: &UdpSocket, queue: &Receiver<PendingResponse>, debug: bool) {
+    let mut pending: Vec<PendingResponse> = Vec::new();
+    let mut closed = false;
+    loop {
+        let now = Instant::now();
+        pending.retain(|response| {
+            if response.send_at > now {
+                return true;
+            }
+            send_pending(socket, response, debug);
+            false
+        });
+
+        let next = pending.iter().map(|response| response.send_at).min();
+        match (next, closed) {
+            (None, true) => return,
+            (Some(next), true) => std::thread::sleep(next.saturating_duration_since(now)),
+            (next, false) => {
+                let received = match next {
+                    Some(next) => queue.recv_timeout(next.saturating_duration_since(now)),
+                    None => queue.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match received {
+                    Ok(response) => pending.push(response),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => closed = true,
+                }
+            }
+        }
+    }
+}
+
+fn send_pending(socket: &UdpSocket, response: &PendingResponse, debug: bool) {
+    let PendingResponse {
+        message,
+        dest,
+        kind,
+        ..
+    } = response;
+    match socket.send_to(message.as_bytes(), dest) {
+        Ok(_) => {
+            if debug {
+                debug!("{kind} sent successfully to {dest}");
+            }
+        }
+        Err(e) => error!("Failed to send {kind} to {dest}: {e}"),
     }
 }
 
 /// Implement Drop to send a Bye message when the server is dropped
 impl Drop for WSDiscoveryServer {
     fn drop(&mut self) {
-        if let Err(e) = self.send_bye() {
-            eprintln!("Failed to send Bye message on drop: {e}");
+        // No-op if Bye was already sent on shutdown or because the source went down
+        self.withdraw();
+    }
+}
+
+/// Picks the delay before a response, spread uniformly from zero to `max_delay` by `random`
+fn response_delay(max_delay: Duration, random: u64) -> Duration {
+    let max_millis = u64::try_from(max_delay.as_millis()).unwrap_or(u64::MAX - 1);
+    Duration::from_millis(random % (max_millis + 1))
+}
+
+/// Returns the wildcard address to bind and the multicast group to join for an interface
+///
+/// The address family follows the interface: IPv4 uses 239.255.255.250, IPv6 ff02::c.
+fn discovery_addresses(interface_addr: IpAddr) -> (SocketAddr, SocketAddr) {
+    match interface_addr {
+        IpAddr::V4(_) => (
+            SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), WS_DISCOVERY_PORT),
+            SocketAddr::new(WS_DISCOVERY_MULTICAST_V4.into(), WS_DISCOVERY_PORT),
+        ),
+        IpAddr::V6(_) => (
+            SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), WS_DISCOVERY_PORT),
+            SocketAddr::new(WS_DISCOVERY_MULTICAST_V6.into(), WS_DISCOVERY_PORT),
+        ),
+    }
+}
+
+/// Returns the index of the interface holding `address`, for IPv6 multicast options
+///
+/// The unspecified address gives 0, which lets the kernel pick the interface.
+fn ipv6_interface_index(address: Ipv6Addr) -> std::io::Result<u32> {
+    if address.is_unspecified() {
+        return Ok(0);
+    }
+    let table = std::fs::read_to_string("/proc/net/if_inet6")?;
+    find_interface_index(&table, address).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("no interface has the address {address}"),
+        )
+    })
+}
+
+/// Looks `address` up in the contents of /proc/net/if_inet6
+///
+/// Each line holds the address as 32 hex digits, then the interface index in hex.
+fn find_interface_index(table: &str, address: Ipv6Addr) -> Option<u32> {
+    let wanted: String = address
+        .octets()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    table.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let (hex_address, index) = (fields.next()?, fields.next()?);
+        if !hex_address.eq_ignore_ascii_case(&wanted) {
+            return None;
+        }
+        u32::from_str_radix(index, 16).ok()
+    })
+}
+
+/// Calls `join` for every interface and returns the ones that joined the group
+///
+/// An interface that fails to join is reported and skipped so discovery keeps
+/// working on the others; it's only an error when none of them joined.
+fn join_multicast_groups(
+    interfaces: &[IpAddr],
+    mut join: impl FnMut(IpAddr) -> std::io::Result<()>,
+) -> Result<Vec<IpAddr>, Box<dyn std::error::Error>> {
+    let mut joined = Vec::new();
+    for &interface in interfaces {
+        match join(interface) {
+            Ok(()) => joined.push(interface),
+            Err(e) => warn!("Failed to join multicast group on interface {interface}: {e}"),
         }
     }
+    if joined.is_empty() {
+        return Err("Failed to join the WS-Discovery multicast group on any interface".into());
+    }
+    Ok(joined)
+}
+
+/// Binds a UDP socket with SO_REUSEADDR so the multicast and unicast listeners can share port 3702
+fn bind_reusable(addr: SocketAddr) -> Result<UdpSocket, Box<dyn std::error::Error>> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))
+        .map_err(|e| format!("Failed to create socket for {addr}: {e}"))?;
+    socket
+        .set_reuse_address(true)
+        .map_err(|e| format!("Failed to set SO_REUSEADDR on {addr}: {e}"))?;
+    if addr.is_ipv6() {
+        // Keep [::]:3702 from also claiming IPv4 traffic
+        socket
+            .set_only_v6(true)
+            .map_err(|e| format!("Failed to set IPV6_V6ONLY on {addr}: {e}"))?;
+    }
+    socket
+        .bind(&addr.into())
+        .map_err(|e| format!("Failed to bind to {addr}: {e}"))?;
+    Ok(socket.into())
 }
 
 // --- Helper functions (pure logic, testable) ---
@@ -306,7 +944,125 @@ fn is_probe_request(message: &str) -> bool {
     is_probe_request || is_onvif_probe
 }
 
-fn extract_message_id(message: &str) -> String {
+/// Returns true for a directed Probe, sent straight to this device rather than to the group
+///
+/// Directed probes address the device itself in `wsa:To`; multicast ones carry
+/// the discovery URN, and probes without `wsa:To` are treated as multicast.
+fn is_directed_probe(message: &str) -> bool {
+    extract_element_text(message, "To")
+        .is_some_and(|to| !to.is_empty() && !MULTICAST_DISCOVERY_TARGETS.contains(&to.as_str()))
+}
+
+/// Returns true for a Resolve request, sent by clients to look up a known endpoint's XAddrs
+fn is_resolve_request(message: &str) -> bool {
+    extract_element_text(message, "Resolve").is_some()
+}
+
+/// Returns the endpoint reference address a Resolve request asks for
+fn resolve_target(message: &str) -> Option<String> {
+    let resolve_start = message
+        .find(":Resolve")
+        .or_else(|| message.find("<Resolve"))?;
+    extract_element_text(&message[resolve_start..], "Address")
+}
+
+/// Returns true if the device satisfies the Probe's Types and Scopes filters
+///
+/// Every requested type must be one of the device's types, compared by local
+/// name since prefixes are chosen by the client. Every requested scope must match
+/// one of the device's scopes by the default RFC 3986 rule: equal, or a prefix
+/// ending at a `/` segment boundary. Missing or empty filters match any device.
+fn probe_matches_device(message: &str, device_info: &DeviceInfo) -> bool {
+    let local_name = |qname: &str| qname.rsplit(':').next().unwrap_or(qname).to_string();
+    let types_match = extract_element_text(message, "Types").is_none_or(|types| {
+        types.split_whitespace().all(|requested| {
+            device_info
+                .types
+                .split_whitespace()
+                .any(|own| local_name(own) == local_name(requested))
+        })
+    });
+    let scopes_match = extract_element_text(message, "Scopes").is_none_or(|scopes| {
+        scopes.split_whitespace().all(|requested| {
+            let requested = requested.trim_end_matches('/');
+            device_info.scopes.split_whitespace().any(|own| {
+                own == requested
+                    || own
+                        .strip_prefix(requested)
+                        .is_some_and(|rest| rest.starts_with('/'))
+            })
+        })
+    });
+    types_match && scopes_match
+}
+
+/// Returns true for a probe from Windows network discovery (`wsdp:Device` type)
+fn is_windows_wsd_probe(message: &str) -> bool {
+    if !message.contains(WS_DEVPROF_NAMESPACE) {
+        return false;
+    }
+    extract_element_text(message, "Types")
+        .map(|types| {
+            types
+                .split_whitespace()
+                .any(|t| t.rsplit(':').next() == Some("Device"))
+        })
+        .unwrap_or(false)
+}
+
+/// Builds the device information advertised to Windows network discovery
+///
+/// Adds the `wsdp:Device` type and a name scope with spaces escaped so the
+/// friendly name survives the space-separated scope list.
+fn windows_device_info(device_info: &DeviceInfo) -> DeviceInfo {
+    let mut windows_info = device_info.clone();
+    if !windows_info
+        .types
+        .split_whitespace()
+        .any(|t| t == WINDOWS_WSD_DEVICE_TYPE)
+    {
+        windows_info.types = format!("{WINDOWS_WSD_DEVICE_TYPE} {}", windows_info.types);
+    }
+
+    let name_scope = format!(
+        "onvif://www.onvif.org/name/{}",
+        windows_info.friendly_name.replace(' ', "%20")
+    );
+    if !windows_info
+        .scopes
+        .split_whitespace()
+        .any(|s| s == name_scope)
+    {
+        windows_info.scopes = format!("{} {name_scope}", windows_info.scopes);
+    }
+    windows_info
+}
+
+/// Returns the trimmed text of the first element with the given local name
+fn extract_element_text(message: &str, local_name: &str) -> Option<String> {
+    let mut search_start = 0;
+    while let Some(pos) = message[search_start..].find('<') {
+        let tag_start = search_start + pos + 1;
+        let tag = &message[tag_start..];
+        let name_end = tag
+            .find(|c: char| c.is_whitespace() || c == '/' || c == '>')
+            .unwrap_or(tag.len());
+        let name = &tag[..name_end];
+
+        if name.rsplit(':').next() == Some(local_name) {
+            let content_start = tag_start + tag.find('>')? + 1;
+            if message[..content_start].ends_with("/>") {
+                return Some(String::new());
+            }
+            let content_end = content_start + message[content_start..].find('<')?;
+            return Some(message[content_start..content_end].trim().to_string());
+        }
+        search_start = tag_start;
+    }
+    None
+}
+
+fn extract_message_id(message: &str, ids: &dyn IdGenerator) -> String {
     // List of possible MessageID patterns to try
     let patterns = [
         ("<a:MessageID>", "</a:MessageID>"),
@@ -324,8 +1080,8 @@ fn extract_message_id(message: &str) -> String {
                 let message_id = message[id_start..id_end].trim();
 
                 // Clean up the message ID - remove urn:uuid: prefix if present
-                if message_id.starts_with("urn:uuid:") {
-                    return message_id[9..].to_string();
+                if let Some(stripped) = message_id.strip_prefix("urn:uuid:") {
+                    return stripped.to_string();
                 } else if !message_id.is_empty() {
                     return message_id.to_string();
                 }
@@ -334,11 +1090,15 @@ fn extract_message_id(message: &str) -> String {
     }
 
     // Fallback to generating a new UUID
-    println!("Could not extract MessageID from probe request, generating new one");
-    generate_uuid()
+    debug!("Could not extract MessageID from probe request, generating new one");
+    ids.new_id()
 }
 
-fn create_hello_message(device_info: &DeviceInfo, message_id: &str) -> String {
+fn create_hello_message(
+    device_info: &DeviceInfo,
+    message_id: &str,
+    metadata_version: u32,
+) -> String {
     format!(
         r#"<?xml version="1.0" encoding="utf-8"?>
 <soap:Envelope xmlns:soap="http://www.w3.org/2003/05/soap-envelope" xmlns:wsa="{}" xmlns:wsd="{}">
@@ -355,21 +1115,22 @@ fn create_hello_message(device_info: &DeviceInfo, message_id: &str) -> String {
 <wsd:Types>{}</wsd:Types>
 <wsd:Scopes>{}</wsd:Scopes>
 <wsd:XAddrs>{}</wsd:XAddrs>
-<wsd:MetadataVersion>1</wsd:MetadataVersion>
+<wsd:MetadataVersion>{}</wsd:MetadataVersion>
 </wsd:Hello>
 </soap:Body>
 </soap:Envelope>"#,
         WS_ADDRESSING_NAMESPACE,
         WS_DISCOVERY_NAMESPACE,
         message_id,
-        device_info.endpoint_reference,
-        device_info.types,
-        device_info.scopes,
-        device_info.xaddrs
+        xml_escape(&device_info.endpoint_reference),
+        xml_escape(&device_info.types),
+        xml_escape(&device_info.scopes),
+        xml_escape(&device_info.xaddrs),
+        metadata_version
     )
 }
 
-fn create_bye_message(device_info: &DeviceInfo, message_id: &str) -> String {
+fn create_bye_message(device_info: &DeviceInfo, message_id: &str, metadata_version: u32) -> String {
     format!(
         r#"<?xml version="1.0" encoding="utf-8"?>
 <soap:Envelope xmlns:soap="http://www.w3.org/2003/05/soap-envelope" xmlns:wsa="{}" xmlns:wsd="{}">
@@ -386,17 +1147,18 @@ fn create_bye_message(device_info: &DeviceInfo, message_id: &str) -> String {
 <wsd:Types>{}</wsd:Types>
 <wsd:Scopes>{}</wsd:Scopes>
 <wsd:XAddrs>{}</wsd:XAddrs>
-<wsd:MetadataVersion>1</wsd:MetadataVersion>
+<wsd:MetadataVersion>{}</wsd:MetadataVersion>
 </wsd:Bye>
 </soap:Body>
 </soap:Envelope>"#,
         WS_ADDRESSING_NAMESPACE,
         WS_DISCOVERY_NAMESPACE,
         message_id,
-        device_info.endpoint_reference,
-        device_info.types,
-        device_info.scopes,
-        device_info.xaddrs
+        xml_escape(&device_info.endpoint_reference),
+        xml_escape(&device_info.types),
+        xml_escape(&device_info.scopes),
+        xml_escape(&device_info.xaddrs),
+        metadata_version
     )
 }
 
@@ -404,10 +1166,11 @@ fn create_probe_match_message(
     device_info: &DeviceInfo,
     message_id: &str,
     relates_to: &str,
+    metadata_version: u32,
 ) -> String {
     format!(
         r#"<?xml version="1.0" encoding="utf-8"?>
-<soap:Envelope xmlns:soap="http://www.w3.org/2003/05/soap-envelope" xmlns:wsa="{}" xmlns:wsd="{}">
+<soap:Envelope xmlns:soap="http://www.w3.org/2003/05/soap-envelope" xmlns:wsa="{}" xmlns:wsd="{}" xmlns:wsdp="{}">
 <soap:Header>
 <wsa:Action>http://schemas.xmlsoap.org/ws/2005/04/discovery/ProbeMatches</wsa:Action>
 <wsa:MessageID>urn:uuid:{}</wsa:MessageID>
@@ -423,36 +1186,76 @@ fn create_probe_match_message(
 <wsd:Types>{}</wsd:Types>
 <wsd:Scopes>{}</wsd:Scopes>
 <wsd:XAddrs>{}</wsd:XAddrs>
-<wsd:MetadataVersion>1</wsd:MetadataVersion>
+<wsd:MetadataVersion>{}</wsd:MetadataVersion>
 </wsd:ProbeMatch>
 </wsd:ProbeMatches>
 </soap:Body>
 </soap:Envelope>"#,
         WS_ADDRESSING_NAMESPACE,
         WS_DISCOVERY_NAMESPACE,
+        WS_DEVPROF_NAMESPACE,
         message_id,
-        relates_to,
-        device_info.endpoint_reference,
-        device_info.types,
-        device_info.scopes,
-        device_info.xaddrs
+        xml_escape(relates_to),
+        xml_escape(&device_info.endpoint_reference),
+        xml_escape(&device_info.types),
+        xml_escape(&device_info.scopes),
+        xml_escape(&device_info.xaddrs),
+        metadata_version
     )
 }
 
-fn generate_uuid() -> String {
-    Uuid::new_v4().to_string()
+fn create_resolve_match_message(
+    device_info: &DeviceInfo,
+    message_id: &str,
+    relates_to: &str,
+    metadata_version: u32,
+) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<soap:Envelope xmlns:soap="http://www.w3.org/2003/05/soap-envelope" xmlns:wsa="{}" xmlns:wsd="{}">
+<soap:Header>
+<wsa:Action>http://schemas.xmlsoap.org/ws/2005/04/discovery/ResolveMatches</wsa:Action>
+<wsa:MessageID>urn:uuid:{}</wsa:MessageID>
+<wsa:RelatesTo>{}</wsa:RelatesTo>
+<wsa:To>http://www.w3.org/2005/08/addressing/anonymous</wsa:To>
+</soap:Header>
+<soap:Body>
+<wsd:ResolveMatches>
+<wsd:ResolveMatch>
+<wsa:EndpointReference>
+<wsa:Address>{}</wsa:Address>
+</wsa:EndpointReference>
+<wsd:Types>{}</wsd:Types>
+<wsd:Scopes>{}</wsd:Scopes>
+<wsd:XAddrs>{}</wsd:XAddrs>
+<wsd:MetadataVersion>{}</wsd:MetadataVersion>
+</wsd:ResolveMatch>
+</wsd:ResolveMatches>
+</soap:Body>
+</soap:Envelope>"#,
+        WS_ADDRESSING_NAMESPACE,
+        WS_DISCOVERY_NAMESPACE,
+        message_id,
+        xml_escape(relates_to),
+        xml_escape(&device_info.endpoint_reference),
+        xml_escape(&device_info.types),
+        xml_escape(&device_info.scopes),
+        xml_escape(&device_info.xaddrs),
+        metadata_version
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::id_generator::SeededIdGenerator;
 
     #[test]
     fn test_is_probe_request() {
+        // Without the discovery namespace the ONVIF type alone is enough to match
         let probe_msg = r#"<soap:Envelope><soap:Body><d:Probe><d:Types>tdn:NetworkVideoTransmitter</d:Types></d:Probe></soap:Body></soap:Envelope>"#;
-        // Note: The simple contains check might fail if namespaces aren't exactly as expected in the constant,
-        // but the function checks for "Probe" and "Types" so it should pass.
-        // Let's make a more realistic probe message that matches the logic
+        assert!(is_probe_request(probe_msg));
+
         let valid_probe = format!(
             r#"<soap:Envelope xmlns:d="{}"><soap:Body><d:Probe><d:Types>tdn:NetworkVideoTransmitter</d:Types></d:Probe></soap:Body></soap:Envelope>"#,
             WS_DISCOVERY_NAMESPACE
@@ -463,15 +1266,90 @@ mod tests {
         assert!(!is_probe_request(non_probe));
     }
 
+    #[test]
+    fn test_probe_types_filter() {
+        let device_info = device_info_with_xaddrs("http://127.0.0.1:8080/onvif/device_service");
+        let probe = |filter: &str| {
+            format!(
+                r#"<soap:Envelope xmlns:d="{WS_DISCOVERY_NAMESPACE}"><soap:Body><d:Probe>{filter}</d:Probe></soap:Body></soap:Envelope>"#
+            )
+        };
+
+        // Matching types, whatever prefix the client picked, and no filter at all
+        assert!(probe_matches_device(
+            &probe("<d:Types>dn:NetworkVideoTransmitter</d:Types>"),
+            &device_info
+        ));
+        assert!(probe_matches_device(
+            &probe("<d:Types></d:Types>"),
+            &device_info
+        ));
+        assert!(probe_matches_device(&probe(""), &device_info));
+
+        // A client looking for a display isn't answered
+        assert!(!probe_matches_device(
+            &probe("<d:Types>dn:NetworkVideoDisplay</d:Types>"),
+            &device_info
+        ));
+        assert!(!probe_matches_device(
+            &probe("<d:Types>dn:NetworkVideoTransmitter dn:NetworkVideoDisplay</d:Types>"),
+            &device_info
+        ));
+    }
+
+    #[test]
+    fn test_probe_scopes_filter() {
+        let mut device_info = device_info_with_xaddrs("http://127.0.0.1:8080/onvif/device_service");
+        device_info.scopes =
+            "onvif://www.onvif.org/type/NetworkVideoTransmitter onvif://www.onvif.org/name/Cam"
+                .to_string();
+        let probe = |scopes: &str| format!("<d:Probe><d:Scopes>{scopes}</d:Scopes></d:Probe>");
+
+        assert!(probe_matches_device(
+            &probe("onvif://www.onvif.org/name/Cam"),
+            &device_info
+        ));
+        assert!(probe_matches_device(
+            &probe("onvif://www.onvif.org/type"),
+            &device_info
+        ));
+        assert!(probe_matches_device(
+            &probe("onvif://www.onvif.org/type/"),
+            &device_info
+        ));
+        // Prefixes only match whole path segments
+        assert!(!probe_matches_device(
+            &probe("onvif://www.onvif.org/name/Ca"),
+            &device_info
+        ));
+        assert!(!probe_matches_device(
+            &probe("onvif://www.onvif.org/location/Garage"),
+            &device_info
+        ));
+    }
+
     #[test]
     fn test_extract_message_id() {
         let msg_with_id =
             r#"<soap:Header><wsa:MessageID>urn:uuid:12345-67890</wsa:MessageID></soap:Header>"#;
-        assert_eq!(extract_message_id(msg_with_id), "12345-67890");
+        assert_eq!(
+            extract_message_id(msg_with_id, &RandomIdGenerator),
+            "12345-67890"
+        );
 
         let msg_without_id = r#"<soap:Header><wsa:To>somewhere</wsa:To></soap:Header>"#;
         // Should generate a new UUID (length 36)
-        assert_eq!(extract_message_id(msg_without_id).len(), 36);
+        assert_eq!(
+            extract_message_id(msg_without_id, &RandomIdGenerator).len(),
+            36
+        );
+
+        // With a seeded generator the fallback is reproducible
+        let expected = SeededIdGenerator::new(3).new_id();
+        assert_eq!(
+            extract_message_id(msg_without_id, &SeededIdGenerator::new(3)),
+            expected
+        );
     }
 
     #[test]
@@ -488,7 +1366,7 @@ mod tests {
             serial_number: "12345".to_string(),
         };
 
-        let hello = create_hello_message(&device_info, "test-message-id");
+        let hello = create_hello_message(&device_info, "test-message-id", 1);
         assert!(hello.contains("Hello"));
         assert!(hello.contains("urn:uuid:test-message-id"));
         assert!(hello.contains("urn:uuid:test-endpoint"));
@@ -509,7 +1387,7 @@ mod tests {
             serial_number: "12345".to_string(),
         };
 
-        let bye = create_bye_message(&device_info, "test-message-id");
+        let bye = create_bye_message(&device_info, "test-message-id", 1);
         assert!(bye.contains("Bye"));
         assert!(bye.contains("urn:uuid:test-message-id"));
         assert!(bye.contains("urn:uuid:test-endpoint"));
@@ -530,17 +1408,513 @@ mod tests {
         };
 
         let probe_match =
-            create_probe_match_message(&device_info, "test-message-id", "relates-to-id");
+            create_probe_match_message(&device_info, "test-message-id", "relates-to-id", 1);
         assert!(probe_match.contains("ProbeMatches"));
         assert!(probe_match.contains("urn:uuid:test-message-id"));
         assert!(probe_match.contains("relates-to-id"));
         assert!(probe_match.contains("urn:uuid:test-endpoint"));
     }
 
+    #[test]
+    fn test_resolve_request_and_match() {
+        let resolve = format!(
+            r#"<soap:Envelope xmlns:d="{WS_DISCOVERY_NAMESPACE}"><soap:Header><wsa:Action>http://schemas.xmlsoap.org/ws/2005/04/discovery/Resolve</wsa:Action><wsa:MessageID>urn:uuid:resolve-1</wsa:MessageID></soap:Header><soap:Body><d:Resolve><wsa:EndpointReference><wsa:Address>urn:uuid:test-endpoint</wsa:Address></wsa:EndpointReference></d:Resolve></soap:Body></soap:Envelope>"#
+        );
+        assert!(is_resolve_request(&resolve));
+        assert!(!is_probe_request(&resolve));
+        assert_eq!(
+            resolve_target(&resolve).as_deref(),
+            Some("urn:uuid:test-endpoint")
+        );
+        assert!(!is_resolve_request(
+            r#"<d:Probe><d:Types>tdn:NetworkVideoTransmitter</d:Types></d:Probe>"#
+        ));
+
+        let mut device_info = device_info_with_xaddrs("http://10.1.0.5:8080/onvif/device_service");
+        device_info.endpoint_reference = "urn:uuid:test-endpoint".to_string();
+        let relates_to = extract_message_id(&resolve, &RandomIdGenerator);
+        let resolve_match = create_resolve_match_message(&device_info, "match-1", &relates_to, 1);
+        assert!(resolve_match.contains("discovery/ResolveMatches</wsa:Action>"));
+        assert!(resolve_match.contains("<wsa:MessageID>urn:uuid:match-1</wsa:MessageID>"));
+        assert!(resolve_match.contains("resolve-1</wsa:RelatesTo>"));
+        assert!(resolve_match.contains(
+            "<wsd:ResolveMatch>\n<wsa:EndpointReference>\n<wsa:Address>urn:uuid:test-endpoint</wsa:Address>"
+        ));
+        assert!(resolve_match
+            .contains("<wsd:XAddrs>http://10.1.0.5:8080/onvif/device_service</wsd:XAddrs>"));
+    }
+
+    #[test]
+    fn test_discovery_messages_escape_device_values() {
+        let device_info = DeviceInfo {
+            endpoint_reference: "urn:uuid:test-endpoint".to_string(),
+            types: "tdn:NetworkVideoTransmitter".to_string(),
+            scopes: "onvif://www.onvif.org/name/R&D<\"Lab\">".to_string(),
+            xaddrs: "http://cam/onvif?a=1&b=2".to_string(),
+            manufacturer: "Test".to_string(),
+            model_name: "Test".to_string(),
+            friendly_name: "R&D".to_string(),
+            firmware_version: "1.0".to_string(),
+            serial_number: "1".to_string(),
+        };
+
+        for message in [
+            create_hello_message(&device_info, "id", 1),
+            create_bye_message(&device_info, "id", 1),
+            create_probe_match_message(&device_info, "id", "urn:uuid:x'<y>", 1),
+        ] {
+            assert!(message.contains("name/R&amp;D&lt;&quot;Lab&quot;&gt;</wsd:Scopes>"));
+            assert!(message.contains("<wsd:XAddrs>http://cam/onvif?a=1&amp;b=2</wsd:XAddrs>"));
+            assert!(!message.contains("a=1&b"));
+        }
+        let probe_match = create_probe_match_message(&device_info, "id", "urn:uuid:x'<y>", 1);
+        assert!(probe_match.contains("<wsa:RelatesTo>urn:uuid:x&apos;&lt;y&gt;</wsa:RelatesTo>"));
+    }
+
+    #[test]
+    fn test_discovery_addresses_follow_interface_family() {
+        let (bind, group) = discovery_addresses("192.168.1.10".parse().unwrap());
+        assert_eq!(bind, "0.0.0.0:3702".parse().unwrap());
+        assert_eq!(group, "239.255.255.250:3702".parse().unwrap());
+
+        let (bind, group) = discovery_addresses("fd00::10".parse().unwrap());
+        assert_eq!(bind, "[::]:3702".parse().unwrap());
+        assert_eq!(group, "[ff02::c]:3702".parse().unwrap());
+    }
+
+    #[test]
+    fn test_ipv6_interface_index_is_looked_up_by_address() {
+        let table = "\
+00000000000000000000000000000001 01 80 10 80       lo
+fd000000000000000000000000000010 03 40 00 80     eth1
+fe800000000000000242acfffe110002 02 40 20 80     eth0
+";
+        let index = |address: &str| find_interface_index(table, address.parse().unwrap());
+        assert_eq!(index("fd00::10"), Some(3));
+        assert_eq!(index("fe80::242:acff:fe11:2"), Some(2));
+        assert_eq!(index("fd00::11"), None);
+
+        assert_eq!(ipv6_interface_index(Ipv6Addr::UNSPECIFIED).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_response_delay_is_bounded_by_max() {
+        let max_delay = Duration::from_millis(500);
+        for random in [0, 1, 499, 500, 501, 12_345, u64::MAX / 3, u64::MAX] {
+            assert!(response_delay(max_delay, random) <= max_delay);
+        }
+        assert_eq!(response_delay(max_delay, 500), max_delay);
+        assert_eq!(response_delay(max_delay, 1002), Duration::from_millis(0));
+        assert_eq!(response_delay(Duration::ZERO, u64::MAX), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_join_attempts_every_interface() {
+        let interfaces: Vec<IpAddr> = ["192.168.1.10", "10.0.0.5", "172.16.0.2"]
+            .iter()
+            .map(|ip| ip.parse().unwrap())
+            .collect();
+        let mut attempted = Vec::new();
+        let joined = join_multicast_groups(&interfaces, |interface| {
+            attempted.push(interface);
+            if interface == interfaces[1] {
+                Err(std::io::Error::other("no such device"))
+            } else {
+                Ok(())
+            }
+        })
+        .unwrap();
+
+        assert_eq!(attempted, interfaces);
+        assert_eq!(joined, [interfaces[0], interfaces[2]]);
+
+        let failing = |_| Err(std::io::Error::other("no such device"));
+        assert!(join_multicast_groups(&interfaces, failing).is_err());
+    }
+
+    #[test]
+    fn test_windows_wsd_probe_match() {
+        let windows_probe = r#"<?xml version="1.0" encoding="utf-8"?><soap:Envelope xmlns:soap="http://www.w3.org/2003/05/soap-envelope" xmlns:wsa="http://schemas.xmlsoap.org/ws/2004/08/addressing" xmlns:wsd="http://schemas.xmlsoap.org/ws/2005/04/discovery" xmlns:wsdp="http://schemas.xmlsoap.org/ws/2006/02/devprof"><soap:Header><wsa:To>urn:schemas-xmlsoap-org:ws:2005:04:discovery</wsa:To><wsa:Action>http://schemas.xmlsoap.org/ws/2005/04/discovery/Probe</wsa:Action><wsa:MessageID>urn:uuid:0a6dc791-2be6-4991-9af1-454778a1917a</wsa:MessageID></soap:Header><soap:Body><wsd:Probe><wsd:Types>wsdp:Device</wsd:Types></wsd:Probe></soap:Body></soap:Envelope>"#;
+
+        assert!(is_probe_request(windows_probe));
+        assert!(is_windows_wsd_probe(windows_probe));
+        assert_eq!(
+            extract_message_id(windows_probe, &RandomIdGenerator),
+            "0a6dc791-2be6-4991-9af1-454778a1917a"
+        );
+
+        let device_info = DeviceInfo {
+            endpoint_reference: "urn:uuid:test-endpoint".to_string(),
+            types: "tdn:NetworkVideoTransmitter".to_string(),
+            scopes: "onvif://www.onvif.org/type/NetworkVideoTransmitter".to_string(),
+            xaddrs: "http://127.0.0.1:8080/onvif/device_service".to_string(),
+            manufacturer: "Test Mfg".to_string(),
+            model_name: "Test Model".to_string(),
+            friendly_name: "Lobby Camera".to_string(),
+            firmware_version: "1.0".to_string(),
+            serial_number: "12345".to_string(),
+        };
+        let windows_info = windows_device_info(&device_info);
+        let probe_match = create_probe_match_message(
+            &windows_info,
+            "test-message-id",
+            &extract_message_id(windows_probe, &RandomIdGenerator),
+            1,
+        );
+
+        assert!(
+            probe_match.contains("<wsd:Types>wsdp:Device tdn:NetworkVideoTransmitter</wsd:Types>")
+        );
+        assert!(probe_match.contains(WS_DEVPROF_NAMESPACE));
+        assert!(probe_match.contains("onvif://www.onvif.org/name/Lobby%20Camera"));
+        assert!(probe_match.contains("0a6dc791-2be6-4991-9af1-454778a1917a"));
+    }
+
+    #[test]
+    fn test_onvif_probe_is_not_windows_wsd() {
+        let onvif_probe = format!(
+            r#"<soap:Envelope xmlns:d="{}"><soap:Body><d:Probe><d:Types>tdn:NetworkVideoTransmitter</d:Types></d:Probe></soap:Body></soap:Envelope>"#,
+            WS_DISCOVERY_NAMESPACE
+        );
+        assert!(!is_windows_wsd_probe(&onvif_probe));
+    }
+
+    #[test]
+    fn test_health_gate_follows_rtsp_flag() {
+        let rtsp_healthy = Arc::new(AtomicBool::new(true));
+        let mut gate = HealthGate::new();
+        gate.rtsp_healthy = Some(rtsp_healthy.clone());
+
+        assert_eq!(gate.poll(), None);
+        assert!(gate.is_advertising());
+
+        // Going down sends a Bye once and stops answering probes
+        rtsp_healthy.store(false, Ordering::SeqCst);
+        assert_eq!(gate.poll(), Some(HealthTransition::Down));
+        assert!(!gate.is_advertising());
+        assert_eq!(gate.poll(), None);
+
+        // Recovery re-announces with Hello
+        rtsp_healthy.store(true, Ordering::SeqCst);
+        assert_eq!(gate.poll(), Some(HealthTransition::Up));
+        assert!(gate.is_advertising());
+    }
+
+    #[test]
+    fn test_health_gate_without_flag_always_advertises() {
+        let mut gate = HealthGate::new();
+        assert_eq!(gate.poll(), None);
+        assert!(gate.is_advertising());
+    }
+
+    #[test]
+    fn test_seeded_message_ids() {
+        let device_info = DeviceInfo {
+            endpoint_reference: "urn:uuid:test-endpoint".to_string(),
+            types: "tdn:TestDevice".to_string(),
+            scopes: "onvif://www.onvif.org/test".to_string(),
+            xaddrs: "http://127.0.0.1:8080/onvif".to_string(),
+            manufacturer: "Test Mfg".to_string(),
+            model_name: "Test Model".to_string(),
+            friendly_name: "Test Device".to_string(),
+            firmware_version: "1.0".to_string(),
+            serial_number: "12345".to_string(),
+        };
+
+        let ids = SeededIdGenerator::new(42);
+        let hello = create_hello_message(&device_info, &ids.new_id(), 1);
+        let bye = create_bye_message(&device_info, &ids.new_id(), 1);
+
+        let replay = SeededIdGenerator::new(42);
+        assert!(hello.contains(&format!(
+            "<wsa:MessageID>urn:uuid:{}</wsa:MessageID>",
+            replay.new_id()
+        )));
+        assert!(bye.contains(&format!(
+            "<wsa:MessageID>urn:uuid:{}</wsa:MessageID>",
+            replay.new_id()
+        )));
+    }
+
+    fn device_info_with_xaddrs(xaddrs: &str) -> DeviceInfo {
+        DeviceInfo {
+            endpoint_reference: "urn:uuid:split-horizon".to_string(),
+            types: "tdn:NetworkVideoTransmitter".to_string(),
+            scopes: "onvif://www.onvif.org/name/test".to_string(),
+            xaddrs: xaddrs.to_string(),
+            manufacturer: "Test".to_string(),
+            model_name: "Test".to_string(),
+            friendly_name: "Test".to_string(),
+            firmware_version: "1.0".to_string(),
+            serial_number: "1".to_string(),
+        }
+    }
+
+    /// Starts a unicast listener on an ephemeral loopback port and returns its address
+    fn spawn_unicast_listener(xaddrs: &str) -> SocketAddr {
+        let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let mut server =
+            WSDiscoveryServer::new_unicast(device_info_with_xaddrs(xaddrs), localhost, false)
+                .unwrap();
+        let addr = server.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let _ = server.start();
+        });
+        addr
+    }
+
+    fn probe(listener: SocketAddr) -> String {
+        probe_with_header(listener, "")
+    }
+
+    /// Sends a probe carrying `extra_header` in its SOAP header and returns the answer
+    fn probe_with_header(listener: SocketAddr, extra_header: &str) -> String {
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        let probe = format!(
+            r#"<soap:Envelope xmlns:d="{WS_DISCOVERY_NAMESPACE}"><soap:Header>{extra_header}<wsa:MessageID>urn:uuid:probe-1</wsa:MessageID></soap:Header><soap:Body><d:Probe><d:Types>tdn:NetworkVideoTransmitter</d:Types></d:Probe></soap:Body></soap:Envelope>"#
+        );
+        client.send_to(probe.as_bytes(), listener).unwrap();
+
+        let mut buffer = [0; 4096];
+        let (size, _) = client.recv_from(&mut buffer).unwrap();
+        String::from_utf8_lossy(&buffer[..size]).into_owned()
+    }
+
+    #[test]
+    fn test_each_listener_answers_with_its_own_xaddrs() {
+        let camera = spawn_unicast_listener("http://10.1.0.5:8080/onvif/device_service");
+        let management = spawn_unicast_listener("http://192.168.100.5:8080/onvif/device_service");
+
+        let from_camera = probe(camera);
+        assert!(from_camera
+            .contains("<wsd:XAddrs>http://10.1.0.5:8080/onvif/device_service</wsd:XAddrs>"));
+        assert!(from_camera.contains("probe-1</wsa:RelatesTo>"));
+
+        let from_management = probe(management);
+        assert!(from_management
+            .contains("<wsd:XAddrs>http://192.168.100.5:8080/onvif/device_service</wsd:XAddrs>"));
+        assert!(!from_management.contains("10.1.0.5"));
+    }
+
+    #[test]
+    fn test_oversized_probe_is_answered_and_junk_ignored() {
+        let listener = spawn_unicast_listener("http://10.1.0.5:8080/onvif/device_service");
+
+        // Binary junk is dropped without stopping the listener
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(&[0xff, 0xfe, 0x00, 0x80], listener).unwrap();
+
+        // Vendor extensions can push a probe past 4 KiB
+        let padding = format!("<x:Padding>{}</x:Padding>", "a".repeat(8192));
+        let probe_match = probe_with_header(listener, &padding);
+        assert!(probe_match.contains("probe-1</wsa:RelatesTo>"));
+    }
+
+    #[test]
+    fn test_device_info_change_bumps_metadata_version() {
+        let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let device_info = device_info_with_xaddrs("http://10.1.0.5:8080/onvif/device_service");
+        let mut server =
+            WSDiscoveryServer::new_unicast(device_info.clone(), localhost, false).unwrap();
+        assert_eq!(server.metadata_version(), 1);
+
+        // Unchanged info keeps the version
+        server.update_device_info(device_info.clone());
+        assert_eq!(server.metadata_version(), 1);
+
+        let mut moved = device_info;
+        moved.xaddrs = "http://10.1.0.6:8080/onvif/device_service".to_string();
+        server.update_device_info(moved);
+        assert_eq!(server.metadata_version(), 2);
+
+        let addr = server.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let _ = server.start();
+        });
+        let probe_match = probe(addr);
+        assert!(probe_match.contains("<wsd:MetadataVersion>2</wsd:MetadataVersion>"));
+        assert!(probe_match.contains("http://10.1.0.6:8080/onvif/device_service"));
+    }
+
+    #[test]
+    fn test_scope_change_bumps_metadata_version() {
+        let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let device_info = device_info_with_xaddrs("http://10.1.0.5:8080/onvif/device_service");
+        let mut server = WSDiscoveryServer::new_unicast(device_info, localhost, false).unwrap();
+        let scopes = DeviceScopes::for_device("Cam", &[]);
+        server.set_scopes(scopes.clone());
+
+        server.follow_scopes();
+        assert_eq!(server.metadata_version(), 1);
+
+        scopes
+            .set_configurable(&["onvif://www.onvif.org/location/Lobby"])
+            .unwrap();
+        server.follow_scopes();
+        assert_eq!(server.metadata_version(), 2);
+        assert!(server
+            .device_info
+            .scopes
+            .ends_with("onvif://www.onvif.org/location/Lobby"));
+    }
+
+    #[test]
+    fn test_configured_location_scope_is_in_hello() {
+        let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let device_info = device_info_with_xaddrs("http://10.1.0.5:8080/onvif/device_service");
+        let mut server = WSDiscoveryServer::new_unicast(device_info, localhost, false).unwrap();
+        server.set_scopes(DeviceScopes::for_device(
+            "Cam",
+            &["onvif://www.onvif.org/location/Lobby".to_string()],
+        ));
+
+        let hello = create_hello_message(&server.device_info, "hello-1", 1);
+        let scopes = extract_element_text(&hello, "Scopes").unwrap();
+        assert!(scopes
+            .split_whitespace()
+            .any(|scope| scope == "onvif://www.onvif.org/location/Lobby"));
+        assert!(!scopes.contains("location/Unknown"));
+    }
+
+    #[test]
+    fn test_start_returns_after_shutdown_request() {
+        let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let mut server = WSDiscoveryServer::new_unicast(
+            device_info_with_xaddrs("http://127.0.0.1:8080/onvif/device_service"),
+            localhost,
+            false,
+        )
+        .unwrap();
+        let status = ServiceStatus::new();
+        server.set_service_status(status.clone());
+        let addr = server.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            server.start().unwrap();
+            server
+        });
+
+        // Still answering until shutdown is requested
+        assert!(probe(addr).contains("probe-1</wsa:RelatesTo>"));
+
+        status.request_shutdown();
+        let server = handle.join().unwrap();
+        assert!(!server.health_gate.is_advertising());
+    }
+
+    /// Clock that only moves when the test advances it
+    struct ManualClock(std::sync::Mutex<Instant>);
+
+    impl ManualClock {
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[test]
+    fn test_hello_is_sent_while_probes_arrive_continuously() {
+        // Hello goes to a loopback "group" the test listens on
+        let group = UdpSocket::bind("127.0.0.1:0").unwrap();
+        group
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut server = WSDiscoveryServer::from_socket(
+            device_info_with_xaddrs("http://127.0.0.1:8080/onvif/device_service"),
+            socket,
+            false,
+            true,
+        );
+        server.multicast_addr = group.local_addr().unwrap();
+        let clock = Arc::new(ManualClock(std::sync::Mutex::new(Instant::now())));
+        server.set_clock(clock.clone());
+        let status = ServiceStatus::new();
+        server.set_service_status(status.clone());
+        let addr = server.local_addr().unwrap();
+        let handle = std::thread::spawn(move || server.start().is_ok());
+
+        let hellos = |timeout: Duration| {
+            let deadline = Instant::now() + timeout;
+            let mut buffer = [0; 4096];
+            let mut count = 0;
+            while Instant::now() < deadline {
+                if let Ok((size, _)) = group.recv_from(&mut buffer) {
+                    if String::from_utf8_lossy(&buffer[..size]).contains("<wsd:Hello>") {
+                        count += 1;
+                    }
+                }
+            }
+            count
+        };
+        assert_eq!(hellos(Duration::from_millis(300)), 1, "startup Hello");
+
+        // Probes arrive far more often than the 1s receive timeout, so Hello
+        // is only sent if it is scheduled independently of receive timeouts
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let probe = format!(
+            r#"<soap:Envelope xmlns:d="{WS_DISCOVERY_NAMESPACE}"><soap:Body><d:Probe/></soap:Body></soap:Envelope>"#
+        );
+        for _ in 0..10 {
+            client.send_to(probe.as_bytes(), addr).unwrap();
+            clock.advance(Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        client.send_to(probe.as_bytes(), addr).unwrap();
+        assert!(hellos(Duration::from_millis(300)) >= 1, "periodic Hello");
+
+        status.request_shutdown();
+        assert!(handle.join().unwrap());
+    }
+
+    #[test]
+    fn test_is_directed_probe() {
+        let probe = |to: &str| format!("<s:Header><wsa:To>{to}</wsa:To></s:Header><d:Probe/>");
+        assert!(is_directed_probe(&probe("soap.udp://10.0.0.5:3702")));
+        assert!(!is_directed_probe(&probe(
+            "urn:schemas-xmlsoap-org:ws:2005:04:discovery"
+        )));
+        assert!(!is_directed_probe(&probe(
+            "urn:docs-oasis-open-org:ws-dd:ns:discovery:2009:01"
+        )));
+        assert!(!is_directed_probe("<d:Probe/>"));
+    }
+
+    #[test]
+    fn test_directed_probe_is_answered_at_its_source_without_delay() {
+        let group = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut server = WSDiscoveryServer::from_socket(
+            device_info_with_xaddrs("http://127.0.0.1:8080/onvif/device_service"),
+            socket,
+            false,
+            true,
+        );
+        server.multicast_addr = group.local_addr().unwrap();
+        // Long enough that a delayed answer would miss the client's timeout
+        server.set_max_response_delay(Duration::from_secs(30));
+        let addr = server.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let _ = server.start();
+        });
+
+        let started = Instant::now();
+        let probe_match = probe_with_header(addr, "<wsa:To>soap.udp://127.0.0.1:3702</wsa:To>");
+        assert!(probe_match.contains("probe-1</wsa:RelatesTo>"));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
     #[test]
     fn test_generate_uuid() {
-        let uuid1 = generate_uuid();
-        let uuid2 = generate_uuid();
+        let uuid1 = RandomIdGenerator.new_id();
+        let uuid2 = RandomIdGenerator.new_id();
         assert_eq!(uuid1.len(), 36);
         assert_ne!(uuid1, uuid2);
     }